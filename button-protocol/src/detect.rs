@@ -0,0 +1,480 @@
+//! Turns the raw up/down edges of the buttons into [`Event`]s: a
+//! [`PressDetector`] per button finds presses and holds, the
+//! [`PanelDetector`] combines buttons pressed together into a [`Chord`].
+//!
+//! Time is passed in as milliseconds so this runs just as well on the panel
+//! as in a test on the host.
+use crate::{Button, ButtonPress, Chord, Event, Hold};
+
+/// Presses shorter then this are contact bounce and ignored
+pub const BOUNCE_MS: u64 = 50;
+/// Presses up to this long are short, longer ones are long
+pub const LONG_MS: u64 = 400;
+/// Presses this long turn into a [`Hold`], long presses keep the window
+/// they had before holds existed
+pub const HOLD_MS: u64 = 2000;
+/// Time between [`Hold::Repeat`] events
+pub const HOLD_REPEAT_MS: u64 = 250;
+/// Short presses closer together then this form a double or triple press
+pub const MULTI_PRESS_GAP_MS: u64 = 250;
+
+/// Classify a single press by how long it was held down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PressLength {
+    Bounce,
+    Short,
+    Long,
+    Hold,
+}
+
+impl PressLength {
+    pub const fn from_millis(held: u64) -> Self {
+        if held <= BOUNCE_MS {
+            PressLength::Bounce
+        } else if held <= LONG_MS {
+            PressLength::Short
+        } else if held < HOLD_MS {
+            PressLength::Long
+        } else {
+            PressLength::Hold
+        }
+    }
+}
+
+/// A single short press is only reported once [`MULTI_PRESS_GAP_MS`] has
+/// passed without another press. Call [`PressDetector::poll`] at
+/// [`PressDetector::deadline`] to get it. The same goes for the [`Hold`]
+/// events while the button is kept down. A long press or hold ends a
+/// sequence of short presses, the short presses before it are dropped.
+#[derive(Debug, Clone)]
+pub struct PressDetector {
+    button: Button,
+    pressed_at: Option<u64>,
+    /// repeats sent so far if the current press turned into a hold, can
+    /// be more than fit in a [`Hold::Repeat`]
+    hold: Option<u64>,
+    /// short presses in the current sequence
+    count: u8,
+    last_release: u64,
+}
+
+impl PressDetector {
+    pub const fn new(button: Button) -> Self {
+        Self {
+            button,
+            pressed_at: None,
+            hold: None,
+            count: 0,
+            last_release: 0,
+        }
+    }
+
+    /// The button went down at `now`
+    pub fn pressed(&mut self, now: u64) -> Option<Event> {
+        let finished = self.poll(now);
+        self.pressed_at = Some(now);
+        finished
+    }
+
+    /// The button came up at `now`
+    pub fn released(&mut self, now: u64) -> Option<Event> {
+        let pressed_at = self.pressed_at.take()?;
+        if self.hold.take().is_some() {
+            return Some(Hold::Release(self.button).into());
+        }
+
+        match PressLength::from_millis(now.saturating_sub(pressed_at)) {
+            PressLength::Bounce => None,
+            PressLength::Short => {
+                self.count += 1;
+                self.last_release = now;
+                if self.count == 3 {
+                    self.count = 0;
+                    Some(ButtonPress::Triple(self.button).into())
+                } else {
+                    None
+                }
+            }
+            PressLength::Long => {
+                self.count = 0;
+                Some(ButtonPress::Long(self.button).into())
+            }
+            // we were not polled in time to start the hold
+            PressLength::Hold => {
+                self.count = 0;
+                None
+            }
+        }
+    }
+
+    /// When [`PressDetector::poll`] needs to be called next, if at all
+    pub fn deadline(&self) -> Option<u64> {
+        match (self.pressed_at, self.hold) {
+            (Some(pressed_at), None) => Some(pressed_at + HOLD_MS),
+            (Some(pressed_at), Some(repeats)) => Some(
+                pressed_at
+                    + HOLD_MS
+                    + repeats.saturating_add(1) * HOLD_REPEAT_MS,
+            ),
+            (None, _) if self.count > 0 => {
+                Some(self.last_release + MULTI_PRESS_GAP_MS)
+            }
+            (None, _) => None,
+        }
+    }
+
+    /// Reports a short or double press once no further press can follow, and
+    /// the hold events while the button is down.
+    pub fn poll(&mut self, now: u64) -> Option<Event> {
+        if now < self.deadline()? {
+            return None;
+        }
+
+        if self.pressed_at.is_some() {
+            self.count = 0;
+            let event = match self.hold {
+                None => Hold::Start(self.button),
+                Some(repeats) => {
+                    let repeat = repeats.saturating_add(1);
+                    // something resting on the button, stop counting
+                    let repeat = u16::try_from(repeat).unwrap_or(u16::MAX);
+                    Hold::Repeat(self.button, repeat)
+                }
+            };
+            self.hold =
+                Some(self.hold.map_or(0, |repeats| repeats.saturating_add(1)));
+            return Some(event.into());
+        }
+
+        let press = match self.count {
+            1 => ButtonPress::Short(self.button),
+            _ => ButtonPress::Double(self.button),
+        };
+        self.count = 0;
+        Some(press.into())
+    }
+
+    pub fn is_holding(&self) -> bool {
+        self.hold.is_some()
+    }
+
+    /// Forget the current press and any short presses before it
+    pub fn cancel(&mut self) {
+        self.pressed_at = None;
+        self.hold = None;
+        self.count = 0;
+    }
+}
+
+/// Runs a [`PressDetector`] per button and recognizes buttons pressed
+/// together as a [`Chord`].
+///
+/// A chord starts when a second button goes down while another is down
+/// (and not yet held) and is reported once all its buttons are released.
+/// The buttons in a chord do not produce presses of their own.
+#[derive(Debug, Clone)]
+pub struct PanelDetector {
+    buttons: [PressDetector; 6],
+    down: Chord,
+    /// every button that took part in the current chord
+    chord: Chord,
+}
+
+impl Default for PanelDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PanelDetector {
+    pub const fn new() -> Self {
+        Self {
+            buttons: [
+                PressDetector::new(Button::TopLeft),
+                PressDetector::new(Button::TopMiddle),
+                PressDetector::new(Button::TopRight),
+                PressDetector::new(Button::BottomLeft),
+                PressDetector::new(Button::BottomMiddle),
+                PressDetector::new(Button::BottomRight),
+            ],
+            down: Chord::EMPTY,
+            chord: Chord::EMPTY,
+        }
+    }
+
+    fn detector(&mut self, button: Button) -> &mut PressDetector {
+        &mut self.buttons[button as usize - 1]
+    }
+
+    fn someone_holding(&self) -> bool {
+        self.down
+            .buttons()
+            .any(|button| self.buttons[button as usize - 1].is_holding())
+    }
+
+    /// `button` went down at `now`
+    pub fn pressed(&mut self, button: Button, now: u64) -> Option<Event> {
+        let starts_chord = !self.down.is_empty()
+            && self.chord.is_empty()
+            && !self.someone_holding();
+        self.down = self.down.with(button);
+
+        if starts_chord {
+            self.chord = self.down;
+        } else if !self.chord.is_empty() {
+            self.chord = self.chord.with(button);
+        } else {
+            return self.detector(button).pressed(now);
+        }
+
+        for button in self.chord.buttons() {
+            self.buttons[button as usize - 1].cancel();
+        }
+        None
+    }
+
+    /// `button` came up at `now`
+    pub fn released(&mut self, button: Button, now: u64) -> Option<Event> {
+        self.down = self.down.without(button);
+        if self.chord.is_empty() {
+            return self.detector(button).released(now);
+        }
+
+        if self.down.is_empty() {
+            let chord = self.chord;
+            self.chord = Chord::EMPTY;
+            Some(chord.into())
+        } else {
+            None
+        }
+    }
+
+    /// When [`PanelDetector::poll`] needs to be called next, if at all
+    pub fn deadline(&self) -> Option<u64> {
+        self.buttons
+            .iter()
+            .filter_map(PressDetector::deadline)
+            .min()
+    }
+
+    /// Get the next event that is due, call until it returns `None`
+    pub fn poll(&mut self, now: u64) -> Option<Event> {
+        self.buttons.iter_mut().find_map(|button| button.poll(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Button::TopMiddle;
+
+    /// Feed (down, up) timestamps, then poll far in the future
+    fn presses(edges: &[(u64, u64)]) -> ([Option<Event>; 8], usize) {
+        let mut detector = PressDetector::new(TopMiddle);
+        let mut out = [None; 8];
+        let mut n = 0;
+        for (down, up) in edges {
+            for press in [detector.pressed(*down), detector.released(*up)] {
+                if press.is_some() {
+                    out[n] = press;
+                    n += 1;
+                }
+            }
+        }
+        if let Some(press) = detector.poll(u64::MAX) {
+            out[n] = Some(press);
+            n += 1;
+        }
+        (out, n)
+    }
+
+    #[test]
+    fn single_short() {
+        let (out, n) = presses(&[(0, 100)]);
+        assert_eq!(
+            &out[..n],
+            &[Some(Event::Press(ButtonPress::Short(TopMiddle)))]
+        );
+    }
+
+    #[test]
+    fn short_waits_for_gap() {
+        let mut detector = PressDetector::new(TopMiddle);
+        detector.pressed(0);
+        assert_eq!(detector.released(100), None);
+        assert_eq!(detector.deadline(), Some(100 + MULTI_PRESS_GAP_MS));
+        assert_eq!(detector.poll(100 + MULTI_PRESS_GAP_MS - 1), None);
+        assert_eq!(
+            detector.poll(100 + MULTI_PRESS_GAP_MS),
+            Some(Event::Press(ButtonPress::Short(TopMiddle)))
+        );
+        assert_eq!(detector.deadline(), None);
+    }
+
+    #[test]
+    fn double() {
+        let (out, n) = presses(&[(0, 100), (200, 300)]);
+        assert_eq!(
+            &out[..n],
+            &[Some(Event::Press(ButtonPress::Double(TopMiddle)))]
+        );
+    }
+
+    #[test]
+    fn triple_is_reported_on_release() {
+        let mut detector = PressDetector::new(TopMiddle);
+        for (down, up) in [(0, 100), (200, 300)] {
+            detector.pressed(down);
+            assert_eq!(detector.released(up), None);
+        }
+        detector.pressed(400);
+        assert_eq!(
+            detector.released(500),
+            Some(Event::Press(ButtonPress::Triple(TopMiddle)))
+        );
+        assert_eq!(detector.deadline(), None);
+    }
+
+    #[test]
+    fn slow_presses_are_separate() {
+        let (out, n) = presses(&[(0, 100), (1000, 1100)]);
+        assert_eq!(
+            &out[..n],
+            &[
+                Some(Event::Press(ButtonPress::Short(TopMiddle))),
+                Some(Event::Press(ButtonPress::Short(TopMiddle)))
+            ]
+        );
+    }
+
+    #[test]
+    fn long_and_ignored() {
+        let (out, n) = presses(&[(0, 20), (1000, 1600), (3000, 6000)]);
+        assert_eq!(
+            &out[..n],
+            &[Some(Event::Press(ButtonPress::Long(TopMiddle)))]
+        );
+    }
+
+    #[test]
+    fn hold_repeats_until_release() {
+        let mut detector = PressDetector::new(TopMiddle);
+        assert_eq!(detector.pressed(0), None);
+        assert_eq!(detector.deadline(), Some(HOLD_MS));
+        assert_eq!(detector.poll(HOLD_MS - 1), None);
+        assert_eq!(
+            detector.poll(HOLD_MS),
+            Some(Event::Hold(Hold::Start(TopMiddle)))
+        );
+        for repeat in 1..=3 {
+            let at = detector.deadline().unwrap();
+            assert_eq!(at, HOLD_MS + u64::from(repeat) * HOLD_REPEAT_MS);
+            assert_eq!(
+                detector.poll(at),
+                Some(Event::Hold(Hold::Repeat(TopMiddle, repeat)))
+            );
+        }
+        assert_eq!(
+            detector.released(HOLD_MS + 900),
+            Some(Event::Hold(Hold::Release(TopMiddle)))
+        );
+        assert_eq!(detector.deadline(), None);
+    }
+
+    #[test]
+    fn long_press_window_is_unchanged() {
+        assert_eq!(PressLength::from_millis(LONG_MS + 1), PressLength::Long);
+        assert_eq!(PressLength::from_millis(1999), PressLength::Long);
+        assert_eq!(PressLength::from_millis(2000), PressLength::Hold);
+
+        let (out, n) = presses(&[(0, 1900)]);
+        assert_eq!(
+            &out[..n],
+            &[Some(Event::Press(ButtonPress::Long(TopMiddle)))]
+        );
+    }
+
+    #[test]
+    fn endless_hold_keeps_repeating() {
+        let mut detector = PressDetector::new(TopMiddle);
+        detector.pressed(0);
+        detector.poll(HOLD_MS);
+        for _ in 0..u16::MAX {
+            let at = detector.deadline().unwrap();
+            detector.poll(at);
+        }
+        for _ in 0..3 {
+            let at = detector.deadline().unwrap();
+            assert_eq!(
+                detector.poll(at),
+                Some(Event::Hold(Hold::Repeat(TopMiddle, u16::MAX)))
+            );
+            assert_eq!(detector.poll(at), None);
+            assert_eq!(detector.deadline(), Some(at + HOLD_REPEAT_MS));
+        }
+    }
+
+    #[test]
+    fn hold_ends_multi_press() {
+        let mut detector = PressDetector::new(TopMiddle);
+        detector.pressed(0);
+        detector.released(100);
+        detector.pressed(200);
+        assert_eq!(
+            detector.poll(200 + HOLD_MS),
+            Some(Event::Hold(Hold::Start(TopMiddle)))
+        );
+        detector.released(200 + HOLD_MS + 10);
+        assert_eq!(detector.poll(u64::MAX), None);
+    }
+
+    #[test]
+    fn chord_replaces_presses() {
+        use crate::Button::{BottomLeft, BottomRight};
+
+        let mut panel = PanelDetector::new();
+        assert_eq!(panel.pressed(BottomLeft, 0), None);
+        assert_eq!(panel.pressed(BottomRight, 30), None);
+        assert_eq!(panel.released(BottomLeft, 200), None);
+        assert_eq!(panel.deadline(), None);
+        assert_eq!(
+            panel.released(BottomRight, 220),
+            Some(Event::Chord(Chord::of(&[BottomLeft, BottomRight])))
+        );
+        assert_eq!(panel.poll(u64::MAX), None);
+    }
+
+    #[test]
+    fn panel_single_presses() {
+        let mut panel = PanelDetector::new();
+        panel.pressed(TopMiddle, 0);
+        panel.released(TopMiddle, 100);
+        let deadline = panel.deadline().unwrap();
+        assert_eq!(
+            panel.poll(deadline),
+            Some(Event::Press(ButtonPress::Short(TopMiddle)))
+        );
+    }
+
+    #[test]
+    fn no_chord_during_hold() {
+        use crate::Button::TopRight;
+
+        let mut panel = PanelDetector::new();
+        panel.pressed(TopRight, 0);
+        assert_eq!(
+            panel.poll(HOLD_MS),
+            Some(Event::Hold(Hold::Start(TopRight)))
+        );
+        panel.pressed(TopMiddle, HOLD_MS + 10);
+        panel.released(TopMiddle, HOLD_MS + 110);
+        assert_eq!(
+            panel.released(TopRight, HOLD_MS + 200),
+            Some(Event::Hold(Hold::Release(TopRight)))
+        );
+        assert_eq!(
+            panel.poll(u64::MAX),
+            Some(Event::Press(ButtonPress::Short(TopMiddle)))
+        );
+    }
+}
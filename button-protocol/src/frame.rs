@@ -0,0 +1,344 @@
+//! Framing for messages sent over the (glitchy) serial link.
+//!
+//! Every frame looks like this:
+//!
+//! ```text
+//! | START | VERSION | KIND | LEN | PAYLOAD (LEN bytes) | CRC (2 bytes, BE) |
+//! ```
+//!
+//! The crc is CRC-16/CCITT-FALSE over everything from `VERSION` up to and
+//! including the payload. A reader that runs into a corrupt or unknown frame
+//! drops bytes until the next `START` (see [`resync`]) and tries again.
+//!
+//! Panels on the network start with a `Hello` (see [`crate::Hello`]) which
+//! the host answers with a `Welcome`. While idle they send a `Ping` every
+//! few seconds, the host answers with a `Pong`. These frames have no payload
+//! apart from the hello.
+use defmt::Format;
+
+pub const START: u8 = 0xA5;
+pub const VERSION: u8 = 1;
+
+pub const HEADER_LEN: usize = 4;
+pub const CRC_LEN: usize = 2;
+/// Fits a [`crate::Hello`], every other payload is at most 7 bytes
+pub const MAX_PAYLOAD: usize = 32;
+pub const MAX_FRAME_LEN: usize = HEADER_LEN + MAX_PAYLOAD + CRC_LEN;
+
+/// Message types, panel to host start at 1, host to panel at 16.
+#[derive(Format, Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Kind {
+    ButtonPress = 1,
+    Hold = 2,
+    Chord = 3,
+    Hello = 4,
+    Ping = 5,
+
+    SetLed = 16,
+    Blink = 17,
+    Brightness = 18,
+    Ack = 19,
+    Welcome = 20,
+    Pong = 21,
+}
+
+impl Kind {
+    pub fn deserialize(byte: u8) -> Result<Self, Error> {
+        Ok(match byte {
+            1 => Kind::ButtonPress,
+            2 => Kind::Hold,
+            3 => Kind::Chord,
+            4 => Kind::Hello,
+            5 => Kind::Ping,
+            16 => Kind::SetLed,
+            17 => Kind::Blink,
+            18 => Kind::Brightness,
+            19 => Kind::Ack,
+            20 => Kind::Welcome,
+            21 => Kind::Pong,
+            _ => return Err(Error::UnknownKind(byte)),
+        })
+    }
+}
+
+#[derive(Format, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Error {
+    /// Not enough bytes for a complete frame yet, try again later
+    Incomplete,
+    /// The data does not start with the start marker
+    NoStart,
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    PayloadTooLong(usize),
+    CrcMismatch {
+        expected: u16,
+        got: u16,
+    },
+    BufferTooSmall,
+}
+
+#[derive(Format, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Frame<'a> {
+    pub kind: Kind,
+    pub payload: &'a [u8],
+}
+
+/// CRC-16/CCITT-FALSE
+pub const fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    let mut i = 0;
+    while i < data.len() {
+        crc ^= (data[i] as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        i += 1;
+    }
+    crc
+}
+
+/// Write a frame with `payload` into `buf`, returns the length of the frame.
+pub fn encode(
+    kind: Kind,
+    payload: &[u8],
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(Error::PayloadTooLong(payload.len()));
+    }
+    let len = HEADER_LEN + payload.len() + CRC_LEN;
+    if buf.len() < len {
+        return Err(Error::BufferTooSmall);
+    }
+
+    buf[0] = START;
+    buf[1] = VERSION;
+    buf[2] = kind as u8;
+    #[allow(clippy::cast_possible_truncation)] // checked against MAX_PAYLOAD
+    {
+        buf[3] = payload.len() as u8;
+    }
+    buf[HEADER_LEN..HEADER_LEN + payload.len()].copy_from_slice(payload);
+
+    let crc = crc16(&buf[1..HEADER_LEN + payload.len()]);
+    buf[len - CRC_LEN..len].copy_from_slice(&crc.to_be_bytes());
+    Ok(len)
+}
+
+/// Decode the frame at the start of `buf`. On success returns the frame and
+/// the number of bytes it took up. On any error other then
+/// [`Error::Incomplete`] the caller should drop [`resync`] bytes and retry.
+pub fn decode(buf: &[u8]) -> Result<(Frame<'_>, usize), Error> {
+    match decode_any_version(buf)? {
+        (VERSION, frame, len) => Ok((frame, len)),
+        (version, _, _) => Err(Error::UnsupportedVersion(version)),
+    }
+}
+
+/// Like [`decode`] but also accepts frames of other versions, returns the
+/// version too. Used to read the [`crate::Hello`] of a panel that speaks
+/// another version, the header of a frame stays the same.
+pub fn decode_any_version(
+    buf: &[u8],
+) -> Result<(u8, Frame<'_>, usize), Error> {
+    match buf.first() {
+        None => return Err(Error::Incomplete),
+        Some(&START) => (),
+        Some(_) => return Err(Error::NoStart),
+    }
+    if buf.len() < HEADER_LEN {
+        return Err(Error::Incomplete);
+    }
+
+    let version = buf[1];
+    let payload_len = buf[3] as usize;
+    if payload_len > MAX_PAYLOAD {
+        return Err(Error::PayloadTooLong(payload_len));
+    }
+
+    let len = HEADER_LEN + payload_len + CRC_LEN;
+    if buf.len() < len {
+        return Err(Error::Incomplete);
+    }
+
+    let expected = crc16(&buf[1..HEADER_LEN + payload_len]);
+    let got = u16::from_be_bytes([buf[len - 2], buf[len - 1]]);
+    if expected != got {
+        return Err(Error::CrcMismatch { expected, got });
+    }
+
+    let kind = Kind::deserialize(buf[2])?;
+    let payload = &buf[HEADER_LEN..HEADER_LEN + payload_len];
+    Ok((version, Frame { kind, payload }, len))
+}
+
+/// Number of bytes to drop from `buf` to get to the next possible frame
+/// start. Always drops at least one byte (unless `buf` is empty) so a
+/// corrupt frame can not be decoded twice.
+pub fn resync(buf: &[u8]) -> usize {
+    if buf.is_empty() {
+        return 0;
+    }
+    buf[1..]
+        .iter()
+        .position(|b| *b == START)
+        .map_or(buf.len(), |pos| pos + 1)
+}
+
+/// Collects a byte stream into frames without needing an allocator.
+#[derive(Debug, Clone)]
+pub struct Reader {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+    /// length of the frame returned last, dropped on the next push
+    consumed: usize,
+}
+
+impl Default for Reader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Reader {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAME_LEN],
+            len: 0,
+            consumed: 0,
+        }
+    }
+
+    fn drop_front(&mut self, n: usize) {
+        self.buf.copy_within(n..self.len, 0);
+        self.len -= n;
+    }
+
+    /// Add a byte, returns a frame once one is complete. Corrupt data is
+    /// skipped.
+    pub fn push(&mut self, byte: u8) -> Option<Frame<'_>> {
+        let consumed = core::mem::take(&mut self.consumed);
+        self.drop_front(consumed);
+        if self.len == MAX_FRAME_LEN {
+            let skip = resync(&self.buf[..self.len]);
+            self.drop_front(skip);
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+
+        loop {
+            match decode(&self.buf[..self.len]) {
+                Ok((_, len)) => {
+                    self.consumed = len;
+                    break;
+                }
+                Err(Error::Incomplete) => return None,
+                Err(_) => {
+                    let skip = resync(&self.buf[..self.len]);
+                    self.drop_front(skip);
+                }
+            }
+        }
+
+        decode(&self.buf[..self.len]).ok().map(|(frame, _)| frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let len = encode(Kind::ButtonPress, &[7], &mut buf).unwrap();
+        let (frame, used) = decode(&buf[..len]).unwrap();
+        assert_eq!(used, len);
+        assert_eq!(frame.kind, Kind::ButtonPress);
+        assert_eq!(frame.payload, &[7]);
+    }
+
+    #[test]
+    fn other_versions_are_only_read_on_request() {
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let len = encode(Kind::Hello, &[2, b'a'], &mut buf).unwrap();
+        buf[1] = 2;
+        let crc = crc16(&buf[1..len - CRC_LEN]);
+        buf[len - CRC_LEN..len].copy_from_slice(&crc.to_be_bytes());
+
+        assert_eq!(decode(&buf[..len]), Err(Error::UnsupportedVersion(2)));
+        let (version, frame, used) = decode_any_version(&buf[..len]).unwrap();
+        assert_eq!((version, frame.kind, used), (2, Kind::Hello, len));
+    }
+
+    #[test]
+    fn incomplete() {
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let len = encode(Kind::ButtonPress, &[7], &mut buf).unwrap();
+        for partial in 0..len {
+            assert_eq!(decode(&buf[..partial]), Err(Error::Incomplete));
+        }
+    }
+
+    #[test]
+    fn every_flipped_bit_is_detected() {
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let len = encode(Kind::ButtonPress, &[3], &mut buf).unwrap();
+        for byte in 0..len {
+            for bit in 0..8 {
+                let mut corrupt = buf;
+                corrupt[byte] ^= 1 << bit;
+                assert!(decode(&corrupt[..len]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn resync_skips_to_next_start() {
+        let mut buf = [0u8; 32];
+        buf[..3].copy_from_slice(&[b'\n', START, 0x42]);
+        let len = encode(Kind::ButtonPress, &[1], &mut buf[3..]).unwrap();
+        let mut data = &buf[..3 + len];
+
+        loop {
+            match decode(data) {
+                Ok((frame, _)) => {
+                    assert_eq!(frame.payload, &[1]);
+                    break;
+                }
+                Err(Error::Incomplete) => panic!("valid frame was not found"),
+                Err(_) => data = &data[resync(data)..],
+            }
+        }
+    }
+
+    #[test]
+    fn reader_finds_frames_between_garbage() {
+        let mut frame = [0u8; MAX_FRAME_LEN];
+        let len = encode(Kind::Brightness, &[9], &mut frame).unwrap();
+
+        let mut reader = Reader::new();
+        let mut found = 0;
+        let garbage = [START, 0, START, START, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+        for byte in garbage.iter().chain(&frame[..len]).chain(&frame[..len]) {
+            if let Some(frame) = reader.push(*byte) {
+                assert_eq!(frame.kind, Kind::Brightness);
+                assert_eq!(frame.payload, &[9]);
+                found += 1;
+            }
+        }
+        assert_eq!(found, 2);
+    }
+}
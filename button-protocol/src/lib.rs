@@ -2,7 +2,10 @@
 use defmt::Format;
 use ha_protocol::Reading;
 
-#[derive(Format, Clone, Copy, PartialEq, Eq, Debug)]
+pub mod detect;
+pub mod frame;
+
+#[derive(Format, Clone, Copy, PartialEq, Eq, Debug, Hash)]
 #[repr(u8)]
 pub enum Button {
     TopLeft = 1,
@@ -14,6 +17,15 @@ pub enum Button {
 }
 
 impl Button {
+    pub const ALL: [Button; 6] = [
+        Button::TopLeft,
+        Button::TopMiddle,
+        Button::TopRight,
+        Button::BottomLeft,
+        Button::BottomMiddle,
+        Button::BottomRight,
+    ];
+
     pub const fn serialize(self) -> u8 {
         self as u8
     }
@@ -32,10 +44,12 @@ impl Button {
     }
 }
 
-#[derive(Format, Clone, Copy, PartialEq, Eq, Debug)]
+#[derive(Format, Clone, Copy, PartialEq, Eq, Debug, Hash)]
 pub enum ButtonPress {
     Short(Button),
     Long(Button),
+    Double(Button),
+    Triple(Button),
 }
 
 impl From<ButtonPress> for Reading {
@@ -45,16 +59,17 @@ impl From<ButtonPress> for Reading {
             small_bedroom::{self, ButtonPanel},
         };
 
+        // the data server only knows about press duration, multi presses
+        // are made up of short presses: send one reading per tap, see
+        // [`ButtonPress::taps`]
         let press = match value {
-            ButtonPress::Short(_) => Press(100),
+            ButtonPress::Short(_)
+            | ButtonPress::Double(_)
+            | ButtonPress::Triple(_) => Press(100),
             ButtonPress::Long(_) => Press(500),
         };
 
-        let button = match value {
-            ButtonPress::Short(button) | ButtonPress::Long(button) => button,
-        };
-
-        let reading = match button {
+        let reading = match value.button() {
             Button::TopLeft => ButtonPanel::TopLeft(press),
             Button::TopMiddle => ButtonPanel::TopMiddle(press),
             Button::TopRight => ButtonPanel::TopRight(press),
@@ -68,11 +83,31 @@ impl From<ButtonPress> for Reading {
 }
 
 impl ButtonPress {
+    pub const fn button(self) -> Button {
+        use ButtonPress::*;
+        match self {
+            Short(button) | Long(button) | Double(button) | Triple(button) => {
+                button
+            }
+        }
+    }
+
     pub fn serialize(self) -> u8 {
         use ButtonPress::*;
         match self {
             Short(button) => button.serialize(),
             Long(button) => button.serialize() + 6,
+            Double(button) => button.serialize() + 12,
+            Triple(button) => button.serialize() + 18,
+        }
+    }
+
+    /// How often the button went down for this press
+    pub const fn taps(self) -> usize {
+        match self {
+            ButtonPress::Short(_) | ButtonPress::Long(_) => 1,
+            ButtonPress::Double(_) => 2,
+            ButtonPress::Triple(_) => 3,
         }
     }
 
@@ -81,11 +116,381 @@ impl ButtonPress {
         Ok(match byte {
             1..=6 => Short(Button::deserialize(byte)?),
             7..=12 => Long(Button::deserialize(byte - 6)?),
+            13..=18 => Double(Button::deserialize(byte - 12)?),
+            19..=24 => Triple(Button::deserialize(byte - 18)?),
             _ => return Err("Could not deserialize byte into ButtonPress"),
         })
     }
 }
 
+/// A button kept down past [`detect::HOLD_MS`]. Used for continuous actions
+/// like seeking. Starts with `Start`, then a `Repeat` every
+/// [`detect::HOLD_REPEAT_MS`] and finally a `Release`.
+#[derive(Format, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Hold {
+    Start(Button),
+    /// The number of repeats since the start, starting at 1
+    Repeat(Button, u16),
+    Release(Button),
+}
+
+impl Hold {
+    pub const fn button(self) -> Button {
+        match self {
+            Hold::Start(button)
+            | Hold::Repeat(button, _)
+            | Hold::Release(button) => button,
+        }
+    }
+
+    pub fn serialize(self) -> [u8; 4] {
+        let (phase, tick) = match self {
+            Hold::Start(_) => (1, 0u16),
+            Hold::Repeat(_, tick) => (2, tick),
+            Hold::Release(_) => (3, 0),
+        };
+        let [hi, lo] = tick.to_be_bytes();
+        [phase, self.button().serialize(), hi, lo]
+    }
+
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, &'static str> {
+        let &[phase, button, hi, lo] = bytes else {
+            return Err("Hold should be 4 bytes");
+        };
+        let button = Button::deserialize(button)?;
+        Ok(match phase {
+            1 => Hold::Start(button),
+            2 => Hold::Repeat(button, u16::from_be_bytes([hi, lo])),
+            3 => Hold::Release(button),
+            _ => return Err("Could not deserialize bytes into Hold"),
+        })
+    }
+}
+
+/// Two or more buttons pressed together, as a bitmask of buttons
+#[derive(Format, Clone, Copy, PartialEq, Eq, Debug, Default, Hash)]
+pub struct Chord(u8);
+
+impl Chord {
+    pub const EMPTY: Chord = Chord(0);
+
+    pub const fn of(buttons: &[Button]) -> Self {
+        let mut chord = Chord::EMPTY;
+        let mut i = 0;
+        while i < buttons.len() {
+            chord = chord.with(buttons[i]);
+            i += 1;
+        }
+        chord
+    }
+
+    const fn bit(button: Button) -> u8 {
+        1 << (button as u8 - 1)
+    }
+
+    #[must_use]
+    pub const fn with(self, button: Button) -> Self {
+        Chord(self.0 | Self::bit(button))
+    }
+
+    #[must_use]
+    pub const fn without(self, button: Button) -> Self {
+        Chord(self.0 & !Self::bit(button))
+    }
+
+    pub const fn contains(self, button: Button) -> bool {
+        self.0 & Self::bit(button) != 0
+    }
+
+    pub const fn len(self) -> u32 {
+        self.0.count_ones()
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn buttons(self) -> impl Iterator<Item = Button> {
+        Button::ALL
+            .into_iter()
+            .filter(move |button| self.contains(*button))
+    }
+
+    pub const fn serialize(self) -> u8 {
+        self.0
+    }
+
+    pub fn deserialize(byte: u8) -> Result<Self, &'static str> {
+        let chord = Chord(byte);
+        if byte & !Chord::of(&Button::ALL).0 != 0 {
+            return Err("Chord contains unknown buttons");
+        }
+        if chord.len() < 2 {
+            return Err("Chord needs at least two buttons");
+        }
+        Ok(chord)
+    }
+}
+
+/// Everything the panel sends to the host
+#[derive(Format, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Event {
+    Press(ButtonPress),
+    Hold(Hold),
+    Chord(Chord),
+}
+
+impl Event {
+    /// Write this event as a frame into `buf`, returns the frame length.
+    pub fn to_frame(self, buf: &mut [u8; frame::MAX_FRAME_LEN]) -> usize {
+        let res = match self {
+            Event::Press(press) => frame::encode(
+                frame::Kind::ButtonPress,
+                &[press.serialize()],
+                buf,
+            ),
+            Event::Hold(hold) => {
+                frame::encode(frame::Kind::Hold, &hold.serialize(), buf)
+            }
+            Event::Chord(chord) => {
+                frame::encode(frame::Kind::Chord, &[chord.serialize()], buf)
+            }
+        };
+        res.expect("buffer fits the largest frame")
+    }
+
+    pub fn from_frame(frame: &frame::Frame) -> Result<Self, &'static str> {
+        match (frame.kind, frame.payload) {
+            (frame::Kind::ButtonPress, &[byte]) => {
+                ButtonPress::deserialize(byte).map(Event::Press)
+            }
+            (frame::Kind::ButtonPress, _) => {
+                Err("ButtonPress frame should have a one byte payload")
+            }
+            (frame::Kind::Hold, payload) => {
+                Hold::deserialize(payload).map(Event::Hold)
+            }
+            (frame::Kind::Chord, &[byte]) => {
+                Chord::deserialize(byte).map(Event::Chord)
+            }
+            (frame::Kind::Chord, _) => {
+                Err("Chord frame should have a one byte payload")
+            }
+            (
+                frame::Kind::Hello
+                | frame::Kind::Ping
+                | frame::Kind::SetLed
+                | frame::Kind::Blink
+                | frame::Kind::Brightness
+                | frame::Kind::Ack
+                | frame::Kind::Welcome
+                | frame::Kind::Pong,
+                _,
+            ) => Err("Frame is not an event"),
+        }
+    }
+}
+
+impl From<ButtonPress> for Event {
+    fn from(press: ButtonPress) -> Self {
+        Event::Press(press)
+    }
+}
+
+impl From<Hold> for Event {
+    fn from(hold: Hold) -> Self {
+        Event::Hold(hold)
+    }
+}
+
+impl From<Chord> for Event {
+    fn from(chord: Chord) -> Self {
+        Event::Chord(chord)
+    }
+}
+
+#[derive(Format, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const OFF: Color = Color::rgb(0, 0, 0);
+    pub const WHITE: Color = Color::rgb(255, 255, 255);
+    pub const RED: Color = Color::rgb(255, 0, 0);
+    pub const GREEN: Color = Color::rgb(0, 255, 0);
+    pub const BLUE: Color = Color::rgb(0, 0, 255);
+    pub const YELLOW: Color = Color::rgb(255, 160, 0);
+    pub const PURPLE: Color = Color::rgb(160, 0, 255);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Color { r, g, b }
+    }
+}
+
+/// Blink `times` times. The on and off time are in steps of 10ms.
+#[derive(Format, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Blink {
+    pub on: u8,
+    pub off: u8,
+    pub times: u8,
+}
+
+impl Blink {
+    /// Something went wrong
+    pub const ERROR: Blink = Blink::new(100, 100, 3);
+    /// Short flash to confirm a press
+    pub const ACK: Blink = Blink::new(80, 0, 1);
+
+    #[allow(clippy::cast_possible_truncation)] // clamped to fit
+    pub const fn new(on_ms: u16, off_ms: u16, times: u8) -> Self {
+        const MAX_MS: u16 = u8::MAX as u16 * 10;
+        let on_ms = if on_ms > MAX_MS { MAX_MS } else { on_ms };
+        let off_ms = if off_ms > MAX_MS { MAX_MS } else { off_ms };
+        Blink {
+            on: (on_ms / 10) as u8,
+            off: (off_ms / 10) as u8,
+            times,
+        }
+    }
+}
+
+/// Everything the host sends to the panel
+#[derive(Format, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Command {
+    /// Set the led of a button, `None` sets all of them
+    SetLed(Option<Button>, Color),
+    /// Blink the led of a button (or all), then go back to the set color
+    Blink(Option<Button>, Color, Blink),
+    /// Brightness of all leds, 0 is off
+    Brightness(u8),
+    /// Let the user know the host got this press
+    Ack(ButtonPress),
+}
+
+fn serialize_target(button: Option<Button>) -> u8 {
+    button.map_or(0, Button::serialize)
+}
+
+fn deserialize_target(byte: u8) -> Result<Option<Button>, &'static str> {
+    match byte {
+        0 => Ok(None),
+        _ => Button::deserialize(byte).map(Some),
+    }
+}
+
+impl Command {
+    /// Write this command as a frame into `buf`, returns the frame length.
+    pub fn to_frame(self, buf: &mut [u8; frame::MAX_FRAME_LEN]) -> usize {
+        use frame::Kind;
+
+        let res = match self {
+            Command::SetLed(button, Color { r, g, b }) => frame::encode(
+                Kind::SetLed,
+                &[serialize_target(button), r, g, b],
+                buf,
+            ),
+            Command::Blink(button, Color { r, g, b }, blink) => frame::encode(
+                Kind::Blink,
+                &[
+                    serialize_target(button),
+                    r,
+                    g,
+                    b,
+                    blink.on,
+                    blink.off,
+                    blink.times,
+                ],
+                buf,
+            ),
+            Command::Brightness(level) => {
+                frame::encode(Kind::Brightness, &[level], buf)
+            }
+            Command::Ack(press) => {
+                frame::encode(Kind::Ack, &[press.serialize()], buf)
+            }
+        };
+        res.expect("buffer fits the largest frame")
+    }
+
+    pub fn from_frame(frame: &frame::Frame) -> Result<Self, &'static str> {
+        use frame::Kind;
+
+        Ok(match (frame.kind, frame.payload) {
+            (Kind::SetLed, &[target, r, g, b]) => {
+                Command::SetLed(deserialize_target(target)?, Color { r, g, b })
+            }
+            (Kind::Blink, &[target, r, g, b, on, off, times]) => {
+                Command::Blink(
+                    deserialize_target(target)?,
+                    Color { r, g, b },
+                    Blink { on, off, times },
+                )
+            }
+            (Kind::Brightness, &[level]) => Command::Brightness(level),
+            (Kind::Ack, &[press]) => {
+                Command::Ack(ButtonPress::deserialize(press)?)
+            }
+            (Kind::SetLed | Kind::Blink | Kind::Brightness | Kind::Ack, _) => {
+                return Err("Command frame has the wrong payload length")
+            }
+            (
+                Kind::ButtonPress
+                | Kind::Hold
+                | Kind::Chord
+                | Kind::Hello
+                | Kind::Ping
+                | Kind::Welcome
+                | Kind::Pong,
+                _,
+            ) => return Err("Frame is not a command"),
+        })
+    }
+}
+
+/// Longest panel id that fits in a [`Hello`]
+pub const MAX_ID_LEN: usize = frame::MAX_PAYLOAD - 1;
+
+/// First frame a panel on the network sends, it introduces the panel
+#[derive(Format, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Hello<'a> {
+    /// [`frame::VERSION`] the panel speaks
+    pub version: u8,
+    pub id: &'a str,
+}
+
+impl<'a> Hello<'a> {
+    /// Write this hello as a frame into `buf`, returns the frame length.
+    pub fn to_frame(
+        self,
+        buf: &mut [u8; frame::MAX_FRAME_LEN],
+    ) -> Result<usize, &'static str> {
+        let id = self.id.as_bytes();
+        if id.len() > MAX_ID_LEN {
+            return Err("Panel id is too long");
+        }
+        let mut payload = [0; frame::MAX_PAYLOAD];
+        payload[0] = self.version;
+        payload[1..=id.len()].copy_from_slice(id);
+        let res = frame::encode(frame::Kind::Hello, &payload[..=id.len()], buf);
+        Ok(res.expect("buffer fits the largest frame"))
+    }
+
+    pub fn from_frame(frame: &frame::Frame<'a>) -> Result<Self, &'static str> {
+        match (frame.kind, frame.payload) {
+            (frame::Kind::Hello, [version, id @ ..]) => Ok(Hello {
+                version: *version,
+                id: core::str::from_utf8(id)
+                    .map_err(|_| "Panel id is not utf-8")?,
+            }),
+            (frame::Kind::Hello, []) => Err("Hello frame without a version"),
+            _ => Err("Frame is not a hello"),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Button::*;
@@ -103,7 +508,7 @@ mod tests {
             BottomRight,
         ];
 
-        for press in [Short, Long] {
+        for press in [Short, Long, Double, Triple] {
             for button in buttons {
                 let buttonpress = press(button);
                 let serialized = buttonpress.serialize();
@@ -118,11 +523,81 @@ mod tests {
     fn test_nonsense_values() {
         for byte in 0..u8::MAX {
             let res = match byte {
-                1..=12 => continue,
+                1..=24 => continue,
                 _ => ButtonPress::deserialize(byte),
             };
 
             assert!(res.is_err());
         }
     }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        use super::{frame, Chord, Event, Hold};
+
+        let events = [
+            Event::Press(Long(BottomRight)),
+            Event::Hold(Hold::Start(TopLeft)),
+            Event::Hold(Hold::Repeat(TopLeft, 300)),
+            Event::Hold(Hold::Release(TopLeft)),
+            Event::Chord(Chord::of(&[TopLeft, BottomRight])),
+        ];
+        for event in events {
+            let mut buf = [0; frame::MAX_FRAME_LEN];
+            let len = event.to_frame(&mut buf);
+            let (frame, _) = frame::decode(&buf[..len]).unwrap();
+            assert_eq!(Event::from_frame(&frame), Ok(event));
+        }
+    }
+
+    #[test]
+    fn test_chord_needs_two_known_buttons() {
+        use super::Chord;
+
+        assert!(Chord::deserialize(0).is_err());
+        assert!(Chord::deserialize(Chord::of(&[TopLeft]).serialize()).is_err());
+        assert!(Chord::deserialize(0b100_0001).is_err());
+
+        let chord = Chord::of(&[TopMiddle, BottomMiddle]);
+        assert_eq!(Chord::deserialize(chord.serialize()), Ok(chord));
+        assert!(chord.buttons().eq([TopMiddle, BottomMiddle]));
+    }
+
+    #[test]
+    fn test_command_roundtrip() {
+        use super::{frame, Blink, Color, Command};
+
+        let commands = [
+            Command::SetLed(Some(TopMiddle), Color::rgb(1, 2, 3)),
+            Command::SetLed(None, Color::OFF),
+            Command::Blink(None, Color::RED, Blink::ERROR),
+            Command::Brightness(42),
+            Command::Ack(Long(TopRight)),
+        ];
+        for command in commands {
+            let mut buf = [0; frame::MAX_FRAME_LEN];
+            let len = command.to_frame(&mut buf);
+            let (frame, _) = frame::decode(&buf[..len]).unwrap();
+            assert_eq!(Command::from_frame(&frame), Ok(command));
+        }
+    }
+
+    #[test]
+    fn test_hello_roundtrip() {
+        use super::{frame, Hello, MAX_ID_LEN};
+
+        let hello = Hello {
+            version: frame::VERSION,
+            id: "bedroom",
+        };
+        let mut buf = [0; frame::MAX_FRAME_LEN];
+        let len = hello.to_frame(&mut buf).unwrap();
+        let (frame, _) = frame::decode(&buf[..len]).unwrap();
+        assert_eq!(Hello::from_frame(&frame), Ok(hello));
+
+        let long = [b'x'; MAX_ID_LEN + 1];
+        let long = core::str::from_utf8(&long).unwrap();
+        let hello = Hello { id: long, ..hello };
+        assert!(hello.to_frame(&mut buf).is_err());
+    }
 }
@@ -1,4 +1,8 @@
-use color_eyre::eyre::Context;
+//! Runs the host with a mock panel that presses the bottom left button.
+//! Pass the mpd host as the first argument, defaults to localhost.
+
+use std::path::Path;
+
 use color_eyre::Result;
 
 use control::panel;
@@ -9,12 +13,18 @@ async fn main() -> Result<()> {
     control::setup_tracing();
 
     let args = control::Args {
-        ip: "192.168.1.101".to_owned(),
+        ip: std::env::args().nth(1).unwrap_or("127.0.0.1".to_owned()),
         ..control::Args::default()
     };
 
-    let panel =
-        panel::Mock::bottom_left_only().wrap_err("Could not connect to Panel")?;
+    let scenario = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("scenarios")
+        .join("press_bottom_left.toml");
+    let (panel, outcome) = panel::Mock::load(&scenario)?;
+    let id = panel::PanelId::main();
 
-    control::run(panel, args).await
+    tokio::select! {
+        res = control::run(id, panel, args) => res,
+        res = outcome.wait() => res,
+    }
 }
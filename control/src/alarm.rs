@@ -0,0 +1,586 @@
+//! Alarms that play the wake-up playlist. A one-off alarm goes off once, on
+//! its date, a recurring one on each of its weekdays. They are kept in the
+//! database together with how far the schedule got, alarms missed while we
+//! were down (a reboot) still go off if they are no more than
+//! `alarm.catch_up_mins` late.
+//!
+//! A ringing alarm can be snoozed, that pauses playback for
+//! `alarm.delay_mins`. Doing anything else on a panel dismisses it.
+//!
+//! The wake-up playlist starts quietly and gets louder following
+//! `alarm.ramp`, until a button is pressed.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use chrono::{Datelike, Local, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use color_eyre::eyre::{eyre, Context};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::audiocontrol::{AudioHandle, AudioMode, ForceRewind};
+use crate::config::{self, Config};
+
+/// Check the schedule at least this often, the clock may jump
+const RECHECK: Duration = Duration::from_secs(60);
+/// Only the last time a recurring alarm should have gone off matters
+const LOOK_BACK_DAYS: u64 = 8;
+/// How often the volume goes up during a ramp
+const RAMP_STEP: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Alarm {
+    pub time: NaiveTime,
+    /// day of a one-off alarm, the first `time` to come if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<NaiveDate>,
+    /// days a recurring alarm goes off
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub days: Vec<Weekday>,
+}
+
+impl fmt::Display for Alarm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.time.format("%H:%M"))?;
+        if let Some(date) = self.date {
+            write!(f, " on {date}")?;
+        }
+        if !self.days.is_empty() {
+            let days: Vec<_> =
+                self.days.iter().map(ToString::to_string).collect();
+            write!(f, " on {}", days.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+impl Alarm {
+    /// Check the alarm can go off and give a one-off alarm without date the
+    /// first date its time comes after `now`
+    fn settle(mut self, now: NaiveDateTime) -> Result<Self, String> {
+        match (self.date, self.days.is_empty()) {
+            (Some(_), false) => {
+                return Err("An alarm has either a date or days".to_owned())
+            }
+            (None, true) => {
+                let today = now.date().and_time(self.time);
+                let date = if today > now {
+                    now.date()
+                } else {
+                    now.date().succ_opt().expect("not the end of time")
+                };
+                self.date = Some(date);
+            }
+            _ => (),
+        }
+        if self.next_after(now).is_none() {
+            return Err(format!("Alarm {self} is in the past"));
+        }
+        Ok(self)
+    }
+
+    fn is_one_off(&self) -> bool {
+        self.date.is_some()
+    }
+
+    /// When the alarm goes off first after `after`
+    fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        if let Some(date) = self.date {
+            let at = date.and_time(self.time);
+            return (at > after).then_some(at);
+        }
+        after
+            .date()
+            .iter_days()
+            .take(8)
+            .filter(|day| self.days.contains(&day.weekday()))
+            .map(|day| day.and_time(self.time))
+            .find(|at| *at > after)
+    }
+}
+
+/// Everything about alarms that is kept in the database
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    /// by id
+    pub alarms: BTreeMap<u32, Alarm>,
+    /// local time up to which the alarms were handled
+    pub checked_until: Option<NaiveDateTime>,
+}
+
+impl Schedule {
+    fn add(&mut self, alarm: Alarm) -> u32 {
+        let id = self.alarms.keys().next_back().map_or(1, |last| last + 1);
+        self.alarms.insert(id, alarm);
+        id
+    }
+
+    /// The first time any alarm goes off after `after`
+    fn next_after(&self, after: NaiveDateTime) -> Option<NaiveDateTime> {
+        self.alarms
+            .values()
+            .filter_map(|alarm| alarm.next_after(after))
+            .min()
+    }
+
+    /// The alarms that should have gone off since the last check, with the
+    /// last time they should have. One-off alarms are done after that.
+    fn due(&mut self, now: NaiveDateTime) -> Vec<(u32, Alarm, NaiveDateTime)> {
+        let look_back = now - chrono::Duration::days(LOOK_BACK_DAYS as i64);
+        // without a previous check nothing was missed
+        let since = self.checked_until.unwrap_or(now).clamp(look_back, now);
+        self.checked_until = Some(now);
+
+        let mut due = Vec::new();
+        for (&id, alarm) in &self.alarms {
+            let mut last = None;
+            let mut after = since;
+            while let Some(at) = alarm.next_after(after).filter(|at| *at <= now)
+            {
+                last = Some(at);
+                after = at;
+            }
+            if let Some(at) = last {
+                due.push((id, alarm.clone(), at));
+            }
+        }
+        self.alarms.retain(|_, alarm| {
+            !alarm.is_one_off() || alarm.next_after(now).is_some()
+        });
+        due
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    schedule: Schedule,
+    ringing: bool,
+    snoozed_until: Option<Instant>,
+    ramp: Option<JoinHandle<()>>,
+}
+
+/// Keeps the alarms and sounds them, clones share the schedule
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    state: Arc<Mutex<State>>,
+    /// wakes [`run`](Self::run) when the schedule changed
+    changed: Arc<Notify>,
+    audio: AudioHandle,
+    config: config::Alarm,
+}
+
+impl Scheduler {
+    /// Uses the schedule stored in the database of `audio`
+    pub async fn load(
+        audio: AudioHandle,
+        config: config::Alarm,
+    ) -> Result<Self, String> {
+        let schedule = audio.call(|audio| audio.schedule()).await?;
+        let state = State {
+            schedule,
+            ..State::default()
+        };
+        Ok(Self {
+            state: Arc::new(Mutex::new(state)),
+            changed: Arc::new(Notify::new()),
+            audio,
+            config,
+        })
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("not poisoned")
+    }
+
+    async fn save(&self) -> Result<(), String> {
+        let schedule = self.state().schedule.clone();
+        self.audio
+            .call(move |audio| audio.store_schedule(&schedule))
+            .await
+    }
+
+    pub fn alarms(&self) -> BTreeMap<u32, Alarm> {
+        self.state().schedule.alarms.clone()
+    }
+
+    /// Returns the id of the new alarm
+    pub async fn add(&self, alarm: Alarm) -> Result<u32, String> {
+        let alarm = alarm.settle(Local::now().naive_local())?;
+        info!("Adding alarm {alarm}");
+        let id = self.state().schedule.add(alarm);
+        self.changed.notify_one();
+        self.save().await?;
+        Ok(id)
+    }
+
+    pub async fn remove(&self, id: u32) -> Result<(), String> {
+        let removed = self.state().schedule.alarms.remove(&id);
+        let Some(alarm) = removed else {
+            return Err(format!("No alarm with id {id}"));
+        };
+        info!("Removed alarm {alarm}");
+        self.save().await
+    }
+
+    /// Play a freshly shuffled wake-up playlist, or the alarm sound if that
+    /// does not work out
+    pub async fn ring(&self) -> Result<(), String> {
+        self.stop_ramp();
+        let mode = self.config.mode.clone();
+        let volume = self.config.ramp.volume_at(Duration::ZERO);
+        match wake_up(&self.audio, mode, volume).await {
+            Ok(true) => self.start_ramp(),
+            Ok(false) => info!("Player has no mixer, not ramping the volume"),
+            Err(err) => {
+                error!("Could not play the wake-up playlist: {err}");
+                let sound = self.config.sound.to_string_lossy().into_owned();
+                let volume = self.config.ramp.target_volume;
+                self.audio
+                    .try_call(move |audio| {
+                        if audio.volume()?.is_some() {
+                            audio.set_volume(volume)?;
+                        }
+                        audio.play_file(&sound)
+                    })
+                    .await?;
+            }
+        }
+        let mut state = self.state();
+        state.ringing = true;
+        state.snoozed_until = None;
+        Ok(())
+    }
+
+    /// Whether an alarm went off and was not dismissed yet, a snoozed one
+    /// is still ringing
+    pub fn is_ringing(&self) -> bool {
+        self.state().ringing
+    }
+
+    /// Pause the ringing alarm, it goes on after `alarm.delay_mins`
+    pub async fn snooze(&self) -> Result<(), String> {
+        if !self.state().ringing {
+            return Err("No alarm is ringing".to_owned());
+        }
+        let delay = Duration::from_secs(self.config.delay_mins * 60);
+        info!("Snoozing for {delay:?}");
+        self.stop_ramp();
+        self.audio.try_call(|audio| audio.pause()).await?;
+        self.state().snoozed_until = Some(Instant::now() + delay);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    /// Stop treating the alarm as ringing, the panel was used
+    pub fn dismiss(&self) {
+        let mut state = self.state();
+        if state.ringing {
+            info!("Alarm dismissed");
+        }
+        state.ringing = false;
+        state.snoozed_until = None;
+    }
+
+    fn start_ramp(&self) {
+        if self.config.ramp.duration_mins == 0 {
+            return;
+        }
+        let ramp = ramp_volume(self.audio.clone(), self.config.ramp.clone());
+        self.state().ramp = Some(tokio::task::spawn(ramp));
+    }
+
+    /// Leave the volume where it is, a button was pressed
+    pub fn stop_ramp(&self) {
+        if let Some(ramp) = self.state().ramp.take() {
+            if !ramp.is_finished() {
+                info!("Stopped the volume ramp");
+            }
+            ramp.abort();
+        }
+    }
+
+    /// Sound the alarms when they are due, runs forever
+    pub async fn run(self) {
+        loop {
+            self.check().await;
+
+            let now = Local::now().naive_local();
+            let next = self.state().schedule.next_after(now);
+            let mut wait = next
+                .and_then(|next| (next - now).to_std().ok())
+                .map_or(RECHECK, |wait| wait.min(RECHECK));
+            if let Some(snoozed_until) = self.state().snoozed_until {
+                wait = wait.min(snoozed_until - Instant::now());
+            }
+            tokio::select! {
+                () = tokio::time::sleep(wait) => (),
+                () = self.changed.notified() => (),
+            }
+        }
+    }
+
+    async fn check(&self) {
+        let snooze_over = {
+            let mut state = self.state();
+            let over = state
+                .snoozed_until
+                .is_some_and(|until| until <= Instant::now());
+            if over {
+                state.snoozed_until = None;
+            }
+            over
+        };
+        if snooze_over {
+            info!("Snooze is over");
+            let resume = self
+                .audio
+                .try_call(|audio| audio.play(ForceRewind::No))
+                .await;
+            if let Err(err) = resume {
+                error!("Could not resume after snoozing: {err}");
+            }
+        }
+
+        let now = Local::now().naive_local();
+        let due = self.state().schedule.due(now);
+        let catch_up = Duration::from_secs(self.config.catch_up_mins * 60);
+        let mut ring = false;
+        for (id, alarm, at) in due {
+            let late = (now - at).to_std().unwrap_or_default();
+            if late <= catch_up {
+                info!("Alarm {id} ({alarm}) goes off");
+                ring = true;
+            } else {
+                warn!("Missed alarm {id} ({alarm}) at {at}, it is too late");
+            }
+        }
+        if ring {
+            if let Err(err) = self.ring().await {
+                error!("Could not sound the alarm: {err}");
+            }
+        }
+        if let Err(err) = self.save().await {
+            error!("Could not store the alarms: {err}");
+        }
+    }
+}
+
+/// Play the wake-up playlist at `volume`, returns whether the volume can
+/// be changed
+async fn wake_up(
+    audio: &AudioHandle,
+    mode: AudioMode,
+    volume: i8,
+) -> Result<bool, String> {
+    audio
+        .try_call(move |audio| {
+            audio.reconnect()?;
+            let pl_name = "music_wakeup";
+            audio.create_wakeup_playlist(pl_name)?;
+            let mixer = audio.volume()?.is_some();
+            if mixer {
+                audio.set_volume(volume)?;
+            }
+            audio.play_mode_playlist(&mode, pl_name)?;
+            Ok(mixer)
+        })
+        .await
+}
+
+/// Raise the volume along `ramp`, starts with the first song
+async fn ramp_volume(audio: AudioHandle, ramp: config::Ramp) {
+    info!("Ramping the volume up over {} minutes", ramp.duration_mins);
+    let start = Instant::now();
+    let mut volume = ramp.volume_at(Duration::ZERO);
+    while start.elapsed() < ramp.duration() {
+        tokio::time::sleep(RAMP_STEP).await;
+        let next = ramp.volume_at(start.elapsed());
+        if next == volume {
+            continue;
+        }
+        volume = next;
+        let res = audio.try_call(move |audio| audio.set_volume(next)).await;
+        if let Err(err) = res {
+            error!("Stopped the volume ramp: {err}");
+            return;
+        }
+    }
+    info!("Volume ramp done");
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// Show the alarms
+    List,
+    /// Add an alarm, once or on the given weekdays
+    Add {
+        /// for example 07:30
+        time: NaiveTime,
+        /// day of a one-off alarm, the first time to come if not set
+        #[clap(long, conflicts_with = "days")]
+        date: Option<NaiveDate>,
+        /// weekdays of a recurring alarm, for example: mon,tue,fri
+        #[clap(long, value_delimiter = ',')]
+        days: Vec<Weekday>,
+    },
+    /// Remove an alarm by its id
+    Remove { id: u32 },
+    /// Snooze the ringing alarm
+    Snooze,
+}
+
+/// Manage the alarms of the running instance through its api
+pub async fn cli(
+    config: Option<&Path>,
+    command: Command,
+) -> color_eyre::Result<()> {
+    let config = Config::load(config)?;
+    let (route, body) = match command {
+        Command::List => ("alarms", None),
+        Command::Add { time, date, days } => {
+            let alarm = Alarm { time, date, days };
+            ("add_alarm", Some(serde_json::to_string(&alarm)?))
+        }
+        Command::Remove { id } => (
+            "remove_alarm",
+            Some(serde_json::json!({ "id": id }).to_string()),
+        ),
+        Command::Snooze => ("snooze", Some(String::new())),
+    };
+
+    let url = crate::status::api_url(&config, route);
+    let client = reqwest::Client::new();
+    let request = match body {
+        Some(body) => client.post(&url).body(body),
+        None => client.get(&url),
+    };
+    let response = request
+        .send()
+        .await
+        .wrap_err_with(|| format!("Could not reach {url}"))?;
+    let failed = !response.status().is_success();
+    let body = response.text().await.wrap_err("Could not read answer")?;
+    let answer: serde_json::Value =
+        serde_json::from_str(&body).wrap_err("Invalid answer")?;
+    if failed {
+        return Err(eyre!("{}", answer["error"].as_str().unwrap_or(&body)));
+    }
+
+    if let Some(id) = answer["id"].as_u64() {
+        println!("Added alarm {id}");
+    } else if route == "alarms" {
+        let alarms: BTreeMap<u32, Alarm> = serde_json::from_value(answer)?;
+        if alarms.is_empty() {
+            println!("No alarms");
+        }
+        for (id, alarm) in alarms {
+            println!("{id:>3}  {alarm}");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(date: &str, time: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(&format!("{date} {time}"), "%F %R")
+            .unwrap()
+    }
+
+    fn time(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%R").unwrap()
+    }
+
+    #[test]
+    fn next_occurrence() {
+        // a saturday
+        let now = at("2026-10-17", "08:00");
+        let weekdays = Alarm {
+            time: time("07:30"),
+            date: None,
+            days: vec![Weekday::Mon, Weekday::Sat],
+        };
+        assert_eq!(weekdays.next_after(now), Some(at("2026-10-19", "07:30")));
+        let weekend = Alarm {
+            time: time("09:00"),
+            ..weekdays.clone()
+        };
+        assert_eq!(weekend.next_after(now), Some(at("2026-10-17", "09:00")));
+
+        let once = Alarm {
+            time: time("07:30"),
+            date: None,
+            days: Vec::new(),
+        };
+        let once = once.settle(now).unwrap();
+        assert_eq!(once.date, NaiveDate::from_ymd_opt(2026, 10, 18));
+        let past = Alarm {
+            date: NaiveDate::from_ymd_opt(2026, 10, 1),
+            ..once.clone()
+        };
+        assert!(past.settle(now).is_err());
+        let both = Alarm {
+            days: vec![Weekday::Mon],
+            ..once
+        };
+        assert!(both.settle(now).is_err());
+    }
+
+    #[test]
+    fn missed_alarms_are_due_once() {
+        let mut schedule = Schedule::default();
+        let once = Alarm {
+            time: time("07:00"),
+            date: NaiveDate::from_ymd_opt(2026, 10, 19),
+            days: Vec::new(),
+        };
+        let daily = Alarm {
+            time: time("06:30"),
+            date: None,
+            days: vec![Weekday::Mon, Weekday::Tue],
+        };
+        let once = schedule.add(once);
+        let daily = schedule.add(daily);
+
+        // the first check only starts the clock
+        assert!(schedule.due(at("2026-10-17", "12:00")).is_empty());
+        // down from saturday until monday morning
+        let due = schedule.due(at("2026-10-19", "07:10"));
+        let due: Vec<_> = due.into_iter().map(|(id, _, at)| (id, at)).collect();
+        assert_eq!(
+            due,
+            [
+                (once, at("2026-10-19", "07:00")),
+                (daily, at("2026-10-19", "06:30"))
+            ]
+        );
+        assert!(!schedule.alarms.contains_key(&once), "one-off is done");
+        assert!(schedule.due(at("2026-10-19", "07:20")).is_empty());
+
+        // down for two weeks, only the last one counts
+        let due = schedule.due(at("2026-11-03", "06:31"));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].2, at("2026-11-03", "06:30"));
+    }
+
+    #[test]
+    fn alarms_as_json() {
+        let alarm: Alarm = serde_json::from_str(
+            r#"{"time": "07:30", "days": ["mon", "Fri"]}"#,
+        )
+        .unwrap();
+        assert_eq!(alarm.days, [Weekday::Mon, Weekday::Fri]);
+        assert_eq!(alarm.to_string(), "07:30 on Mon, Fri");
+        let json = serde_json::to_value(&alarm).unwrap();
+        assert!(json.get("date").is_none());
+    }
+}
@@ -0,0 +1,542 @@
+//! Http api for controlling the audio, used by home automation for example to
+//! sound the alarm. Apart from `GET /status` and `GET /events` every route
+//! takes a `POST` with an (optional) json body. Answers are json:
+//! `{"ok": true}`, the [`Status`](crate::status::Status) or
+//! `{"error": "<what went wrong>"}`. `/events` streams the
+//! [`Event`](crate::events::Event)s as server sent events.
+//!
+//! | route             | body                                       |
+//! |-------------------|--------------------------------------------|
+//! | `/status` (`GET`) | -                                          |
+//! | `/events` (`GET`) | -                                          |
+//! | `/alarms` (`GET`) | -                                          |
+//! | `/<action>`       | -                                          |
+//! | `/go_to_mode`     | `{"mode": "Podcast"}`                      |
+//! | `/go_to_playlist` | `{"playlist": "music_all"}`                |
+//! | `/insert_next`    | `{"song": "path/to/song.mp3"}`             |
+//! | `/alarm`          | -                                          |
+//! | `/snooze`         | -                                          |
+//! | `/add_alarm`      | `{"time": "07:30", "days": ["Mon"]}`       |
+//! | `/remove_alarm`   | `{"id": 1}`                                |
+//!
+//! Where `<action>` is one of: `toggle_playback`, `play`, `pause`, `stop`,
+//! `next`, `previous`, `skip`, `rewind`, `prev_playlist`, `next_playlist` or
+//! `next_mode`. For backwards compatibility a `POST /` with the body `alarm`
+//! also sounds the alarm.
+//!
+//! `/alarms` answers with the [`Alarm`]s by id. An alarm has either a
+//! `date` (`"2026-10-18"`), then it goes off once, or `days`. With neither
+//! it goes off the next time it is `time`. `/add_alarm` answers with the id
+//! of the new alarm: `{"id": 3}`.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hyper::header::{ALLOW, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Response, Server, StatusCode};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
+
+use crate::alarm::{Alarm, Scheduler};
+use crate::audiocontrol::{AudioError, AudioHandle, ForceRewind};
+use crate::events::{Event, Events};
+use crate::keymap::Action;
+use crate::status::SharedHealth;
+
+/// Bodies are tiny, anything larger is a mistake
+const MAX_BODY: usize = 16 * 1024;
+/// Proxies tend to close connections that are quiet for too long
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    Action(Action),
+    GoToMode { mode: String },
+    GoToPlaylist { playlist: String },
+    InsertNext { song: String },
+    Alarm,
+    Snooze,
+    Alarms,
+    AddAlarm(Alarm),
+    RemoveAlarm { id: u32 },
+    Status,
+    Events,
+}
+
+impl Request {
+    /// Name in the action events, for requests that do something
+    fn action_name(&self) -> Option<&'static str> {
+        match self {
+            Request::Action(action) => Some(action.name()),
+            Request::GoToMode { .. } => Some("go_to_mode"),
+            Request::GoToPlaylist { .. } => Some("go_to_playlist"),
+            Request::InsertNext { .. } => Some("insert_next"),
+            Request::Alarm => Some("alarm"),
+            Request::Snooze => Some(Action::Snooze.name()),
+            Request::Alarms
+            | Request::AddAlarm(_)
+            | Request::RemoveAlarm { .. }
+            | Request::Status
+            | Request::Events => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GoToMode {
+    mode: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GoToPlaylist {
+    playlist: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InsertNext {
+    song: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RemoveAlarm {
+    id: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Error {
+    status: StatusCode,
+    message: String,
+}
+
+impl Error {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    /// The request is fine but can not be done right now
+    fn conflict(message: String) -> Self {
+        Self::new(StatusCode::CONFLICT, message)
+    }
+}
+
+impl From<AudioError> for Error {
+    fn from(err: AudioError) -> Self {
+        if err.is_unavailable() {
+            Self::new(StatusCode::SERVICE_UNAVAILABLE, err.to_string())
+        } else {
+            Self::conflict(err.to_string())
+        }
+    }
+}
+
+fn json<T: DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+    serde_json::from_slice(body).map_err(|err| {
+        Error::new(StatusCode::BAD_REQUEST, format!("Invalid body: {err}"))
+    })
+}
+
+/// Actions the panel can do that make sense over the api
+fn api_action(name: &str) -> Option<Action> {
+    Action::from_name(name).filter(|action| {
+        !matches!(
+            action,
+            Action::SendToDataServer
+                | Action::SleepTimer
+                | Action::Snooze
+                | Action::LockPanel
+                | Action::Nothing
+        )
+    })
+}
+
+/// `None` if there is no such route
+fn allowed_method(route: &str) -> Option<Method> {
+    match route {
+        "status" | "events" | "alarms" => Some(Method::GET),
+        "go_to_mode" | "go_to_playlist" | "insert_next" | "alarm"
+        | "snooze" | "add_alarm" | "remove_alarm" => Some(Method::POST),
+        action => api_action(action).map(|_| Method::POST),
+    }
+}
+
+fn parse(method: &Method, path: &str, body: &[u8]) -> Result<Request, Error> {
+    let route = path.trim_matches('/');
+    let legacy_alarm = body.trim_ascii() == b"alarm";
+    if route.is_empty() && method == Method::POST && legacy_alarm {
+        warn!("Deprecated alarm request, use `POST /alarm` instead");
+        return Ok(Request::Alarm);
+    }
+
+    let Some(allowed) = allowed_method(route) else {
+        return Err(Error::new(
+            StatusCode::NOT_FOUND,
+            format!("Unknown route: {path}"),
+        ));
+    };
+    if method != allowed {
+        return Err(Error::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            format!("{path} only supports {allowed}"),
+        ));
+    }
+
+    Ok(match route {
+        "status" => Request::Status,
+        "events" => Request::Events,
+        "alarms" => Request::Alarms,
+        "add_alarm" => Request::AddAlarm(json(body)?),
+        "remove_alarm" => Request::RemoveAlarm {
+            id: json::<RemoveAlarm>(body)?.id,
+        },
+        "go_to_mode" => Request::GoToMode {
+            mode: json::<GoToMode>(body)?.mode,
+        },
+        "go_to_playlist" => Request::GoToPlaylist {
+            playlist: json::<GoToPlaylist>(body)?.playlist,
+        },
+        "insert_next" => Request::InsertNext {
+            song: json::<InsertNext>(body)?.song,
+        },
+        route => {
+            if !body.trim_ascii().is_empty() {
+                json::<IgnoredAny>(body)?;
+            }
+            match route {
+                "alarm" => Request::Alarm,
+                "snooze" => Request::Snooze,
+                action => Request::Action(
+                    api_action(action).expect("checked to be known above"),
+                ),
+            }
+        }
+    })
+}
+
+fn ok(res: Result<(), Error>) -> Result<serde_json::Value, Error> {
+    res.map(|()| json!({ "ok": true }))
+}
+
+/// Performs the request and lets the event subscribers know
+async fn perform(
+    state: &State,
+    request: Request,
+) -> Result<serde_json::Value, Error> {
+    let action = request.action_name();
+    let res = perform_request(state, request).await;
+    if let Some(action) = action {
+        let reported = match &res {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err.message.clone()),
+        };
+        state.events.send(Event::performed(action, None, &reported));
+    }
+    res
+}
+
+async fn perform_request(
+    state: &State,
+    request: Request,
+) -> Result<serde_json::Value, Error> {
+    let unavailable = |err| Error::new(StatusCode::SERVICE_UNAVAILABLE, err);
+    let alarms = &state.alarms;
+    match request {
+        Request::Alarm => return ok(alarms.ring().await.map_err(unavailable)),
+        Request::Snooze => {
+            return ok(alarms.snooze().await.map_err(Error::conflict))
+        }
+        Request::Alarms => {
+            return Ok(serde_json::to_value(alarms.alarms())
+                .expect("serializable"))
+        }
+        Request::AddAlarm(alarm) => {
+            let id = alarms.add(alarm).await.map_err(Error::conflict)?;
+            return Ok(json!({ "id": id }));
+        }
+        Request::RemoveAlarm { id } => {
+            return ok(alarms.remove(id).await.map_err(Error::conflict))
+        }
+        _ => (),
+    }
+
+    let done = state.audio.call(move |audio| {
+        match request {
+            Request::Action(action) => crate::perform_audio(audio, action)?,
+            Request::GoToMode { mode } => {
+                let Some(mode) = audio.modes.find(&mode).cloned() else {
+                    return Err(Error::new(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        format!("Unknown mode: {mode}"),
+                    ));
+                };
+                audio.go_to_mode(&mode)?;
+                audio.play(ForceRewind::No)?;
+            }
+            Request::GoToPlaylist { playlist } => {
+                audio.go_to_playlist(&playlist)?;
+                audio.play(ForceRewind::No)?;
+            }
+            Request::InsertNext { song } => {
+                audio.insert_next(&song)?;
+            }
+            Request::Status => {
+                return Ok(Some(audio.status()?));
+            }
+            Request::Alarm
+            | Request::Snooze
+            | Request::Alarms
+            | Request::AddAlarm(_)
+            | Request::RemoveAlarm { .. }
+            | Request::Events => unreachable!("handled above"),
+        }
+        Ok(None)
+    });
+
+    let Some(mut status) = done.await.map_err(unavailable)?? else {
+        return ok(Ok(()));
+    };
+    let health = state.health.lock().expect("not poisoned");
+    status.panels = health.panels.clone();
+    status.data_server = health.data_server.clone();
+    Ok(serde_json::to_value(status).expect("serializable"))
+}
+
+fn respond(
+    status: StatusCode,
+    path: &str,
+    body: &serde_json::Value,
+) -> Response<Body> {
+    let mut response = Response::new(Body::from(body.to_string()));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().expect("valid"));
+    if status == StatusCode::METHOD_NOT_ALLOWED {
+        if let Some(allowed) = allowed_method(path.trim_matches('/')) {
+            let allowed = allowed.as_str().parse().expect("valid");
+            response.headers_mut().insert(ALLOW, allowed);
+        }
+    }
+    response
+}
+
+async fn handle(
+    state: Arc<State>,
+    request: hyper::Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let method = request.method().clone();
+    let path = request.uri().path().to_owned();
+    info!("{method} {path}");
+
+    let res = async {
+        let body = read_body(request).await?;
+        state.events.send(Event::Request {
+            method: method.to_string(),
+            path: path.clone(),
+            body: String::from_utf8_lossy(&body).into_owned(),
+        });
+        let request = parse(&method, &path, &body)?;
+        if request == Request::Events {
+            return Ok(None);
+        }
+        perform(&state, request).await.map(Some)
+    }
+    .await;
+
+    Ok(match res {
+        Ok(None) => stream_events(&state.events),
+        Ok(Some(body)) => respond(StatusCode::OK, &path, &body),
+        Err(Error { status, message }) => {
+            warn!("{method} {path} failed ({status}): {message}");
+            respond(status, &path, &json!({ "error": message }))
+        }
+    })
+}
+
+/// Forwards events until the client goes away
+fn stream_events(events: &Events) -> Response<Body> {
+    let mut rx = events.subscribe();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut keepalive = tokio::time::interval(KEEPALIVE);
+        loop {
+            let message = tokio::select! {
+                event = rx.recv() => match event {
+                    // bodies can be private, only the recorder gets these
+                    Ok(Event::Request { .. }) => continue,
+                    Ok(event) => event.to_sse(),
+                    Err(RecvError::Lagged(missed)) => {
+                        Event::Lagged { missed }.to_sse()
+                    }
+                    Err(RecvError::Closed) => return,
+                },
+                _ = keepalive.tick() => ": keepalive\n\n".to_owned(),
+            };
+            if sender.send_data(message.into()).await.is_err() {
+                debug!("Event stream closed");
+                return;
+            }
+        }
+    });
+
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, "text/event-stream".parse().expect("valid"));
+    headers.insert(CACHE_CONTROL, "no-cache".parse().expect("valid"));
+    response
+}
+
+async fn read_body(request: hyper::Request<Body>) -> Result<Vec<u8>, Error> {
+    let too_large =
+        || Error::new(StatusCode::PAYLOAD_TOO_LARGE, "Body is too large");
+
+    let length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok());
+    if length.is_some_and(|len| len > MAX_BODY) {
+        return Err(too_large());
+    }
+
+    let body = hyper::body::to_bytes(request.into_body())
+        .await
+        .map_err(|err| {
+            Error::new(StatusCode::BAD_REQUEST, format!("Broken body: {err}"))
+        })?;
+    if body.len() > MAX_BODY {
+        return Err(too_large());
+    }
+    Ok(body.to_vec())
+}
+
+struct State {
+    audio: AudioHandle,
+    health: SharedHealth,
+    events: Events,
+    alarms: Scheduler,
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    audio: AudioHandle,
+    health: SharedHealth,
+    events: Events,
+    alarms: Scheduler,
+) -> Result<(), hyper::Error> {
+    let state = Arc::new(State {
+        audio,
+        health,
+        events,
+        alarms,
+    });
+    let make_service = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                handle(state.clone(), request)
+            }))
+        }
+    });
+
+    let incoming = AddrIncoming::from_listener(listener)?;
+    Server::builder(incoming).serve(make_service).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(method: Method, path: &str, body: &str) -> StatusCode {
+        match parse(&method, path, body.as_bytes()) {
+            Ok(_) => StatusCode::OK,
+            Err(err) => err.status,
+        }
+    }
+
+    #[test]
+    fn routes() {
+        let parse = |path, body: &str| {
+            parse(&Method::POST, path, body.as_bytes()).unwrap()
+        };
+
+        assert_eq!(parse("/next", ""), Request::Action(Action::Next));
+        assert_eq!(parse("/pause/", "{}"), Request::Action(Action::Pause));
+        assert_eq!(parse("/alarm", ""), Request::Alarm);
+        assert_eq!(parse("/", "alarm\n"), Request::Alarm);
+        assert_eq!(parse("/snooze", ""), Request::Snooze);
+        assert_eq!(
+            parse("/remove_alarm", r#"{"id": 2}"#),
+            Request::RemoveAlarm { id: 2 }
+        );
+        let Request::AddAlarm(alarm) =
+            parse("/add_alarm", r#"{"time": "06:45", "days": ["Sat"]}"#)
+        else {
+            panic!("not an alarm");
+        };
+        assert_eq!(alarm.to_string(), "06:45 on Sat");
+        assert_eq!(
+            parse("/go_to_mode", r#"{"mode": "podcast"}"#),
+            Request::GoToMode {
+                mode: "podcast".to_owned()
+            }
+        );
+        assert_eq!(
+            parse("/insert_next", r#"{"song": "a.mp3"}"#),
+            Request::InsertNext {
+                song: "a.mp3".to_owned()
+            }
+        );
+    }
+
+    #[test]
+    fn requests_that_do_something_are_actions() {
+        let parse = |path, body: &str| {
+            parse(&Method::POST, path, body.as_bytes()).unwrap()
+        };
+        let insert = parse("/insert_next", r#"{"song": "a.mp3"}"#);
+        assert_eq!(insert.action_name(), Some("insert_next"));
+        assert_eq!(parse("/next", "").action_name(), Some("next"));
+        assert_eq!(parse("/snooze", "").action_name(), Some("snooze"));
+        assert_eq!(parse("/remove_alarm", r#"{"id": 1}"#).action_name(), None);
+        let status = super::parse(&Method::GET, "/status", b"").unwrap();
+        assert_eq!(status.action_name(), None);
+    }
+
+    #[test]
+    fn status_codes() {
+        use StatusCode as S;
+
+        assert_eq!(status(Method::GET, "/next", ""), S::METHOD_NOT_ALLOWED);
+        assert_eq!(status(Method::GET, "/status", ""), S::OK);
+        assert_eq!(status(Method::GET, "/events", ""), S::OK);
+        assert_eq!(status(Method::POST, "/events", ""), S::METHOD_NOT_ALLOWED);
+        assert_eq!(status(Method::POST, "/status", ""), S::METHOD_NOT_ALLOWED);
+        assert_eq!(status(Method::POST, "/jump", ""), S::NOT_FOUND);
+        assert_eq!(status(Method::POST, "/lock_panel", ""), S::NOT_FOUND);
+        assert_eq!(status(Method::POST, "/alarms", ""), S::METHOD_NOT_ALLOWED);
+        let add_alarm = |body| status(Method::POST, "/add_alarm", body);
+        assert_eq!(add_alarm(r#"{"time": "25:00"}"#), S::BAD_REQUEST);
+        let bad_day = r#"{"time": "7:00", "days": ["x"]}"#;
+        assert_eq!(add_alarm(bad_day), S::BAD_REQUEST);
+        assert_eq!(status(Method::POST, "/", "wake up"), S::NOT_FOUND);
+        assert_eq!(status(Method::POST, "/next", "{"), S::BAD_REQUEST);
+        assert_eq!(status(Method::POST, "/go_to_mode", ""), S::BAD_REQUEST);
+        let go_to_mode = |body| status(Method::POST, "/go_to_mode", body);
+        assert_eq!(go_to_mode(r#"{"playlist": "all"}"#), S::BAD_REQUEST);
+        assert_eq!(go_to_mode(r#"{"mode": 3}"#), S::BAD_REQUEST);
+    }
+}
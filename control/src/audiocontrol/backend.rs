@@ -0,0 +1,118 @@
+//! What the [`AudioController`](super::AudioController) needs from a music
+//! player. mpd is the one we use, [`Memory`](super::Memory) only keeps state
+//! and is there for tests.
+
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlayState {
+    Stop,
+    Play,
+    Pause,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub state: PlayState,
+    /// percent, negative if the player can not change the volume
+    pub volume: i8,
+    /// position of the current song in the queue
+    pub song: Option<u32>,
+    pub elapsed: Option<Duration>,
+    pub duration: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Song {
+    pub file: String,
+    pub title: Option<String>,
+    /// position in the queue, if it is queued
+    pub pos: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// Needs a current song but there is none
+    NotPlaying,
+    BadSongIndex,
+    NoSuchPlaylist,
+    PlaylistExists,
+    /// The player refused for another reason
+    Refused(String),
+    /// Could not reach the player
+    Connection(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotPlaying => f.write_str("Not playing"),
+            Error::BadSongIndex => f.write_str("Bad song index"),
+            Error::NoSuchPlaylist => f.write_str("No such playlist"),
+            Error::PlaylistExists => f.write_str("Playlist already exists"),
+            Error::Refused(reason) => f.write_str(reason),
+            Error::Connection(err) => write!(f, "Connection failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The operations the controller uses, named after their mpd commands where
+/// that helps. Positions are in the queue, times in whole seconds.
+pub trait MusicBackend: Send {
+    /// Drop the connection and make a new one
+    fn reconnect(&mut self) -> Result<()>;
+    /// Look for new and changed files, returns once that is done
+    fn rescan(&mut self) -> Result<()>;
+
+    fn status(&mut self) -> Result<Status>;
+    fn current_song(&mut self) -> Result<Option<Song>>;
+    fn queue(&mut self) -> Result<Vec<Song>>;
+    /// Names of the stored playlists
+    fn playlists(&mut self) -> Result<Vec<String>>;
+    /// Songs in the stored playlist `name`
+    fn playlist(&mut self, name: &str) -> Result<Vec<Song>>;
+
+    fn play(&mut self) -> Result<()>;
+    fn toggle_pause(&mut self) -> Result<()>;
+    /// Pause if playing, does nothing when stopped
+    fn pause(&mut self) -> Result<()>;
+    fn stop(&mut self) -> Result<()>;
+    fn prev(&mut self) -> Result<()>;
+    fn next(&mut self) -> Result<()>;
+    /// Go to `secs` into the song at `pos`
+    fn seek(&mut self, pos: u32, secs: u32) -> Result<()>;
+    /// Go to `secs` into the current song
+    fn seek_current(&mut self, secs: u32) -> Result<()>;
+
+    fn repeat(&mut self, on: bool) -> Result<()>;
+    fn random(&mut self, on: bool) -> Result<()>;
+    fn single(&mut self, on: bool) -> Result<()>;
+    fn consume(&mut self, on: bool) -> Result<()>;
+    fn volume(&mut self, percent: i8) -> Result<()>;
+
+    /// Empty the queue
+    fn clear(&mut self) -> Result<()>;
+    /// Add the stored playlist `name` to the queue
+    fn load(&mut self, name: &str) -> Result<()>;
+    /// Add `file` to the queue, returns its id
+    fn push(&mut self, file: &str) -> Result<u32>;
+    /// Songs with a higher priority are played first in random mode
+    fn prioid(&mut self, id: u32, prio: u8) -> Result<()>;
+
+    /// Store the queue as playlist `name`, which must not exist
+    fn save(&mut self, name: &str) -> Result<()>;
+    fn pl_remove(&mut self, name: &str) -> Result<()>;
+    /// Empty the stored playlist `name`, creating it if needed
+    fn pl_clear(&mut self, name: &str) -> Result<()>;
+    /// Add `file` to the stored playlist `name`
+    fn pl_push(&mut self, name: &str, file: &str) -> Result<()>;
+
+    fn playlist_exists(&mut self, name: &str) -> bool {
+        self.playlist(name).is_ok()
+    }
+}
@@ -0,0 +1,78 @@
+//! What the controller remembers as json: per mode the current playlist, per
+//! playlist the position and when it was last played, the current mode and
+//! the alarms.
+//! For backups and moving that state to another instance. The database can
+//! only be opened once, stop the service first.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use tracing::warn;
+
+use super::migrate::LAYOUT_VERSION;
+use super::store::{self, Snapshot, Store};
+use super::{db, db2, AudioMode, Modes};
+
+/// Everything in the database at `database` as pretty printed json. Does
+/// not migrate the database, a backup should change nothing.
+pub fn export(database: &Path, modes: &Modes) -> Result<String, String> {
+    let snapshot = read(database, modes)?;
+    Ok(serde_json::to_string_pretty(&snapshot).expect("serializable"))
+}
+
+/// What is stored, from the old layout as long as it was not migrated
+fn read(database: &Path, modes: &Modes) -> Result<Snapshot, String> {
+    let layout = store::layout_path(database);
+    if layout.exists() {
+        let new = db2::Db::open(&layout)?;
+        if new.version() == Some(LAYOUT_VERSION) {
+            return Ok(new.snapshot(modes));
+        }
+    }
+    if !database.exists() {
+        return Ok(Snapshot::default());
+    }
+
+    let old = db::Db::open_unmigrated(database).map_err(|err| {
+        format!("Could not open database {}: {err}", database.display())
+    })?;
+    let (snapshot, skipped) = old.read_all(modes);
+    if !skipped.is_empty() {
+        warn!("Not exporting unknown keys: {}", skipped.join(", "));
+    }
+    Ok(snapshot)
+}
+
+/// Store everything in `json`, made by [`export`], in the database at
+/// `database`. What is in the database but not in `json` is kept. Returns
+/// a summary of what was imported.
+pub fn import(
+    database: &Path,
+    modes: &Modes,
+    json: &str,
+) -> Result<String, String> {
+    let snapshot: Snapshot = serde_json::from_str(json)
+        .map_err(|err| format!("Not an export: {err}"))?;
+    // the playlists of unknown modes would be stored but never used
+    let known = |mode: &AudioMode| modes.iter().any(|m| m.name == *mode);
+    let unknown: BTreeSet<_> = snapshot
+        .current_mode
+        .iter()
+        .chain(snapshot.playlists.keys())
+        .filter(|mode| !known(mode))
+        .map(AudioMode::name)
+        .collect();
+    if !unknown.is_empty() {
+        let unknown: Vec<_> = unknown.into_iter().collect();
+        return Err(format!("Unknown modes: {}", unknown.join(", ")));
+    }
+
+    store::open(database, modes)?.restore(&snapshot);
+    Ok(format!(
+        "Imported the playlist of {} modes, the position in {} playlists \
+        and when {} playlists were last played",
+        snapshot.playlists.len(),
+        snapshot.positions.len(),
+        snapshot.last_played.len(),
+    ))
+}
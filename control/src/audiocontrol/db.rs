@@ -1,22 +1,26 @@
-use super::AudioMode;
-use std::time::{SystemTime, UNIX_EPOCH};
-
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
-pub(crate) struct Position {
-    pub(crate) pos_in_pl: u32,
-    pub(crate) elapsed: u32,
+//! The first layout: every value under a key of its own, numbers in native
+//! endianness.
+
+use super::store::{Position, Snapshot, Store};
+use super::{AudioMode, Modes};
+use crate::alarm::Schedule;
+use std::path::Path;
+
+const CURRENT_MODE: &str = "current_mode";
+const PLAYLIST_SUFFIX: &str = "_cur_playlist";
+const POSITION_SUFFIX: &str = "_position";
+const LAST_PLAYED_SUFFIX: &str = "last_played";
+/// as json, alarms came after this layout
+const SCHEDULE: &str = "alarm_schedule";
+
+fn position_to_bytes(position: &Position) -> Vec<u8> {
+    [position.pos_in_pl.to_ne_bytes(), position.elapsed.to_ne_bytes()].concat()
 }
 
-impl Position {
-    fn to_bytes(&self) -> Vec<u8> {
-        [self.pos_in_pl.to_ne_bytes(), self.elapsed.to_ne_bytes()].concat()
-    }
-
-    fn from_bytes(bytes: &[u8]) -> Self {
-        Position {
-            pos_in_pl: u32::from_ne_bytes(bytes[..4].try_into().unwrap()),
-            elapsed: u32::from_ne_bytes(bytes[4..].try_into().unwrap()),
-        }
+fn position_from_bytes(bytes: &[u8]) -> Position {
+    Position {
+        pos_in_pl: u32::from_ne_bytes(bytes[..4].try_into().unwrap()),
+        elapsed: u32::from_ne_bytes(bytes[4..].try_into().unwrap()),
     }
 }
 
@@ -26,112 +30,165 @@ pub(crate) struct Db {
 }
 
 impl Db {
-    pub(crate) fn open(path: &str) -> Self {
-        Db {
-            database: sled::Config::default()
-                .path(path)
-                .cache_capacity(1_000_000)
-                .open()
-                .unwrap(),
+    /// Opens the database as it is, old values are only fixed up on reading
+    pub(crate) fn open_unmigrated(path: &Path) -> sled::Result<Self> {
+        let database = sled::Config::default()
+            .path(path)
+            .cache_capacity(1_000_000)
+            .open()?;
+        Ok(Db { database })
+    }
+
+    /// Everything stored and the keys that could not be made sense of.
+    /// Playlists of modes not in `modes` can not be told apart from other
+    /// keys and end up with the latter.
+    pub(crate) fn read_all(&self, modes: &Modes) -> (Snapshot, Vec<String>) {
+        let mut snapshot = Snapshot::default();
+        let mut skipped = Vec::new();
+        for entry in self.database.iter() {
+            let (key, value) = entry.unwrap();
+            let key = String::from_utf8_lossy(&key).into_owned();
+            if key == CURRENT_MODE {
+                snapshot.current_mode = Some(AudioMode::from_bytes(&value));
+            } else if key == SCHEDULE {
+                match serde_json::from_slice(&value) {
+                    Ok(schedule) => snapshot.schedule = schedule,
+                    Err(_) => skipped.push(key),
+                }
+            } else if let Some(mode_key) = key.strip_suffix(PLAYLIST_SUFFIX) {
+                let mode =
+                    modes.iter().find(|mode| mode.name.key() == mode_key);
+                match (mode, std::str::from_utf8(&value)) {
+                    (Some(mode), Ok(playlist)) => {
+                        let mode = mode.name.clone();
+                        snapshot.playlists.insert(mode, playlist.to_owned());
+                    }
+                    _ => skipped.push(key),
+                }
+            } else if let (Some(playlist), 8) =
+                (key.strip_suffix(POSITION_SUFFIX), value.len())
+            {
+                let position = position_from_bytes(&value);
+                snapshot.positions.insert(playlist.to_owned(), position);
+            } else if let (Some(playlist), 8) =
+                (key.strip_suffix(LAST_PLAYED_SUFFIX), value.len())
+            {
+                let bytes = value[..].try_into().unwrap();
+                let last_played = u64::from_ne_bytes(bytes);
+                snapshot.last_played.insert(playlist.to_owned(), last_played);
+            } else {
+                skipped.push(key);
+            }
         }
+        (snapshot, skipped)
     }
+}
 
-    pub(crate) fn fetch_playlist_name(
-        &self,
-        mode: &AudioMode,
-    ) -> Option<String> {
-        let key = mode.to_prefix().to_owned() + "cur_playlist";
+impl Store for Db {
+    fn fetch_playlist_name(&self, mode: &AudioMode) -> Option<String> {
+        let key = mode.key() + PLAYLIST_SUFFIX;
         self.database
             .get(key.as_bytes())
             .unwrap()
             .map(|data| String::from_utf8(data.to_vec()).unwrap())
     }
 
-    pub(crate) fn store_playlist_name(
-        &self,
-        mode: &AudioMode,
-        playlist_name: &str,
-    ) {
-        let key = mode.to_prefix().to_owned() + "cur_playlist";
+    fn store_playlist_name(&self, mode: &AudioMode, playlist_name: &str) {
+        let key = mode.key() + PLAYLIST_SUFFIX;
         self.database
             .insert(key.as_bytes(), playlist_name.as_bytes())
             .unwrap();
     }
 
-    pub(crate) fn fetch_position(
-        &self,
-        playlist_name: &str,
-    ) -> Option<Position> {
-        let key = playlist_name.to_owned() + "_position";
+    fn fetch_position(&self, playlist_name: &str) -> Option<Position> {
+        let key = playlist_name.to_owned() + POSITION_SUFFIX;
         self.database
             .get(key.as_bytes())
             .unwrap()
-            .map(|buffer| Position::from_bytes(buffer.as_ref()))
+            .map(|buffer| position_from_bytes(buffer.as_ref()))
     }
 
-    pub(crate) fn store_position(
-        &mut self,
-        playlist_name: &str,
-        position: &Position,
-    ) {
-        let key = playlist_name.to_owned() + "_position";
+    fn store_position(&self, playlist_name: &str, position: &Position) {
+        let key = playlist_name.to_owned() + POSITION_SUFFIX;
         self.database
-            .insert(key.as_bytes(), position.to_bytes())
+            .insert(key.as_bytes(), position_to_bytes(position))
             .unwrap();
     }
 
-    pub(crate) fn now_timestamp() -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-    }
-
-    pub(crate) fn fetch_last_played(&self, playlist: &str) -> Option<u64> {
-        let key = playlist.to_owned() + "last_played";
+    fn fetch_last_played(&self, playlist: &str) -> Option<u64> {
+        let key = playlist.to_owned() + LAST_PLAYED_SUFFIX;
         self.database
             .get(key.as_bytes())
             .unwrap()
             .map(|bytes| u64::from_ne_bytes(bytes[..8].try_into().unwrap()))
     }
 
-    pub(crate) fn store_last_played(&self, playlist: &str, last_played: u64) {
-        let key = playlist.to_owned() + "last_played";
+    fn store_last_played(&self, playlist: &str, last_played: u64) {
+        let key = playlist.to_owned() + LAST_PLAYED_SUFFIX;
 
         self.database
             .insert(key.as_bytes(), &last_played.to_ne_bytes())
             .unwrap();
     }
 
-    pub(crate) fn fetch_mode(&self) -> Option<AudioMode> {
-        let key = "current_mode";
+    fn fetch_mode(&self) -> Option<AudioMode> {
         self.database
-            .get(key.as_bytes())
+            .get(CURRENT_MODE.as_bytes())
             .unwrap()
             .map(|buffer| AudioMode::from_bytes(buffer.as_ref()))
     }
 
-    pub(crate) fn store_mode(&self, mode: &AudioMode) {
-        let key = "current_mode";
+    fn store_mode(&self, mode: &AudioMode) {
         self.database
-            .insert(key.as_bytes(), &mode.to_bytes())
+            .insert(CURRENT_MODE.as_bytes(), mode.to_bytes())
             .unwrap();
     }
+
+    fn fetch_schedule(&self) -> Schedule {
+        self.database
+            .get(SCHEDULE.as_bytes())
+            .unwrap()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default()
+    }
+
+    fn store_schedule(&self, schedule: &Schedule) {
+        let json = serde_json::to_vec(schedule).expect("serializable");
+        self.database.insert(SCHEDULE.as_bytes(), json).unwrap();
+    }
+
+    fn snapshot(&self, modes: &Modes) -> Snapshot {
+        self.read_all(modes).0
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::audiocontrol::store::now_timestamp;
 
     #[test]
     fn fetch_and_store_last_played() {
-        let db = Db::open("test_db");
+        let db = Db::open_unmigrated(Path::new("test_db")).unwrap();
 
         let playlist = "test_playlist_name";
-        let last_played = Db::now_timestamp();
+        let last_played = now_timestamp();
 
         db.store_last_played(&playlist, last_played);
         let fetched = db.fetch_last_played(&playlist).unwrap();
         assert_eq!(fetched, last_played);
     }
+
+    #[test]
+    fn legacy_mode_is_read() {
+        let path = Path::new("test_db_migrate");
+        let db = Db::open_unmigrated(path).unwrap();
+        db.database.insert(CURRENT_MODE.as_bytes(), &[3]).unwrap();
+        drop(db);
+
+        let db = Db::open_unmigrated(path).unwrap();
+        assert_eq!(db.fetch_mode(), Some(AudioMode::from("Podcast")));
+        let stored = db.database.get(CURRENT_MODE.as_bytes()).unwrap();
+        assert_eq!(stored.as_deref(), Some(&[3][..]));
+    }
 }
@@ -1,12 +1,25 @@
-use super::{db::Position, AudioMode};
-use std::time::{SystemTime, UNIX_EPOCH};
+//! The dbstruct layout, which knows the types of what it stores.
+
+use super::store::{Position, Snapshot, Store};
+use super::{AudioMode, Modes};
+use crate::alarm::Schedule;
+use std::collections::BTreeSet;
+use std::path::Path;
 
 #[dbstruct::dbstruct(db=sled)]
 struct PersistentData {
+    /// layout version, set once the old layout has been moved over
+    version: Option<u8>,
     mode_cur_playlist: HashMap<AudioMode, String>,
     playlist_positions: HashMap<String, Position>,
     playlist_last_played: HashMap<String, u64>,
     current_mode: Option<AudioMode>,
+    /// every playlist with a position or last played time, the maps can
+    /// not be listed
+    #[dbstruct(Default)]
+    playlists: BTreeSet<String>,
+    #[dbstruct(Default)]
+    schedule: Schedule,
 }
 
 pub(crate) struct Db {
@@ -14,97 +27,124 @@ pub(crate) struct Db {
 }
 
 impl Db {
-    pub(crate) fn open(path: &str) -> Self {
-        Db {
-            database: PersistentData::new(path.to_owned()+"_test").unwrap(),
+    pub(crate) fn open(path: &Path) -> Result<Self, String> {
+        let database = PersistentData::new(path).map_err(|err| {
+            format!("Could not open database {}: {err}", path.display())
+        })?;
+        Ok(Db { database })
+    }
+
+    pub(crate) fn version(&self) -> Option<u8> {
+        self.database.version().get().unwrap()
+    }
+
+    pub(crate) fn set_version(&self, version: u8) {
+        self.database.version().set(&version).unwrap();
+    }
+
+    fn remember_playlist(&self, playlist: &str) {
+        let mut playlists = self.database.playlists().get().unwrap();
+        if playlists.insert(playlist.to_owned()) {
+            self.database.playlists().set(&playlists).unwrap();
         }
     }
+}
 
-    pub(crate) fn fetch_playlist_name(
-        &self,
-        mode: &AudioMode,
-    ) -> Option<String> {
+impl Store for Db {
+    fn fetch_playlist_name(&self, mode: &AudioMode) -> Option<String> {
         self.database.mode_cur_playlist().get(mode).unwrap()
     }
 
-    pub(crate) fn store_playlist_name(
-        &self,
-        mode: &AudioMode,
-        playlist_name: &str,
-    ) {
+    fn store_playlist_name(&self, mode: &AudioMode, playlist_name: &str) {
         self.database
             .mode_cur_playlist()
             .insert(mode, &playlist_name.to_owned())
             .unwrap();
     }
 
-    pub(crate) fn fetch_position(
-        &self,
-        playlist_name: &str,
-    ) -> Option<Position> {
+    fn fetch_position(&self, playlist_name: &str) -> Option<Position> {
         self.database
             .playlist_positions()
             .get(&playlist_name.to_owned())
             .unwrap()
     }
 
-    pub(crate) fn store_position(
-        &mut self,
-        playlist_name: &str,
-        position: &Position,
-    ) {
+    fn store_position(&self, playlist_name: &str, position: &Position) {
         self.database
             .playlist_positions()
             .insert(&playlist_name.to_owned(), position)
             .unwrap();
+        self.remember_playlist(playlist_name);
     }
 
-    pub(crate) fn now_timestamp() -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-    }
-
-    pub(crate) fn fetch_last_played(&self, playlist: &str) -> Option<u64> {
+    fn fetch_last_played(&self, playlist: &str) -> Option<u64> {
         self.database
             .playlist_last_played()
             .get(&playlist.to_owned())
             .unwrap()
     }
 
-    pub(crate) fn store_last_played(&self, playlist: &str, last_played: u64) {
+    fn store_last_played(&self, playlist: &str, last_played: u64) {
         self.database
             .playlist_last_played()
             .insert(&playlist.to_owned(), &last_played)
             .unwrap();
+        self.remember_playlist(playlist);
     }
 
-    pub(crate) fn fetch_mode(&self) -> Option<AudioMode> {
+    fn fetch_mode(&self) -> Option<AudioMode> {
         self.database.current_mode().get().unwrap()
     }
 
-    pub(crate) fn store_mode(&self, mode: &AudioMode) {
-        self.database
-            .current_mode()
-            .set(mode)
-            .unwrap();
+    fn store_mode(&self, mode: &AudioMode) {
+        self.database.current_mode().set(mode).unwrap();
+    }
+
+    fn fetch_schedule(&self) -> Schedule {
+        self.database.schedule().get().unwrap()
+    }
+
+    fn store_schedule(&self, schedule: &Schedule) {
+        self.database.schedule().set(schedule).unwrap();
+    }
+
+    fn snapshot(&self, modes: &Modes) -> Snapshot {
+        let mut snapshot = Snapshot {
+            current_mode: self.fetch_mode(),
+            schedule: self.fetch_schedule(),
+            ..Snapshot::default()
+        };
+        for mode in modes.iter() {
+            if let Some(playlist) = self.fetch_playlist_name(&mode.name) {
+                snapshot.playlists.insert(mode.name.clone(), playlist);
+            }
+        }
+        for playlist in self.database.playlists().get().unwrap() {
+            if let Some(position) = self.fetch_position(&playlist) {
+                snapshot.positions.insert(playlist.clone(), position);
+            }
+            if let Some(last_played) = self.fetch_last_played(&playlist) {
+                snapshot.last_played.insert(playlist, last_played);
+            }
+        }
+        snapshot
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::audiocontrol::store::now_timestamp;
 
     #[test]
     fn fetch_and_store_last_played() {
-        let db = Db::open("test_db2");
+        let db = Db::open(Path::new("test_db2")).unwrap();
 
         let playlist = "test_playlist_name";
-        let last_played = Db::now_timestamp();
+        let last_played = now_timestamp();
 
-        db.store_last_played(&playlist, last_played);
-        let fetched = db.fetch_last_played(&playlist).unwrap();
+        db.store_last_played(playlist, last_played);
+        let fetched = db.fetch_last_played(playlist).unwrap();
         assert_eq!(fetched, last_played);
     }
 }
@@ -0,0 +1,65 @@
+use std::fmt;
+
+use super::backend;
+use super::AudioMode;
+
+/// Why the controller could not do what was asked. None of these leave it
+/// in a state it can not recover from, the next press may well work.
+#[derive(Debug)]
+pub enum AudioError {
+    /// the player refused or could not be reached
+    Player(backend::Error),
+    /// the database could not be opened
+    Database(String),
+    UnknownMode(AudioMode),
+    NoPlaylists(AudioMode),
+    NoPlaylistsAtAll,
+    PlaylistNotFound(String),
+    /// the song could not be added to the queue or moved up in it
+    NotQueued {
+        song: String,
+        err: backend::Error,
+    },
+}
+
+impl AudioError {
+    /// Could not talk to the player at all, as opposed to a request that
+    /// does not make sense right now
+    pub fn is_unavailable(&self) -> bool {
+        matches!(self, Self::Player(backend::Error::Connection(_)))
+            || matches!(self, Self::Database(_))
+    }
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Player(err) => write!(f, "Player: {err}"),
+            Self::Database(err) => f.write_str(err),
+            Self::UnknownMode(mode) => {
+                write!(f, "Mode {mode} is not configured")
+            }
+            Self::NoPlaylists(mode) => {
+                write!(f, "No playlists for mode {mode}")
+            }
+            Self::NoPlaylistsAtAll => f.write_str("No playlists for any mode"),
+            Self::PlaylistNotFound(playlist) => {
+                write!(
+                    f,
+                    "Could not find target playlist {playlist:?} to go to"
+                )
+            }
+            Self::NotQueued { song, err } => {
+                write!(f, "Could not play {song:?} next: {err}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for AudioError {}
+
+impl From<backend::Error> for AudioError {
+    fn from(err: backend::Error) -> Self {
+        Self::Player(err)
+    }
+}
@@ -0,0 +1,497 @@
+//! An in-process stand-in for mpd, speaking just enough of its protocol for
+//! everything [`super::mpdinterface`] uses, in front of a [`Player`]. Time
+//! does not pass on its own, tests move playback along through
+//! [`FakeMpd::player`].
+//!
+//! Every connection gets a thread. Like mpd, changes that happen while a
+//! client is not idling are remembered, its next `idle` returns right away.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use super::backend::{Error, PlayState};
+use super::memory::{Memory, Player, Queued, SONG_LENGTH};
+
+const ACK_ERROR_ARG: u8 = 2;
+const ACK_ERROR_UNKNOWN: u8 = 5;
+const ACK_ERROR_NO_EXIST: u8 = 50;
+const ACK_ERROR_PLAYER_SYNC: u8 = 55;
+const ACK_ERROR_EXIST: u8 = 56;
+
+const MODIFIED: &str = "2024-01-01T00:00:00Z";
+
+struct Shared {
+    memory: Memory,
+    /// subsystems that changed, numbered so clients can tell what is new
+    changes: Mutex<Vec<&'static str>>,
+    changed: Condvar,
+}
+
+pub(super) struct FakeMpd {
+    addr: SocketAddr,
+    shared: Arc<Shared>,
+}
+
+impl FakeMpd {
+    /// Listens on a free port on localhost until the test ends
+    pub(super) fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("free port");
+        let addr = listener.local_addr().expect("bound");
+        let shared = Arc::new(Shared {
+            memory: Memory::new(),
+            changes: Mutex::new(Vec::new()),
+            changed: Condvar::new(),
+        });
+
+        let accepting = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let shared = accepting.clone();
+                thread::spawn(move || serve(stream, &shared));
+            }
+        });
+        Self { addr, shared }
+    }
+
+    pub(super) fn port(&self) -> u16 {
+        self.addr.port()
+    }
+
+    pub(super) fn player(&self) -> MutexGuard<'_, Player> {
+        self.shared.memory.player()
+    }
+}
+
+struct Ack {
+    code: u8,
+    message: String,
+}
+
+fn ack(code: u8, message: impl Into<String>) -> Ack {
+    Ack {
+        code,
+        message: message.into(),
+    }
+}
+
+type Reply = Result<String, Ack>;
+
+/// Splits a command line into words, unquoting `"quoted words"`
+fn split(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c == ' ' {
+            chars.next();
+            continue;
+        }
+        let mut word = String::new();
+        if c == '"' {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => word.extend(chars.next()),
+                    c => word.push(c),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ' ' {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+        }
+        words.push(word);
+    }
+    words
+}
+
+fn arg(args: &[String], i: usize) -> Result<&str, Ack> {
+    args.get(i)
+        .map(String::as_str)
+        .ok_or_else(|| ack(ACK_ERROR_ARG, "too few arguments"))
+}
+
+fn number<T: std::str::FromStr>(args: &[String], i: usize) -> Result<T, Ack> {
+    let word = arg(args, i)?;
+    word.parse()
+        .map_err(|_| ack(ACK_ERROR_ARG, format!("Integer expected: {word}")))
+}
+
+fn flag(args: &[String]) -> Result<bool, Ack> {
+    match arg(args, 0)? {
+        "0" => Ok(false),
+        "1" | "oneshot" => Ok(true),
+        word => Err(ack(ACK_ERROR_ARG, format!("Boolean expected: {word}"))),
+    }
+}
+
+fn seconds(word: &str) -> Result<Duration, Ack> {
+    word.parse::<f64>()
+        .ok()
+        .filter(|secs| *secs >= 0.0)
+        .map(Duration::from_secs_f64)
+        .ok_or_else(|| ack(ACK_ERROR_ARG, format!("Number expected: {word}")))
+}
+
+fn song_info(file: &str) -> String {
+    let secs = SONG_LENGTH.as_secs();
+    format!("file: {file}\nTime: {secs}\nduration: {secs}.000\n")
+}
+
+fn queued_info(song: &Queued, pos: usize) -> String {
+    let mut info = song_info(&song.file);
+    info += &format!("Pos: {pos}\nId: {}\n", song.id);
+    if song.prio > 0 {
+        info += &format!("Prio: {}\n", song.prio);
+    }
+    info
+}
+
+fn status(player: &Player, version: usize) -> String {
+    let mut status = format!(
+        "volume: {}\nrepeat: {}\nrandom: {}\nsingle: {}\nconsume: {}\n\
+         playlist: {}\nplaylistlength: {}\nmixrampdb: 0.000000\nstate: {}\n",
+        player.volume,
+        u8::from(player.repeat),
+        u8::from(player.random),
+        u8::from(player.single),
+        u8::from(player.consume),
+        version,
+        player.queue.len(),
+        match player.state {
+            PlayState::Stop => "stop",
+            PlayState::Play => "play",
+            PlayState::Pause => "pause",
+        },
+    );
+    let Some(pos) = player.current else {
+        return status;
+    };
+    status += &format!("song: {pos}\nsongid: {}\n", player.queue[pos].id);
+    if player.state != PlayState::Stop {
+        let elapsed = player.elapsed.as_secs_f64();
+        let length = SONG_LENGTH.as_secs();
+        status += &format!(
+            "time: {}:{length}\nelapsed: {elapsed:.3}\n\
+             duration: {length}.000\n",
+            player.elapsed.as_secs(),
+        );
+    }
+    status
+}
+
+impl From<Error> for Ack {
+    fn from(err: Error) -> Self {
+        let code = match err {
+            Error::NotPlaying => ACK_ERROR_PLAYER_SYNC,
+            Error::BadSongIndex | Error::Refused(_) => ACK_ERROR_ARG,
+            Error::NoSuchPlaylist => ACK_ERROR_NO_EXIST,
+            Error::PlaylistExists => ACK_ERROR_EXIST,
+            Error::Connection(_) => ACK_ERROR_UNKNOWN,
+        };
+        ack(code, err.to_string())
+    }
+}
+
+/// The subsystems `command` changes when it succeeds
+fn changed_by(command: &str) -> &'static [&'static str] {
+    match command {
+        "clear" | "load" | "addid" | "prioid" => &["playlist"],
+        "play" | "pause" | "stop" | "next" | "previous" | "seek"
+        | "seekcur" => &["player"],
+        "repeat" | "random" | "single" | "consume" => &["options"],
+        "setvol" => &["mixer"],
+        "save" | "rm" | "playlistclear" | "playlistadd" => &["stored_playlist"],
+        "update" | "rescan" => &["update", "database"],
+        _ => &[],
+    }
+}
+
+/// Handles everything but `idle`, which needs to wait
+fn perform(
+    player: &mut Player,
+    version: usize,
+    command: &str,
+    args: &[String],
+) -> Reply {
+    let ok = || Ok(String::new());
+    match command {
+        "ping" => ok(),
+        "status" => Ok(status(player, version)),
+        "currentsong" => Ok(player
+            .current
+            .map(|pos| queued_info(&player.queue[pos], pos))
+            .unwrap_or_default()),
+        "playlistinfo" => Ok(player
+            .queue
+            .iter()
+            .enumerate()
+            .map(|(pos, song)| queued_info(song, pos))
+            .collect()),
+        "listplaylists" => Ok(player
+            .playlists
+            .keys()
+            .map(|name| {
+                format!("playlist: {name}\nLast-Modified: {MODIFIED}\n")
+            })
+            .collect()),
+        "listplaylist" => Ok(player
+            .stored(arg(args, 0)?)?
+            .iter()
+            .map(|file| format!("file: {file}\n"))
+            .collect()),
+        "listplaylistinfo" => Ok(player
+            .stored(arg(args, 0)?)?
+            .iter()
+            .map(|file| song_info(file))
+            .collect()),
+        "clear" => {
+            player.clear();
+            ok()
+        }
+        "load" => {
+            let (start, end) = match args.get(1) {
+                None => (0, usize::MAX),
+                Some(range) => {
+                    let (start, end) =
+                        range.split_once(':').unwrap_or((range, ""));
+                    let start = start.parse().unwrap_or(0);
+                    (start, end.parse().unwrap_or(usize::MAX))
+                }
+            };
+            player.load(arg(args, 0)?, start, end)?;
+            ok()
+        }
+        "addid" => {
+            let id = player.push(arg(args, 0)?.to_owned());
+            Ok(format!("Id: {id}\n"))
+        }
+        "prioid" => {
+            let prio: u8 = number(args, 0)?;
+            for i in 1..args.len() {
+                player.prioid(number(args, i)?, prio)?;
+            }
+            ok()
+        }
+        "play" => {
+            let pos = match args.first() {
+                Some(_) => Some(number(args, 0)?),
+                None => None,
+            };
+            Ok(player.play(pos).map(|()| String::new())?)
+        }
+        "pause" => {
+            let pause = match args.first() {
+                Some(_) => flag(args)?,
+                None => player.state == PlayState::Play,
+            };
+            player.set_pause(pause);
+            ok()
+        }
+        "stop" => {
+            player.stop();
+            ok()
+        }
+        "next" | "previous" => {
+            player.skip_song(command == "next")?;
+            ok()
+        }
+        "seek" => {
+            let pos: usize = number(args, 0)?;
+            player.seek(pos, seconds(arg(args, 1)?)?)?;
+            ok()
+        }
+        "seekcur" => {
+            let word = arg(args, 0)?;
+            let elapsed = match word.as_bytes()[0] {
+                b'+' => player.elapsed + seconds(&word[1..])?,
+                b'-' => player.elapsed.saturating_sub(seconds(&word[1..])?),
+                _ => seconds(word)?,
+            };
+            player.seek_current(elapsed)?;
+            ok()
+        }
+        "repeat" | "random" | "single" | "consume" => {
+            let value = flag(args)?;
+            match command {
+                "repeat" => player.repeat = value,
+                "random" => player.random = value,
+                "single" => player.single = value,
+                _ => player.consume = value,
+            }
+            ok()
+        }
+        "setvol" => {
+            player.set_volume(number(args, 0)?)?;
+            ok()
+        }
+        "save" => {
+            player.save(arg(args, 0)?)?;
+            ok()
+        }
+        "rm" => {
+            player.remove_playlist(arg(args, 0)?)?;
+            ok()
+        }
+        "playlistclear" => {
+            player.clear_playlist(arg(args, 0)?);
+            ok()
+        }
+        "playlistadd" => {
+            player.add_to_playlist(arg(args, 0)?, arg(args, 1)?);
+            ok()
+        }
+        // the library is whatever the playlists refer to, so the update
+        // is done right away
+        "update" | "rescan" => Ok("updating_db: 1\n".to_owned()),
+        _ => Err(ack(
+            ACK_ERROR_UNKNOWN,
+            format!("unknown command \"{command}\""),
+        )),
+    }
+}
+
+/// Waits for a change in one of `subsystems` after the `seen` first changes
+fn idle(shared: &Shared, seen: &mut usize, subsystems: &[String]) -> String {
+    let wanted = |change: &&str| {
+        subsystems.is_empty() || subsystems.iter().any(|s| s == change)
+    };
+    let mut changes = shared.changes.lock().expect("not poisoned");
+    loop {
+        let mut changed: Vec<_> =
+            changes[*seen..].iter().copied().filter(wanted).collect();
+        *seen = changes.len();
+        if !changed.is_empty() {
+            changed.dedup();
+            return changed
+                .iter()
+                .map(|change| format!("changed: {change}\n"))
+                .collect();
+        }
+        changes = shared.changed.wait(changes).expect("not poisoned");
+    }
+}
+
+fn serve(stream: TcpStream, shared: &Shared) {
+    let Ok(mut writer) = stream.try_clone() else {
+        return;
+    };
+    let mut seen = shared.changes.lock().expect("not poisoned").len();
+    if writer.write_all(b"OK MPD 0.23.5\n").is_err() {
+        return;
+    }
+
+    let mut list: Option<Vec<String>> = None;
+    for line in BufReader::new(stream).lines() {
+        let Ok(line) = line else {
+            return;
+        };
+        let mut words = split(&line);
+        if words.is_empty() {
+            continue;
+        }
+        let command = words.remove(0);
+
+        let reply = match (command.as_str(), &mut list) {
+            ("command_list_begin" | "command_list_ok_begin", _) => {
+                list = Some(Vec::new());
+                continue;
+            }
+            ("command_list_end", Some(_)) => {
+                let lines = list.take().unwrap_or_default();
+                run_list(shared, &lines)
+            }
+            (_, Some(lines)) => {
+                lines.push(line);
+                continue;
+            }
+            ("idle", None) => Ok(idle(shared, &mut seen, &words)),
+            ("close", None) => return,
+            (_, None) => run(shared, &command, &words),
+        };
+
+        let response = match reply {
+            Ok(body) => format!("{body}OK\n"),
+            Err(Ack { code, message }) => {
+                format!("ACK [{code}@0] {{{command}}} {message}\n")
+            }
+        };
+        if writer.write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+fn run(shared: &Shared, command: &str, args: &[String]) -> Reply {
+    let mut changes = shared.changes.lock().expect("not poisoned");
+    let version = changes.len() + 1;
+    let reply = perform(&mut shared.memory.player(), version, command, args);
+    if reply.is_ok() && !changed_by(command).is_empty() {
+        changes.extend(changed_by(command));
+        shared.changed.notify_all();
+    }
+    reply
+}
+
+fn run_list(shared: &Shared, lines: &[String]) -> Reply {
+    let mut body = String::new();
+    for line in lines {
+        let mut words = split(line);
+        let command = words.remove(0);
+        body += &run(shared, &command, &words)?;
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_arguments() {
+        let words = split(r#"load "music_my \"best\" songs" 0:"#);
+        assert_eq!(words, ["load", r#"music_my "best" songs"#, "0:"]);
+    }
+
+    #[test]
+    fn speaks_the_protocol() {
+        let mpd = FakeMpd::start();
+        mpd.player().add_playlist("music_a", &["a.ogg", "b.ogg"]);
+
+        let stream = TcpStream::connect(("127.0.0.1", mpd.port())).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+        assert_eq!(greeting, "OK MPD 0.23.5\n");
+        let mut response = |command: &str| {
+            writer.write_all(format!("{command}\n").as_bytes()).unwrap();
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let done = line == "OK\n" || line.starts_with("ACK");
+                lines.push(line);
+                if done {
+                    return lines.concat();
+                }
+            }
+        };
+
+        assert_eq!(response("ping"), "OK\n");
+        assert_eq!(response("load music_a"), "OK\n");
+        assert_eq!(response("seek 1 30"), "OK\n");
+        assert!(response("status").contains("song: 1\n"));
+        assert_eq!(response("seek 5 0"), "ACK [2@0] {seek} Bad song index\n");
+        assert!(response("stop").ends_with("OK\n"));
+        assert_eq!(response("next"), "ACK [55@0] {next} Not playing\n");
+        assert_eq!(mpd.player().queue.len(), 2);
+    }
+}
@@ -0,0 +1,87 @@
+//! The [`AudioController`] on a thread of its own. Talking to the player
+//! blocks, on that thread it can not hold up the panels or the api. They
+//! send it jobs and wait for the answer without blocking, giving up after a
+//! timeout. Jobs run one at a time in the order they were sent.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+use tracing::error;
+
+use super::{AudioController, AudioError};
+
+type Job = Box<dyn FnOnce(&mut AudioController) + Send>;
+
+/// Jobs waiting for the controller, more means the player is stuck
+const QUEUE: usize = 32;
+
+#[derive(Debug, Clone)]
+pub struct AudioHandle {
+    jobs: mpsc::Sender<Job>,
+    timeout: Duration,
+}
+
+impl AudioHandle {
+    /// Moves `audio` to a new thread, waiting at most `timeout` for it in
+    /// [`call`](Self::call)
+    pub fn spawn(mut audio: AudioController, timeout: Duration) -> Self {
+        let (jobs, mut queue) = mpsc::channel::<Job>(QUEUE);
+        thread::Builder::new()
+            .name("audio".to_owned())
+            .spawn(move || {
+                while let Some(job) = queue.blocking_recv() {
+                    // a job that panics should not take the others with it
+                    let job = AssertUnwindSafe(|| job(&mut audio));
+                    if panic::catch_unwind(job).is_err() {
+                        error!("Audio job panicked, continuing with the next");
+                    }
+                }
+            })
+            .expect("can spawn threads");
+        Self { jobs, timeout }
+    }
+
+    /// Run `job` on the controller and return what it returns
+    pub async fn call<R: Send + 'static>(
+        &self,
+        job: impl FnOnce(&mut AudioController) -> R + Send + 'static,
+    ) -> Result<R, String> {
+        self.call_within(self.timeout, job).await
+    }
+
+    /// Like [`call`](Self::call) for jobs that can fail, with the failure
+    /// as error
+    pub async fn try_call<R: Send + 'static>(
+        &self,
+        job: impl FnOnce(&mut AudioController) -> Result<R, AudioError>
+            + Send
+            + 'static,
+    ) -> Result<R, String> {
+        self.call(job).await?.map_err(|err| err.to_string())
+    }
+
+    /// Like [`call`](Self::call) for jobs that take longer than usual. On
+    /// a timeout the job still runs, the answer is dropped.
+    pub async fn call_within<R: Send + 'static>(
+        &self,
+        timeout: Duration,
+        job: impl FnOnce(&mut AudioController) -> R + Send + 'static,
+    ) -> Result<R, String> {
+        let (reply, answer) = oneshot::channel();
+        let job: Job = Box::new(move |audio| {
+            let _ = reply.send(job(audio));
+        });
+        let answered = tokio::time::timeout(timeout, async {
+            let stopped = |_| "Audio controller stopped".to_owned();
+            self.jobs.send(job).await.map_err(stopped)?;
+            answer.await.map_err(|_| "Audio job panicked".to_owned())
+        });
+        answered.await.unwrap_or_else(|_| {
+            Err(format!(
+                "Audio controller did not answer within {timeout:?}"
+            ))
+        })
+    }
+}
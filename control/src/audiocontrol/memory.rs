@@ -0,0 +1,404 @@
+//! A [`MusicBackend`] that only keeps the state of a player, without playing
+//! anything. Time does not pass on its own, move playback along through
+//! [`Memory::player`]. It behaves like mpd where the controller cares, down
+//! to the errors.
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use super::backend::{Error, MusicBackend, PlayState, Result, Song, Status};
+
+/// How long every song is
+pub const SONG_LENGTH: Duration = Duration::from_secs(180);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Queued {
+    pub file: String,
+    pub id: u32,
+    pub prio: u8,
+}
+
+#[derive(Debug)]
+pub struct Player {
+    /// stored playlists by name
+    pub playlists: BTreeMap<String, Vec<String>>,
+    pub queue: Vec<Queued>,
+    /// position in the queue of the current song
+    pub current: Option<usize>,
+    pub elapsed: Duration,
+    pub state: PlayState,
+    pub repeat: bool,
+    pub random: bool,
+    pub single: bool,
+    pub consume: bool,
+    pub volume: i8,
+    next_id: u32,
+}
+
+impl Default for Player {
+    fn default() -> Self {
+        Self {
+            playlists: BTreeMap::new(),
+            queue: Vec::new(),
+            current: None,
+            elapsed: Duration::ZERO,
+            state: PlayState::Stop,
+            repeat: false,
+            random: false,
+            single: false,
+            consume: false,
+            volume: 50,
+            next_id: 1,
+        }
+    }
+}
+
+impl Player {
+    /// Replace the stored playlist `name`
+    pub fn add_playlist(&mut self, name: &str, songs: &[&str]) {
+        let songs = songs.iter().map(|&song| song.to_owned()).collect();
+        self.playlists.insert(name.to_owned(), songs);
+    }
+
+    pub fn current_file(&self) -> Option<&str> {
+        self.current.map(|pos| self.queue[pos].file.as_str())
+    }
+
+    pub(super) fn status(&self) -> Status {
+        let playing = self.state != PlayState::Stop;
+        Status {
+            state: self.state,
+            volume: self.volume,
+            song: self.current.map(|pos| pos as u32),
+            elapsed: playing.then_some(self.elapsed),
+            duration: playing.then_some(SONG_LENGTH),
+        }
+    }
+
+    fn queued_song(&self, pos: usize) -> Song {
+        Song {
+            file: self.queue[pos].file.clone(),
+            title: None,
+            pos: Some(pos as u32),
+        }
+    }
+
+    pub(super) fn current_song(&self) -> Option<Song> {
+        self.current.map(|pos| self.queued_song(pos))
+    }
+
+    pub(super) fn queued_songs(&self) -> Vec<Song> {
+        (0..self.queue.len())
+            .map(|pos| self.queued_song(pos))
+            .collect()
+    }
+
+    pub(super) fn stored(&self, name: &str) -> Result<&Vec<String>> {
+        self.playlists.get(name).ok_or(Error::NoSuchPlaylist)
+    }
+
+    fn playing(&self) -> Result<usize> {
+        match self.current {
+            Some(pos) if self.state != PlayState::Stop => Ok(pos),
+            _ => Err(Error::NotPlaying),
+        }
+    }
+
+    fn go_to(&mut self, pos: usize, elapsed: Duration) {
+        self.current = Some(pos);
+        self.elapsed = elapsed;
+        if self.state == PlayState::Stop {
+            self.state = PlayState::Play;
+        }
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.queue.clear();
+        self.current = None;
+        self.state = PlayState::Stop;
+    }
+
+    /// Queue the songs `start..end` of the stored playlist `name`, the end
+    /// may be past the last song
+    pub(super) fn load(
+        &mut self,
+        name: &str,
+        start: usize,
+        end: usize,
+    ) -> Result<()> {
+        let songs = self.stored(name)?.clone();
+        let end = end.min(songs.len());
+        for file in songs.get(start..end).unwrap_or_default() {
+            self.push(file.clone());
+        }
+        Ok(())
+    }
+
+    pub(super) fn push(&mut self, file: String) -> u32 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queue.push(Queued { file, id, prio: 0 });
+        id
+    }
+
+    pub(super) fn prioid(&mut self, id: u32, prio: u8) -> Result<()> {
+        let song = self
+            .queue
+            .iter_mut()
+            .find(|song| song.id == id)
+            .ok_or_else(|| Error::Refused("No such song".to_owned()))?;
+        song.prio = prio;
+        Ok(())
+    }
+
+    /// Play the song at `pos`, or the current one where it was
+    pub(super) fn play(&mut self, pos: Option<usize>) -> Result<()> {
+        if self.queue.is_empty() {
+            return Ok(());
+        }
+        let pos = pos.unwrap_or(self.current.unwrap_or(0));
+        if pos >= self.queue.len() {
+            return Err(Error::BadSongIndex);
+        }
+        let elapsed = if self.current == Some(pos) {
+            self.elapsed
+        } else {
+            Duration::ZERO
+        };
+        self.go_to(pos, elapsed);
+        self.state = PlayState::Play;
+        Ok(())
+    }
+
+    /// Pause or resume, does nothing when stopped
+    pub(super) fn set_pause(&mut self, pause: bool) {
+        self.state = match (self.state, pause) {
+            (PlayState::Play, true) => PlayState::Pause,
+            (PlayState::Pause, false) => PlayState::Play,
+            (unchanged, _) => unchanged,
+        };
+    }
+
+    pub(super) fn stop(&mut self) {
+        self.state = PlayState::Stop;
+        self.elapsed = Duration::ZERO;
+    }
+
+    /// Go `forward` to the next song or back to the previous one, stopping
+    /// after the last
+    pub(super) fn skip_song(&mut self, forward: bool) -> Result<()> {
+        let pos = self.playing()?;
+        let pos = if forward {
+            pos + 1
+        } else {
+            pos.saturating_sub(1)
+        };
+        if pos < self.queue.len() {
+            self.go_to(pos, Duration::ZERO);
+        } else {
+            self.state = PlayState::Stop;
+            self.current = None;
+        }
+        Ok(())
+    }
+
+    pub(super) fn seek(&mut self, pos: usize, elapsed: Duration) -> Result<()> {
+        if pos >= self.queue.len() {
+            return Err(Error::BadSongIndex);
+        }
+        self.go_to(pos, elapsed);
+        Ok(())
+    }
+
+    pub(super) fn seek_current(&mut self, elapsed: Duration) -> Result<()> {
+        let pos = self.playing()?;
+        self.go_to(pos, elapsed);
+        Ok(())
+    }
+
+    pub(super) fn set_volume(&mut self, volume: i8) -> Result<()> {
+        if !(0..=100).contains(&volume) {
+            return Err(Error::Refused("Invalid volume value".to_owned()));
+        }
+        self.volume = volume;
+        Ok(())
+    }
+
+    pub(super) fn save(&mut self, name: &str) -> Result<()> {
+        if self.playlists.contains_key(name) {
+            return Err(Error::PlaylistExists);
+        }
+        let files = self.queue.iter().map(|song| song.file.clone());
+        self.playlists.insert(name.to_owned(), files.collect());
+        Ok(())
+    }
+
+    pub(super) fn remove_playlist(&mut self, name: &str) -> Result<()> {
+        self.playlists
+            .remove(name)
+            .map(drop)
+            .ok_or(Error::NoSuchPlaylist)
+    }
+
+    pub(super) fn clear_playlist(&mut self, name: &str) {
+        self.playlists.insert(name.to_owned(), Vec::new());
+    }
+
+    pub(super) fn add_to_playlist(&mut self, name: &str, file: &str) {
+        let playlist = self.playlists.entry(name.to_owned()).or_default();
+        playlist.push(file.to_owned());
+    }
+}
+
+/// A handle to a [`Player`], clones share it
+#[derive(Debug, Clone, Default)]
+pub struct Memory(Arc<Mutex<Player>>);
+
+impl Memory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn player(&self) -> MutexGuard<'_, Player> {
+        self.0.lock().expect("not poisoned")
+    }
+}
+
+fn secs(secs: u32) -> Duration {
+    Duration::from_secs(secs.into())
+}
+
+impl MusicBackend for Memory {
+    fn reconnect(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// The library is whatever the playlists refer to, nothing to scan
+    fn rescan(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn status(&mut self) -> Result<Status> {
+        Ok(self.player().status())
+    }
+
+    fn current_song(&mut self) -> Result<Option<Song>> {
+        Ok(self.player().current_song())
+    }
+
+    fn queue(&mut self) -> Result<Vec<Song>> {
+        Ok(self.player().queued_songs())
+    }
+
+    fn playlists(&mut self) -> Result<Vec<String>> {
+        Ok(self.player().playlists.keys().cloned().collect())
+    }
+
+    fn playlist(&mut self, name: &str) -> Result<Vec<Song>> {
+        let player = self.player();
+        let files = player.stored(name)?.iter();
+        Ok(files
+            .map(|file| Song {
+                file: file.clone(),
+                ..Song::default()
+            })
+            .collect())
+    }
+
+    fn play(&mut self) -> Result<()> {
+        self.player().play(None)
+    }
+
+    fn toggle_pause(&mut self) -> Result<()> {
+        let mut player = self.player();
+        let pause = player.state == PlayState::Play;
+        player.set_pause(pause);
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        self.player().set_pause(true);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<()> {
+        self.player().stop();
+        Ok(())
+    }
+
+    fn prev(&mut self) -> Result<()> {
+        self.player().skip_song(false)
+    }
+
+    fn next(&mut self) -> Result<()> {
+        self.player().skip_song(true)
+    }
+
+    fn seek(&mut self, pos: u32, elapsed: u32) -> Result<()> {
+        self.player().seek(pos as usize, secs(elapsed))
+    }
+
+    fn seek_current(&mut self, elapsed: u32) -> Result<()> {
+        self.player().seek_current(secs(elapsed))
+    }
+
+    fn repeat(&mut self, on: bool) -> Result<()> {
+        self.player().repeat = on;
+        Ok(())
+    }
+
+    fn random(&mut self, on: bool) -> Result<()> {
+        self.player().random = on;
+        Ok(())
+    }
+
+    fn single(&mut self, on: bool) -> Result<()> {
+        self.player().single = on;
+        Ok(())
+    }
+
+    fn consume(&mut self, on: bool) -> Result<()> {
+        self.player().consume = on;
+        Ok(())
+    }
+
+    fn volume(&mut self, percent: i8) -> Result<()> {
+        self.player().set_volume(percent)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.player().clear();
+        Ok(())
+    }
+
+    fn load(&mut self, name: &str) -> Result<()> {
+        self.player().load(name, 0, usize::MAX)
+    }
+
+    fn push(&mut self, file: &str) -> Result<u32> {
+        Ok(self.player().push(file.to_owned()))
+    }
+
+    fn prioid(&mut self, id: u32, prio: u8) -> Result<()> {
+        self.player().prioid(id, prio)
+    }
+
+    fn save(&mut self, name: &str) -> Result<()> {
+        self.player().save(name)
+    }
+
+    fn pl_remove(&mut self, name: &str) -> Result<()> {
+        self.player().remove_playlist(name)
+    }
+
+    fn pl_clear(&mut self, name: &str) -> Result<()> {
+        self.player().clear_playlist(name);
+        Ok(())
+    }
+
+    fn pl_push(&mut self, name: &str, file: &str) -> Result<()> {
+        self.player().add_to_playlist(name, file);
+        Ok(())
+    }
+}
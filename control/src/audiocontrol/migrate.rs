@@ -0,0 +1,115 @@
+//! Moves the old database layout over to the dbstruct one, once. The new
+//! layout is stored next to the old database, which is left as it was as a
+//! backup. Once everything is copied and checked the new layout gets its
+//! version, from then on there is nothing to do. An interrupted migration
+//! starts over the next time.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use super::store::{layout_path, Snapshot, Store};
+use super::{db, db2, Modes};
+
+/// Version of the dbstruct layout
+pub(crate) const LAYOUT_VERSION: u8 = 2;
+
+#[derive(Debug)]
+pub struct Report {
+    from: PathBuf,
+    to: PathBuf,
+    dry_run: bool,
+    /// there was no old database to move
+    fresh: bool,
+    /// the new layout was there already, nothing was read
+    pub(crate) up_to_date: bool,
+    found: Snapshot,
+    /// keys in the old database that mean nothing to us
+    skipped: Vec<String>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (from, to) = (self.from.display(), self.to.display());
+        if self.up_to_date {
+            return write!(f, "{to} is at layout version {LAYOUT_VERSION}");
+        }
+        if self.fresh {
+            return write!(f, "No database at {from}, starting {to} empty");
+        }
+        if self.dry_run {
+            writeln!(f, "Would move {from} to {to}, nothing was changed:")?;
+        } else {
+            writeln!(f, "Moved {from} to {to}, {from} is kept as backup:")?;
+        }
+
+        let found = &self.found;
+        match &found.current_mode {
+            Some(mode) => writeln!(f, "  current mode: {mode}")?,
+            None => writeln!(f, "  no current mode")?,
+        }
+        writeln!(f, "  current playlist of {} modes", found.playlists.len())?;
+        for (mode, playlist) in &found.playlists {
+            writeln!(f, "    {mode}: {playlist}")?;
+        }
+        writeln!(f, "  position in {} playlists", found.positions.len())?;
+        let last_played = found.last_played.len();
+        writeln!(f, "  when {last_played} playlists were last played")?;
+        write!(f, "  skipped {} unknown keys", self.skipped.len())?;
+        for key in &self.skipped {
+            write!(f, "\n    {key:?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Moves the database at `path` to the new layout, unless that happened
+/// already. With `dry_run` only reports what would be moved. Playlists of
+/// modes not in `modes` are skipped.
+pub fn migrate(
+    path: &Path,
+    modes: &Modes,
+    dry_run: bool,
+) -> Result<Report, String> {
+    let to = layout_path(path);
+    let mut report = Report {
+        from: path.to_owned(),
+        to: to.clone(),
+        dry_run,
+        fresh: !path.exists(),
+        up_to_date: false,
+        found: Snapshot::default(),
+        skipped: Vec::new(),
+    };
+
+    // opening creates the database, a dry run should not
+    let new = if dry_run && !to.exists() {
+        None
+    } else {
+        Some(db2::Db::open(&to)?)
+    };
+    if new.as_ref().and_then(db2::Db::version) == Some(LAYOUT_VERSION) {
+        report.up_to_date = true;
+        return Ok(report);
+    }
+
+    if !report.fresh {
+        // old values are fixed up while reading, fixing them up in place
+        // would change the backup
+        let old = db::Db::open_unmigrated(path).map_err(|err| {
+            format!("Could not open database {}: {err}", path.display())
+        })?;
+        (report.found, report.skipped) = old.read_all(modes);
+    }
+    if let (Some(new), false) = (new, dry_run) {
+        new.restore(&report.found);
+        if new.snapshot(modes) != report.found {
+            return Err(format!(
+                "Copy in {} differs from {}, not switching over",
+                to.display(),
+                path.display()
+            ));
+        }
+        new.set_version(LAYOUT_VERSION);
+    }
+    Ok(report)
+}
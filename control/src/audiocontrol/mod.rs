@@ -1,103 +1,57 @@
 #![allow(clippy::enum_glob_use)]
 
+use std::collections::BTreeMap;
 use std::fmt;
 use std::time::Duration;
 
-use mpdrs::error::Error;
-use mpdrs::status::State;
-use mpdrs::Playlist;
+use crate::alarm::Schedule;
+use crate::config::{Config, Meditation};
+use crate::events::{Event, Events};
+use crate::status::{self, Link, ModeStatus, Status};
 
+mod backup;
 mod db;
 mod db2;
-use db::Db;
+mod migrate;
+mod store;
+pub use backup::{export, import};
+pub use migrate::{migrate, Report};
+pub(crate) use store::now_timestamp;
+use store::Store;
 
-mod mpdinterface;
-use mpdinterface::MpdInterface;
-use rand::seq::SliceRandom;
-use tracing::{debug, info, instrument};
+mod modes;
+pub use modes::{AudioMode, Mode, Modes, Rewind};
 
-#[derive(Debug)]
-enum Direction {
-    Next,
-    Previous,
-}
+mod backend;
+pub use backend::{MusicBackend, PlayState};
+use backend::Error;
 
-#[derive(Default)]
-#[allow(clippy::struct_excessive_bools)]
-struct Settings {
-    repeat: bool,
-    random: bool,
-    single: bool,
-    consume: bool,
+mod error;
+pub use error::AudioError;
 
-    save_playlist: bool,
-}
+mod handle;
+pub use handle::AudioHandle;
 
-#[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize, Clone)]
-pub enum AudioMode {
-    Music,
-    Singing,
-    Podcast,
-    Meditation,
-}
+mod memory;
+pub use memory::{Memory, Player};
 
-impl AudioMode {
-    fn next(&mut self) {
-        use AudioMode::*;
-        *self = match self {
-            Music => Singing,
-            Singing => Podcast,
-            Podcast => Meditation,
-            Meditation => Music,
-        }
-    }
-
-    fn to_prefix(&self) -> &str {
-        use AudioMode::*;
-        match self {
-            Music => "music_",
-            Singing => "singing_",
-            Podcast => "podcast_",
-            Meditation => "meditation_",
-        }
-    }
+mod mpdinterface;
+use mpdinterface::MpdInterface;
 
-    fn settings(&self) -> Settings {
-        use AudioMode::*;
-        match self {
-            Music | Singing => Settings::default(),
-            Podcast => Settings {
-                consume: true,
-                save_playlist: true,
-                ..Settings::default()
-            },
-            Meditation => Settings {
-                single: true,
-                ..Settings::default()
-            },
-        }
-    }
+#[cfg(test)]
+mod fake_mpd;
+#[cfg(test)]
+mod tests;
+use rand::seq::SliceRandom;
+use tracing::{debug, info, instrument, warn};
 
-    fn to_bytes(&self) -> [u8; 1] {
-        use AudioMode::*;
-        match self {
-            Music => [1],
-            Singing => [2],
-            Podcast => [3],
-            Meditation => [4],
-        }
-    }
+/// Scanning a large library takes a while
+pub const RESCAN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
 
-    fn from_bytes(bytes: &[u8]) -> Self {
-        use AudioMode::*;
-        match bytes {
-            &[1] => Music,
-            &[2] => Singing,
-            &[3] => Podcast,
-            &[4] => Meditation,
-            other => panic!("Unexpected serialized mode: {:?}", other),
-        }
-    }
+#[derive(Debug)]
+enum Direction {
+    Next,
+    Previous,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -107,11 +61,14 @@ pub enum ForceRewind {
 }
 
 pub struct AudioController {
-    ip: String,
-    port: String,
-    client: MpdInterface,
-    db: Db,
+    client: Box<dyn MusicBackend>,
+    db: Box<dyn Store>,
     pub(crate) mode: AudioMode,
+    pub(crate) modes: Modes,
+    /// only used by the (disabled) meditation check in `next_mode`
+    #[allow(dead_code)]
+    meditation: Meditation,
+    events: Events,
 }
 
 impl fmt::Debug for AudioController {
@@ -123,58 +80,137 @@ impl fmt::Debug for AudioController {
 }
 
 impl AudioController {
-    pub fn new(ip: &str, port: &str) -> Self {
-        let address = format!("{}:{}", ip, port);
-        let client = MpdInterface::connect(&address).unwrap();
+    /// Controls the mpd at `ip`, on the port from the config
+    pub fn new(
+        ip: &str,
+        config: &Config,
+        events: Events,
+    ) -> Result<Self, AudioError> {
+        let address = format!("{}:{}", ip, config.mpd.port);
+        let timeout = Duration::from_secs(config.mpd.timeout_secs);
+        let client =
+            MpdInterface::connect(&address, timeout).map_err(Error::from)?;
+        Self::with_backend(client, config, events)
+    }
+
+    pub fn with_backend(
+        client: impl MusicBackend + 'static,
+        config: &Config,
+        events: Events,
+    ) -> Result<Self, AudioError> {
+        let db = store::open(&config.database.path, &config.modes)
+            .map_err(AudioError::Database)?;
         let mut controller = AudioController {
-            ip: ip.to_owned(),
-            port: port.to_owned(),
-            client,
-            db: Db::open("database"),
-            mode: AudioMode::Music,
+            client: Box::new(client),
+            db,
+            mode: config.modes.first().clone(),
+            modes: config.modes.clone(),
+            meditation: config.meditation.clone(),
+            events,
         };
 
-        if let Some(mode) = controller.fetch_current_mode() {
-            info!("Initializing with stored mode {:?}", mode);
-            controller.mode = mode;
-        } else {
-            info!("No current mode stored, defaulting to music");
+        match controller.fetch_current_mode() {
+            Some(mode) if controller.modes.contains(&mode) => {
+                info!("Initializing with stored mode {mode}");
+                controller.mode = mode;
+            }
+            Some(mode) => warn!(
+                "Stored mode {mode} is not configured, defaulting to {}",
+                controller.mode
+            ),
+            None => info!(
+                "No current mode stored, defaulting to {}",
+                controller.mode
+            ),
         }
 
-        controller.playing();
-        controller
+        controller.playing()?;
+        Ok(controller)
+    }
+
+    pub fn reconnect(&mut self) -> Result<(), AudioError> {
+        self.client.reconnect()?;
+        Ok(())
     }
 
-    pub fn reconnect(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let address = format!("{}:{}", self.ip, self.port);
-        self.client = MpdInterface::connect(&address)?;
+    pub fn rescan(&mut self) -> Result<(), AudioError> {
+        info!("Rescanning music library");
+        self.client.rescan()?;
         Ok(())
     }
 
-    pub fn rescan(&mut self) {
-        info!("Rescanning mpd library");
-        self.client.rescan().unwrap();
+    fn playing(&mut self) -> Result<bool, AudioError> {
+        let playback_state = self.client.status()?.state;
+        Ok(playback_state == PlayState::Play)
     }
 
-    fn playing(&mut self) -> bool {
-        let playback_state = self.client.status().unwrap().state;
-        playback_state == State::Play
+    fn stopped(&mut self) -> Result<bool, AudioError> {
+        let playback_state = self.client.status()?.state;
+        Ok(playback_state == PlayState::Stop)
     }
 
-    fn stopped(&mut self) -> bool {
-        let playback_state = self.client.status().unwrap().state;
-        playback_state == State::Stop
+    /// Everything but the connection health, which the controller does not
+    /// know about
+    pub fn status(&mut self) -> Result<Status, AudioError> {
+        let player_status = self.client.status()?;
+        let song = self.client.current_song()?;
+
+        let state = match player_status.state {
+            PlayState::Play => "play",
+            PlayState::Pause => "pause",
+            PlayState::Stop => "stop",
+        };
+        let song = song.map(|song| status::Song {
+            file: song.file,
+            title: song.title,
+            elapsed_secs: player_status.elapsed.map(|e| e.as_secs()),
+            duration_secs: player_status.duration.map(|d| d.as_secs()),
+        });
+
+        let modes = self
+            .modes
+            .iter()
+            .map(|mode| {
+                let playlist = self.db.fetch_playlist_name(&mode.name);
+                let position = playlist
+                    .as_deref()
+                    .and_then(|playlist| self.db.fetch_position(playlist))
+                    .map(|position| status::Position {
+                        song: position.pos_in_pl,
+                        elapsed_secs: position.elapsed,
+                    });
+                let last_played = playlist
+                    .as_deref()
+                    .and_then(|playlist| self.db.fetch_last_played(playlist));
+                ModeStatus {
+                    mode: mode.name.to_string(),
+                    playlist,
+                    position,
+                    last_played,
+                }
+            })
+            .collect();
+
+        Ok(Status {
+            mode: self.mode.to_string(),
+            state: state.to_owned(),
+            song,
+            modes,
+            panels: BTreeMap::new(),
+            data_server: Link::default(),
+        })
     }
 
-    fn get_playlists(&mut self) -> Vec<Playlist> {
-        self.client.playlists().unwrap()
+    fn get_playlists(&mut self) -> Result<Vec<String>, AudioError> {
+        Ok(self.client.playlists()?)
     }
 
     #[instrument(ret)]
     fn auto_rewind_time(last_played: u64) -> Duration {
         const MIN_REWIND: u32 = 2;
 
-        let since_last_played = Db::now_timestamp() - last_played;
+        // the clock may have been set back since
+        let since_last_played = now_timestamp().saturating_sub(last_played);
         info!("{}s since last played", since_last_played);
 
         #[allow(
@@ -197,190 +233,241 @@ impl AudioController {
         if let Some(current_playlist) = self.db.fetch_playlist_name(&self.mode)
         {
             self.db
-                .store_last_played(&current_playlist, Db::now_timestamp());
+                .store_last_played(&current_playlist, now_timestamp());
         }
     }
 
-    fn rewind_after_pause(&mut self) {
-        use AudioMode::*;
+    /// Settings of the current mode
+    pub fn current_mode(&self) -> &Mode {
+        self.modes.get(&self.mode)
+    }
+
+    fn rewind_after_pause(&mut self) -> Result<(), AudioError> {
         const SONG_RESTART_THRESHOLD: Duration =
             Duration::from_secs(4 * 60 * 60 * 10000);
         const ALMOST_OVER: Duration = Duration::from_secs(30);
 
         let current_playlist = match self.db.fetch_playlist_name(&self.mode) {
             Some(current_playlist) => current_playlist,
-            None => return,
+            None => return Ok(()),
         };
 
         let last_played = self.db.fetch_last_played(&current_playlist);
 
-        match (&self.mode, last_played) {
-            (Podcast, Some(last_played)) => {
-                self.rewind_by(Self::auto_rewind_time(last_played));
+        match (self.current_mode().rewind, last_played) {
+            (Rewind::Auto, Some(last_played)) => {
+                self.rewind_by(Self::auto_rewind_time(last_played))?;
             }
-            (Music | Singing | Meditation, Some(last_played)) => {
+            (Rewind::RestartSong, Some(last_played)) => {
                 if let (Some(length), Some(position)) =
-                    (self.get_song_length(), self.get_elapsed())
+                    (self.get_song_length()?, self.get_elapsed()?)
                 {
                     debug!(
                         "Song length: {length:?}, song position: {position:?}"
                     );
-                    let time_left = length - position;
-                    if Duration::from_secs(Db::now_timestamp() - last_played)
-                        > SONG_RESTART_THRESHOLD
+                    let time_left = length.saturating_sub(position);
+                    let paused = now_timestamp().saturating_sub(last_played);
+                    if Duration::from_secs(paused) > SONG_RESTART_THRESHOLD
                         && time_left > ALMOST_OVER
                     {
-                        self.seek_in_cur(0);
+                        self.seek_in_cur(0)?;
                     }
                 }
             }
-            (_, None) => (),
+            (Rewind::None, _) | (_, None) => (),
         }
+        Ok(())
     }
 
     #[instrument]
-    pub fn toggle_playback(&mut self) {
+    pub fn toggle_playback(&mut self) -> Result<(), AudioError> {
         info!("Toggle playback");
-        let was_playing = self.playing();
+        let was_playing = self.playing()?;
 
-        if self.stopped() {
-            self.client.play().unwrap();
+        if self.stopped()? {
+            self.client.play()?;
         } else {
-            self.client.toggle_pause().unwrap();
+            self.client.toggle_pause()?;
         }
 
         if was_playing {
             self.store_current_pausing();
+            Ok(())
         } else {
-            self.rewind_after_pause();
+            self.rewind_after_pause()
+        }
+    }
+
+    #[instrument]
+    pub fn pause(&mut self) -> Result<(), AudioError> {
+        if self.playing()? {
+            self.toggle_playback()?;
+        }
+        Ok(())
+    }
+
+    /// Stops playback, remembering where we were in the current playlist
+    #[instrument]
+    pub fn stop(&mut self) -> Result<(), AudioError> {
+        info!("Stop");
+        if let Some(current_playlist) = self.db.fetch_playlist_name(&self.mode)
+        {
+            self.leave_playlist(&current_playlist)?;
         }
+        self.client.stop()?;
+        Ok(())
     }
 
     #[instrument]
-    pub fn play(&mut self, force_rewind: ForceRewind) {
-        if !self.playing() {
-            self.toggle_playback();
+    pub fn play(
+        &mut self,
+        force_rewind: ForceRewind,
+    ) -> Result<(), AudioError> {
+        if !self.playing()? {
+            self.toggle_playback()
         } else if force_rewind == ForceRewind::Yes {
             self.rewind_after_pause()
+        } else {
+            Ok(())
         }
     }
 
-    fn get_song_length(&mut self) -> Option<Duration> {
-        self.client.status().unwrap().duration
+    fn get_song_length(&mut self) -> Result<Option<Duration>, AudioError> {
+        Ok(self.client.status()?.duration)
     }
 
-    fn get_elapsed(&mut self) -> Option<Duration> {
-        self.client.status().unwrap().elapsed
+    fn get_elapsed(&mut self) -> Result<Option<Duration>, AudioError> {
+        Ok(self.client.status()?.elapsed)
     }
 
-    /// # Panics
-    ///
-    /// Panics if new position is over 4,294,967,295 seconds into the song,
-    /// which is 136 years. I assume this will never happen.
-    ///
-    /// Panics if client.rewind() returns an error. This may very well happen.
-    fn rewind_by(&mut self, duration: Duration) {
+    pub fn rewind_by(&mut self, duration: Duration) -> Result<(), AudioError> {
         if duration == Duration::from_secs(0) {
             debug!("0 seconds, not rewinding");
-            return;
+            return Ok(());
         }
         info!("Rewinding by {:?}", duration);
 
-        if let Some(position) = self.get_elapsed() {
-            self.client
-                .rewind(
-                    position
-                        .saturating_sub(duration)
-                        .as_secs()
-                        .try_into()
-                        .unwrap(),
-                )
-                .unwrap();
+        if let Some(position) = self.get_elapsed()? {
+            let target = position.saturating_sub(duration);
+            self.client.seek_current(whole_secs(target))?;
         }
+        Ok(())
     }
 
-    pub fn rewind(&mut self) {
-        self.rewind_by(Duration::from_secs(15));
-        self.play(ForceRewind::No);
+    pub fn rewind(&mut self) -> Result<(), AudioError> {
+        self.rewind_by(Duration::from_secs(15))?;
+        self.play(ForceRewind::No)
     }
 
-    /// # Panics
-    ///
-    /// Panics if new position is over 4,294,967,295 seconds into the song,
-    /// which is 136 years. I assume this will never happen.
-    ///
-    /// Panics if client.rewind() returns an error. This may very well happen.
-    pub fn skip(&mut self) {
-        info!("Skipping by 15 seconds");
+    /// Skips ahead in the current song, stopping just short of the end.
+    pub fn skip_by(&mut self, duration: Duration) -> Result<(), AudioError> {
+        info!("Skipping by {:?}", duration);
 
-        if let Some(position) = self.get_elapsed() {
-            self.client
-                .rewind((position.as_secs() + 15).try_into().unwrap())
-                .unwrap();
+        if let Some(position) = self.get_elapsed()? {
+            let mut target = position + duration;
+            if let Some(length) = self.get_song_length()? {
+                let almost_end =
+                    length.saturating_sub(Duration::from_secs(1));
+                target = target.min(almost_end);
+            }
+            self.client.seek_current(whole_secs(target))?;
         }
+        Ok(())
+    }
+
+    pub fn skip(&mut self) -> Result<(), AudioError> {
+        self.skip_by(Duration::from_secs(15))?;
+        self.play(ForceRewind::No)
+    }
+
+    /// The volume in percent, `None` if the player has no mixer
+    pub fn volume(&mut self) -> Result<Option<i8>, AudioError> {
+        let volume = self.client.status()?.volume;
+        Ok((volume >= 0).then_some(volume))
+    }
+
+    /// Set the volume to `percent`, kept between 0 and 100
+    pub fn set_volume(&mut self, percent: i8) -> Result<(), AudioError> {
+        let volume = percent.clamp(0, 100);
+        debug!("Setting volume to {volume}");
+        self.client.volume(volume)?;
+        Ok(())
+    }
 
-        self.play(ForceRewind::No);
+    /// Change the volume by `delta` percent
+    pub fn change_volume(&mut self, delta: i8) -> Result<(), AudioError> {
+        let Some(volume) = self.volume()? else {
+            debug!("Player has no mixer, can not change volume");
+            return Ok(());
+        };
+        self.set_volume(volume.saturating_add(delta))
     }
 
-    pub fn previous(&mut self) {
+    pub fn previous(&mut self) -> Result<(), AudioError> {
         info!("Going to previous track");
 
         match self.client.prev() {
-            Ok(_) => (),
-            Err(Error::Server(server_error)) => {
-                info!("Ignoring error during prev: {server_error}");
-                assert!(
-                    server_error.detail == "Not playing",
-                    "Unexpected ServerError: {server_error}"
-                );
+            Ok(()) => (),
+            Err(Error::NotPlaying) => {
+                info!("Ignoring error during prev: {}", Error::NotPlaying);
             }
-            Err(other_error) => panic!("Unexpected error: {other_error}"),
+            Err(other_error) => return Err(other_error.into()),
         };
         // self.play(ForceRewind::No);
+        Ok(())
     }
 
     #[instrument]
-    pub fn next(&mut self) {
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<(), AudioError> {
         info!("Next");
 
         match self.client.next() {
-            Ok(_) => (),
-            Err(Error::Server(server_error)) => {
-                assert!(
-                    server_error.detail == "Not playing",
-                    "Unexpected ServerError: {server_error}"
-                );
-            }
-            Err(other_error) => panic!("Unexpected error: {other_error}"),
+            Ok(()) | Err(Error::NotPlaying) => (),
+            Err(other_error) => return Err(other_error.into()),
         };
 
-        self.play(ForceRewind::No);
+        self.play(ForceRewind::No)
     }
 
-    fn apply_shuffle(&mut self, playlist_name: &str) {
+    fn apply_shuffle(&mut self, playlist_name: &str) -> Result<(), AudioError> {
         if playlist_name.ends_with("_shuf") {
-            self.client.random(true).unwrap();
+            self.client.random(true)?;
         } else {
-            let random = self.mode.settings().random;
-            self.client.random(random).unwrap();
+            let random = self.current_mode().random;
+            self.client.random(random)?;
         }
-        self.client.pause().unwrap();
+        self.client.pause()?;
+        Ok(())
+    }
+
+    /// Remember where we were in `playlist_name`, before going elsewhere
+    fn leave_playlist(
+        &mut self,
+        playlist_name: &str,
+    ) -> Result<(), AudioError> {
+        self.store_position(playlist_name)?;
+        self.save_playlist_if_necessary(playlist_name)?;
+        self.db.store_last_played(playlist_name, now_timestamp());
+        Ok(())
     }
 
     #[instrument]
-    fn switch_playlist(&mut self, direction: Direction) {
+    fn switch_playlist(
+        &mut self,
+        direction: Direction,
+    ) -> Result<(), AudioError> {
         let current_playlist_name =
             match self.db.fetch_playlist_name(&self.mode) {
                 Some(playlist_name) => playlist_name,
-                None => self.first_playlist_for_mode().unwrap(),
+                None => self.first_playlist_for_mode()?.ok_or_else(|| {
+                    AudioError::NoPlaylists(self.mode.clone())
+                })?,
             };
-        self.store_position(&current_playlist_name);
-        self.save_playlist_if_necessary(&current_playlist_name);
-        self.db
-            .store_last_played(&current_playlist_name, Db::now_timestamp());
+        self.leave_playlist(&current_playlist_name)?;
 
         let new_playlist_name = if let Some(playlist_name) =
-            self.playlist_for_mode(direction, &current_playlist_name)
+            self.playlist_for_mode(direction, &current_playlist_name)?
         {
             playlist_name
         } else {
@@ -388,44 +475,41 @@ impl AudioController {
         };
 
         info!("Switching to playlist {}", new_playlist_name);
-        self.load_playlist(&new_playlist_name);
+        self.load_playlist(&new_playlist_name)?;
         self.db.store_playlist_name(&self.mode, &new_playlist_name);
-        self.apply_shuffle(&new_playlist_name);
+        self.apply_shuffle(&new_playlist_name)?;
 
         let new_position = self.db.fetch_position(&new_playlist_name);
-        self.load_position(new_position);
+        self.load_position(new_position)?;
+        self.events.send(Event::Playlist {
+            mode: self.mode.to_string(),
+            playlist: new_playlist_name,
+        });
 
         //self.play(ForceRewind::Yes);
+        Ok(())
     }
 
-    pub fn prev_playlist(&mut self) {
-        self.switch_playlist(Direction::Previous);
+    pub fn prev_playlist(&mut self) -> Result<(), AudioError> {
+        self.switch_playlist(Direction::Previous)
     }
 
-    pub fn next_playlist(&mut self) {
-        self.switch_playlist(Direction::Next);
+    pub fn next_playlist(&mut self) -> Result<(), AudioError> {
+        self.switch_playlist(Direction::Next)
     }
 
     /// Meditation mode is only enabled at night
-    fn is_meditation_time() -> bool {
-        use chrono::{naive::NaiveTime, offset::Local};
-
-        const START_HOUR: u32 = 21;
-        const START_MIN: u32 = 30;
-        const END_HOUR: u32 = 9;
-        const END_MIN: u32 = 0;
+    fn is_meditation_time(&self) -> bool {
+        use chrono::offset::Local;
 
         let now = Local::now().time();
         debug!("Checking if it is meditation time: now is {:?}", now);
-        let start = NaiveTime::from_hms_opt(START_HOUR, START_MIN, 0).unwrap();
-        let end = NaiveTime::from_hms_opt(END_HOUR, END_MIN, 0).unwrap();
-
-        debug!("Meditation start time: {}, end time: {}", start, end);
-        let start_sm_now = start < now;
-        let now_sm_end = now < end;
-        debug!("start < now: {}, now < end: {}", start_sm_now, now_sm_end);
+        debug!(
+            "Meditation start time: {}, end time: {}",
+            self.meditation.start, self.meditation.end
+        );
 
-        start < now || now < end
+        self.meditation.contains(now)
     }
 
     fn fetch_current_mode(&self) -> Option<AudioMode> {
@@ -436,76 +520,113 @@ impl AudioController {
         self.db.store_mode(&self.mode);
     }
 
-    pub fn next_mode(&mut self) {
+    /// Switches to the next mode in the cycle, skipping modes that have no
+    /// playlists.
+    pub fn next_mode(&mut self) -> Result<(), AudioError> {
         let current_playlist_name =
             match self.db.fetch_playlist_name(&self.mode) {
-                Some(playlist_name) => playlist_name,
-                None => self.first_playlist_for_mode().unwrap(),
+                Some(playlist_name) => Some(playlist_name),
+                None => self.first_playlist_for_mode()?,
             };
-        self.store_position(&current_playlist_name);
-        self.save_playlist_if_necessary(&current_playlist_name);
-        self.db
-            .store_last_played(&current_playlist_name, Db::now_timestamp());
-
-        self.mode.next();
-        info!("Switching to mode {:?}", self.mode);
-
-        // if self.mode == AudioMode::Meditation && !Self::is_meditation_time() {
-        //     self.mode.next();
-        //     info!("Skipping meditation");
-        // }
-
-        // Check if a playlist is stored in the db, and still exists
-        let new_playlist_name = self.db.fetch_playlist_name(&self.mode);
-        let new_playlist_name = match new_playlist_name {
+        if let Some(current_playlist_name) = current_playlist_name {
+            self.leave_playlist(&current_playlist_name)?;
+        }
+
+        let previous_mode = self.mode.clone();
+        for _ in 0..self.modes.len() {
+            self.mode = self.modes.next(&self.mode).clone();
+            info!("Switching to mode {}", self.mode);
+
+            // if self.mode == AudioMode::from("Meditation")
+            //     && !self.is_meditation_time()
+            // {
+            //     info!("Skipping meditation");
+            //     continue;
+            // }
+
+            if let Some(playlist_name) = self.mode_playlist()? {
+                self.enter_mode(&playlist_name)?;
+                return Ok(());
+            }
+            info!("No playlists for mode {}, skipping it", self.mode);
+        }
+
+        self.mode = previous_mode;
+        Err(AudioError::NoPlaylistsAtAll)
+    }
+
+    /// The playlist stored for the current mode if it still exists, otherwise
+    /// the first playlist of the mode
+    fn mode_playlist(&mut self) -> Result<Option<String>, AudioError> {
+        match self.db.fetch_playlist_name(&self.mode) {
             Some(playlist_name)
                 if self.client.playlist_exists(&playlist_name) =>
             {
-                playlist_name
+                Ok(Some(playlist_name))
             }
             _ => {
-                let playlist_name = self.first_playlist_for_mode().unwrap();
+                let Some(playlist_name) = self.first_playlist_for_mode()?
+                else {
+                    return Ok(None);
+                };
                 self.db.store_playlist_name(&self.mode, &playlist_name);
-                playlist_name
+                Ok(Some(playlist_name))
             }
-        };
-        self.load_playlist(&new_playlist_name);
+        }
+    }
+
+    fn enter_mode(&mut self, playlist_name: &str) -> Result<(), AudioError> {
+        self.load_playlist(playlist_name)?;
         self.store_current_mode();
 
-        let new_position = self.db.fetch_position(&new_playlist_name);
-        self.load_position(new_position);
+        let new_position = self.db.fetch_position(playlist_name);
+        self.load_position(new_position)?;
 
-        self.apply_settings(&self.mode.settings());
-        self.apply_shuffle(&new_playlist_name);
+        let mode = self.current_mode().clone();
+        self.apply_settings(&mode)?;
+        self.apply_shuffle(playlist_name)?;
+        self.events.send(Event::Mode {
+            mode: self.mode.to_string(),
+            playlist: playlist_name.to_owned(),
+        });
+        Ok(())
     }
 
-    fn save_playlist_if_necessary(&mut self, playlist_name: &str) {
-        if self.mode.settings().save_playlist {
-            self.client.pl_remove(playlist_name).unwrap();
-            self.client.save(playlist_name).unwrap();
+    fn save_playlist_if_necessary(
+        &mut self,
+        playlist_name: &str,
+    ) -> Result<(), AudioError> {
+        if self.current_mode().save_playlist {
+            match self.client.pl_remove(playlist_name) {
+                Ok(()) | Err(Error::NoSuchPlaylist) => (),
+                Err(other_error) => return Err(other_error.into()),
+            }
+            self.client.save(playlist_name)?;
         }
+        Ok(())
     }
 
     #[instrument(ret)]
-    fn first_playlist_for_mode(&mut self) -> Option<String> {
-        let playlists = self.get_playlists();
-        for playlist in playlists {
-            if playlist.name.starts_with(self.mode.to_prefix()) {
-                return Some(playlist.name);
-            }
-        }
-        None
+    fn first_playlist_for_mode(
+        &mut self,
+    ) -> Result<Option<String>, AudioError> {
+        let playlists = self.get_playlists()?;
+        let prefix = &self.current_mode().prefix;
+        Ok(playlists.into_iter().find(|name| name.starts_with(prefix)))
     }
 
+    /// The playlist after `current_playlist_name` in `direction`, or the
+    /// first one if it is gone
     #[instrument(ret)]
     fn playlist_for_mode(
         &mut self,
         direction: Direction,
         current_playlist_name: &String,
-    ) -> Option<String> {
-        let playlist_names = self.get_playlists().into_iter().map(|pl| pl.name);
+    ) -> Result<Option<String>, AudioError> {
+        let playlist_names = self.get_playlists()?.into_iter();
+        let prefix = &self.current_mode().prefix;
         let mut playlist_names = playlist_names
-            .filter(|pl| pl.starts_with(self.mode.to_prefix()))
+            .filter(|pl| pl.starts_with(prefix))
             .collect::<Vec<_>>();
 
         playlist_names.sort();
@@ -513,129 +634,153 @@ impl AudioController {
         if let Direction::Previous = direction {
             playlist_names.reverse();
         }
-        let mut playlist_names = playlist_names.iter().cycle().peekable();
-
-        while *playlist_names.peek().unwrap() != current_playlist_name {
-            playlist_names.next();
-        }
-        playlist_names.nth(1).map(std::borrow::ToOwned::to_owned)
-    }
-
-    fn store_position(&mut self, playlist_name: &str) {
-        let pos_in_pl = if let Some(song) = self.client.status().unwrap().song {
-            song.pos
-        } else {
-            0
+        let next = match playlist_names
+            .iter()
+            .position(|pl| pl == current_playlist_name)
+        {
+            Some(current) => (current + 1) % playlist_names.len(),
+            None => 0,
         };
+        Ok(playlist_names.get(next).cloned())
+    }
 
-        let elapsed = if let Some(elapsed) = self.get_elapsed() {
-            elapsed.as_secs().try_into().unwrap()
-        } else {
-            0
-        };
+    fn store_position(
+        &mut self,
+        playlist_name: &str,
+    ) -> Result<(), AudioError> {
+        let pos_in_pl = self.client.status()?.song.unwrap_or(0);
+        let elapsed = self.get_elapsed()?.map_or(0, whole_secs);
 
-        let position = db::Position { pos_in_pl, elapsed };
+        let position = store::Position { pos_in_pl, elapsed };
         self.db.store_position(playlist_name, &position);
+        Ok(())
     }
 
-    fn load_playlist(&mut self, playlist_name: &str) {
-        self.client.clear().unwrap();
-        self.client.load(playlist_name, ..).expect("Should exist");
-        self.client.pause().unwrap();
+    fn load_playlist(&mut self, playlist_name: &str) -> Result<(), AudioError> {
+        self.client.clear()?;
+        match self.client.load(playlist_name) {
+            Ok(()) => (),
+            Err(Error::NoSuchPlaylist) => {
+                return Err(AudioError::PlaylistNotFound(
+                    playlist_name.to_owned(),
+                ))
+            }
+            Err(other_error) => return Err(other_error.into()),
+        }
+        self.client.pause()?;
+        Ok(())
     }
 
-    fn load_position(&mut self, position: Option<db::Position>) {
+    fn load_position(
+        &mut self,
+        position: Option<store::Position>,
+    ) -> Result<(), AudioError> {
         if let Some(position) = position {
-            self.client.queue().unwrap();
-            self.seek_to(position.pos_in_pl, position.elapsed);
+            self.client.queue()?;
+            self.seek_to(position.pos_in_pl, position.elapsed)?;
         } else {
-            self.seek_to(0, 0);
+            self.seek_to(0, 0)?;
         }
-        self.client.pause().unwrap();
+        self.client.pause()?;
+        Ok(())
     }
 
-    fn seek_to(&mut self, pos_in_pl: u32, elapsed: u32) {
+    /// Positions past the end of the playlist are ignored, it may have
+    /// gotten shorter
+    fn seek_to(
+        &mut self,
+        pos_in_pl: u32,
+        elapsed: u32,
+    ) -> Result<(), AudioError> {
         match self.client.seek(pos_in_pl, elapsed) {
-            Ok(_) => (),
-            Err(Error::Server(server_error)) => {
-                assert!(
-                    server_error.detail == "Bad song index",
-                    "Unexpected ServerError: {server_error}"
-                );
-            }
-            Err(other_error) => panic!("Unexpected error: {other_error}"),
+            Ok(()) | Err(Error::BadSongIndex) => Ok(()),
+            Err(other_error) => Err(other_error.into()),
         }
     }
 
-    fn seek_in_cur(&mut self, elapsed: u32) {
-        if let Some(song) = self.client.currentsong().unwrap() {
-            if let Some(place) = song.place {
-                self.seek_to(place.pos, elapsed);
+    fn seek_in_cur(&mut self, elapsed: u32) -> Result<(), AudioError> {
+        if let Some(song) = self.client.current_song()? {
+            if let Some(pos) = song.pos {
+                self.seek_to(pos, elapsed)?;
             }
         }
+        Ok(())
     }
 
-    fn apply_settings(&mut self, audio_settings: &Settings) {
-        self.client.repeat(audio_settings.repeat).unwrap();
-        self.client.random(audio_settings.random).unwrap();
-        self.client.single(audio_settings.single).unwrap();
-        self.client.consume(audio_settings.consume).unwrap();
-        self.client.pause().unwrap();
+    fn apply_settings(
+        &mut self,
+        audio_settings: &Mode,
+    ) -> Result<(), AudioError> {
+        self.client.repeat(audio_settings.repeat)?;
+        self.client.random(audio_settings.random)?;
+        self.client.single(audio_settings.single)?;
+        self.client.consume(audio_settings.consume)?;
+        self.client.pause()?;
+        Ok(())
     }
 
-    pub(crate) fn go_to_mode(&mut self, target_mode: &AudioMode) {
-        while self.mode != *target_mode {
-            self.next_mode();
+    pub(crate) fn go_to_mode(
+        &mut self,
+        target_mode: &AudioMode,
+    ) -> Result<(), AudioError> {
+        if !self.modes.contains(target_mode) {
+            return Err(AudioError::UnknownMode(target_mode.clone()));
+        }
+        for _ in 0..self.modes.len() {
+            if self.mode == *target_mode {
+                return Ok(());
+            }
+            self.next_mode()?;
+        }
+        if self.mode == *target_mode {
+            Ok(())
+        } else {
+            Err(AudioError::NoPlaylists(target_mode.clone()))
         }
     }
 
     pub(crate) fn go_to_playlist(
         &mut self,
         target_playlist: &str,
-    ) -> Result<(), String> {
-        let old_playlist = self.db.fetch_playlist_name(&self.mode).unwrap();
-        while self.db.fetch_playlist_name(&self.mode).unwrap()
-            != *target_playlist
-        {
-            self.switch_playlist(Direction::Next);
-            if self.db.fetch_playlist_name(&self.mode).unwrap() == old_playlist
-            {
-                return Err(format!(
-                    "Could not find target playlist {target_playlist:?} to go to"
-                ));
+    ) -> Result<(), AudioError> {
+        // going through every playlist once is enough to find it
+        for _ in 0..=self.get_playlists()?.len() {
+            let current = self.db.fetch_playlist_name(&self.mode);
+            if current.as_deref() == Some(target_playlist) {
+                return Ok(());
             }
+            self.switch_playlist(Direction::Next)?;
         }
 
-        Ok(())
+        Err(AudioError::PlaylistNotFound(target_playlist.to_owned()))
     }
 
-    pub(crate) async fn play_mode_playlist(
+    /// Play `playlist` from the start in `mode`. Getting there the usual
+    /// way is best effort, the playlist is loaded either way.
+    pub(crate) fn play_mode_playlist(
         &mut self,
         mode: &AudioMode,
         playlist: &str,
-    ) {
-        self.go_to_mode(mode);
-        tokio::time::sleep(Duration::from_millis(100)).await;
-        match self.go_to_playlist(playlist) {
-            Ok(()) => (),
-            Err(e) => println!("{e}"),
-        };
-        tokio::time::sleep(Duration::from_millis(100)).await;
-        self.load_playlist(playlist);
-        tokio::time::sleep(Duration::from_millis(100)).await;
-        self.load_position(None);
-        tokio::time::sleep(Duration::from_millis(100)).await;
-        self.play(ForceRewind::No);
+    ) -> Result<(), AudioError> {
+        if let Err(err) = self.go_to_mode(mode) {
+            warn!("Could not go to mode {mode}: {err}");
+        }
+        if let Err(err) = self.go_to_playlist(playlist) {
+            warn!("Could not go to playlist {playlist}: {err}");
+        }
+        self.load_playlist(playlist)?;
+        self.load_position(None)?;
+        self.play(ForceRewind::No)
     }
 
-    pub(crate) async fn create_wakeup_playlist(&mut self, pl_name: &str) {
-        let slow_songs = self.client.playlist("slow").unwrap();
-        tokio::time::sleep(Duration::from_millis(100)).await;
-        let normal_songs = self.client.playlist("music_all_shuf").unwrap();
-        tokio::time::sleep(Duration::from_millis(100)).await;
+    pub(crate) fn create_wakeup_playlist(
+        &mut self,
+        pl_name: &str,
+    ) -> Result<(), AudioError> {
+        let slow_songs = self.client.playlist("slow")?;
+        let normal_songs = self.client.playlist("music_all_shuf")?;
 
-        self.client.pl_clear(pl_name).unwrap();
-        tokio::time::sleep(Duration::from_millis(100)).await;
+        self.client.pl_clear(pl_name)?;
 
         let to_add = {
             let mut rng = rand::thread_rng();
@@ -646,14 +791,42 @@ impl AudioController {
         };
 
         for song in to_add {
-            self.client.pl_push(pl_name, song).unwrap();
-            tokio::time::sleep(Duration::from_millis(10)).await;
+            self.client.pl_push(pl_name, &song.file)?;
         }
+        Ok(())
+    }
+
+    /// Replace the queue with `file` and play it
+    pub(crate) fn play_file(&mut self, file: &str) -> Result<(), AudioError> {
+        self.client.clear()?;
+        self.client.push(file)?;
+        self.client.play()?;
+        Ok(())
     }
 
-    pub(crate) fn insert_next(&mut self, song_path: &str) {
-        if let Ok(id) = self.client.push(song_path) {
-            let _ = self.client.prioid(id, 128);
+    pub(crate) fn schedule(&self) -> Schedule {
+        self.db.fetch_schedule()
+    }
+
+    pub(crate) fn store_schedule(&self, schedule: &Schedule) {
+        self.db.store_schedule(schedule);
+    }
+
+    /// Add a song to the queue and play it after the current song
+    pub(crate) fn insert_next(
+        &mut self,
+        song_path: &str,
+    ) -> Result<(), AudioError> {
+        let not_queued = |err| AudioError::NotQueued {
+            song: song_path.to_owned(),
+            err,
         };
+        let id = self.client.push(song_path).map_err(not_queued)?;
+        self.client.prioid(id, 128).map_err(not_queued)
     }
 }
+
+/// Seconds for the player, which takes whole ones
+fn whole_secs(duration: Duration) -> u32 {
+    u32::try_from(duration.as_secs()).unwrap_or(u32::MAX)
+}
@@ -0,0 +1,275 @@
+//! Audio modes are defined in the config. Each mode owns the playlists whose
+//! name starts with its prefix and has its own mpd settings. The panel
+//! cycles through the modes in the order they are configured.
+
+use std::fmt;
+
+use button_protocol::Color;
+use serde::{Deserialize, Serialize};
+
+/// Name of a configured mode
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct AudioMode(String);
+
+impl From<&str> for AudioMode {
+    fn from(name: &str) -> Self {
+        Self(name.to_owned())
+    }
+}
+
+impl fmt::Display for AudioMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AudioMode {
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    /// Lowercase name, used in database keys and the keymap. For the
+    /// original modes this is the prefix without the `_`, which keeps
+    /// the existing database keys valid.
+    pub(crate) fn key(&self) -> String {
+        self.0.to_lowercase()
+    }
+
+    /// Modes used to be stored as a single byte, before they were
+    /// configurable
+    fn from_legacy_byte(byte: u8) -> Option<Self> {
+        let name = match byte {
+            1 => "Music",
+            2 => "Singing",
+            3 => "Podcast",
+            4 => "Meditation",
+            _ => return None,
+        };
+        Some(Self::from(name))
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        self.0.as_bytes().to_vec()
+    }
+
+    /// Also reads the single byte format used before modes were
+    /// configurable
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Self {
+        if let &[byte] = bytes {
+            if let Some(mode) = Self::from_legacy_byte(byte) {
+                return mode;
+            }
+        }
+        Self(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/// What to do when playback resumes after a pause
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rewind {
+    /// Rewind a bit, longer pauses rewind further
+    Auto,
+    /// Restart the song after a very long pause, unless it is almost over
+    RestartSong,
+    #[default]
+    None,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct Mode {
+    pub name: AudioMode,
+    /// playlists starting with this prefix belong to the mode
+    pub prefix: String,
+    /// color of the top middle led while in this mode
+    #[serde(default = "white")]
+    pub color: [u8; 3],
+    #[serde(default)]
+    pub rewind: Rewind,
+
+    #[serde(default)]
+    pub repeat: bool,
+    #[serde(default)]
+    pub random: bool,
+    #[serde(default)]
+    pub single: bool,
+    #[serde(default)]
+    pub consume: bool,
+    /// save the queue (and thus what got consumed) when leaving a playlist
+    #[serde(default)]
+    pub save_playlist: bool,
+}
+
+fn white() -> [u8; 3] {
+    [255, 255, 255]
+}
+
+impl Mode {
+    fn new(name: &str, color: Color, rewind: Rewind) -> Self {
+        Self {
+            name: AudioMode::from(name),
+            prefix: format!("{}_", name.to_lowercase()),
+            color: [color.r, color.g, color.b],
+            rewind,
+            repeat: false,
+            random: false,
+            single: false,
+            consume: false,
+            save_playlist: false,
+        }
+    }
+
+    pub fn color(&self) -> Color {
+        let [r, g, b] = self.color;
+        Color::rgb(r, g, b)
+    }
+}
+
+/// All configured modes, in cycle order
+#[derive(Debug, Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Modes(Vec<Mode>);
+
+impl Default for Modes {
+    fn default() -> Self {
+        Self(vec![
+            Mode::new("Music", Color::GREEN, Rewind::RestartSong),
+            Mode::new("Singing", Color::YELLOW, Rewind::RestartSong),
+            Mode {
+                consume: true,
+                save_playlist: true,
+                ..Mode::new("Podcast", Color::BLUE, Rewind::Auto)
+            },
+            Mode {
+                single: true,
+                ..Mode::new("Meditation", Color::PURPLE, Rewind::RestartSong)
+            },
+            Mode {
+                save_playlist: true,
+                ..Mode::new("Audiobook", Color::rgb(255, 60, 0), Rewind::Auto)
+            },
+            Mode {
+                repeat: true,
+                ..Mode::new("Kids", Color::rgb(0, 255, 200), Rewind::None)
+            },
+        ])
+    }
+}
+
+impl Modes {
+    pub fn iter(&self) -> impl Iterator<Item = &Mode> {
+        self.0.iter()
+    }
+
+    pub fn first(&self) -> &AudioMode {
+        &self.0.first().expect("validated: there is at least one mode").name
+    }
+
+    pub fn contains(&self, mode: &AudioMode) -> bool {
+        self.0.iter().any(|m| m.name == *mode)
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the mode is not configured, check with
+    /// [`contains`](Self::contains) for modes that are not from the config.
+    pub fn get(&self, mode: &AudioMode) -> &Mode {
+        self.0
+            .iter()
+            .find(|m| m.name == *mode)
+            .unwrap_or_else(|| panic!("Mode {mode} is not configured"))
+    }
+
+    /// Find a mode by name, ignoring case
+    pub fn find(&self, name: &str) -> Option<&AudioMode> {
+        self.0
+            .iter()
+            .map(|m| &m.name)
+            .find(|m| m.name().eq_ignore_ascii_case(name))
+    }
+
+    /// The mode after `mode` in the cycle
+    pub fn next(&self, mode: &AudioMode) -> &AudioMode {
+        let idx = self.0.iter().position(|m| m.name == *mode).unwrap_or(0);
+        &self.0[(idx + 1) % self.0.len()].name
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.0.is_empty() {
+            return Err("There should be at least one mode".to_owned());
+        }
+        for (i, mode) in self.0.iter().enumerate() {
+            if mode.name.name().is_empty() {
+                return Err("Mode names can not be empty".to_owned());
+            }
+            if mode.prefix.is_empty() {
+                return Err(format!("Mode {} has an empty prefix", mode.name));
+            }
+            for other in &self.0[i + 1..] {
+                if mode.name.key() == other.name.key() {
+                    return Err(format!("Mode {} is defined twice", mode.name));
+                }
+                if mode.prefix.starts_with(&other.prefix)
+                    || other.prefix.starts_with(&mode.prefix)
+                {
+                    return Err(format!(
+                        "Prefixes of modes {} and {} overlap: {:?} and {:?}",
+                        mode.name, other.name, mode.prefix, other.prefix
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_bytes_are_read() {
+        let podcast = AudioMode::from_bytes(&[3]);
+        assert_eq!(podcast, AudioMode::from("Podcast"));
+        assert_eq!(AudioMode::from_bytes(&podcast.to_bytes()), podcast);
+    }
+
+    #[test]
+    fn keys_match_old_prefixes() {
+        let modes = Modes::default();
+        for mode in modes.iter().take(4) {
+            assert_eq!(mode.name.key() + "_", mode.prefix);
+        }
+    }
+
+    #[test]
+    fn cycle_wraps_around() {
+        let modes = Modes::default();
+        let mut mode = modes.first().clone();
+        for _ in 0..modes.len() {
+            mode = modes.next(&mode).clone();
+        }
+        assert_eq!(&mode, modes.first());
+    }
+
+    #[test]
+    fn overlapping_prefixes_are_rejected() {
+        let mut modes = Modes::default();
+        assert!(modes.validate().is_ok());
+        modes.0[1].prefix = "music_sing_".to_owned();
+        assert!(modes.validate().is_err());
+    }
+}
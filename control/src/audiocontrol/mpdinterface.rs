@@ -1,16 +1,46 @@
 use std::fmt;
-use std::thread;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
 
 use mpdrs::error::{Error, Result};
 use mpdrs::song::Range;
+use mpdrs::status::State;
 use mpdrs::{Playlist, Song, Status};
 use tracing::{debug, instrument};
 
+use super::backend::{self, MusicBackend, PlayState};
+use super::RESCAN_TIMEOUT;
+
 pub(super) struct MpdInterface {
     ip: String,
+    /// for every connect, read and write
+    timeout: Duration,
+    /// the socket of `client`, to change its timeouts
+    stream: TcpStream,
     client: mpdrs::Client,
 }
 
+/// Connects to mpd giving up on every step after `timeout`
+fn open(ip: &str, timeout: Duration) -> Result<(mpdrs::Client, TcpStream)> {
+    let mut last_err = None;
+    for addr in ip.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))?;
+                let client = mpdrs::Client::new(stream.try_clone()?)?;
+                return Ok((client, stream));
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    let err = last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to")
+    });
+    Err(err.into())
+}
+
 impl fmt::Debug for MpdInterface {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("MpdClient").field("ip", &self.ip).finish()
@@ -27,7 +57,7 @@ macro_rules! ok_or_reconnect_no_args {
             };
 
             debug!("IOError or ParseError, reconnecting...");
-            self.client = mpdrs::Client::connect(&self.ip)?;
+            self.reopen()?;
             self.client.$name()
         }
     };
@@ -45,7 +75,7 @@ macro_rules! ok_or_reconnect_one_arg {
             };
 
             debug!("IOError or ParseError, reconnecting...");
-            self.client = mpdrs::Client::connect(&self.ip)?;
+            self.reopen()?;
             self.client.$name($arg)
         }
     };
@@ -53,29 +83,38 @@ macro_rules! ok_or_reconnect_one_arg {
 
 impl MpdInterface {
     #[instrument(ret, err)]
-    pub(crate) fn connect(ip: &str) -> Result<Self> {
-        let client = mpdrs::Client::connect(ip)?;
+    pub(crate) fn connect(ip: &str, timeout: Duration) -> Result<Self> {
+        let (client, stream) = open(ip, timeout)?;
         Ok(MpdInterface {
             ip: ip.to_owned(),
+            timeout,
+            stream,
             client,
         })
     }
 
+    fn reopen(&mut self) -> Result<()> {
+        (self.client, self.stream) = open(&self.ip, self.timeout)?;
+        Ok(())
+    }
+
+    /// Returns once mpd is done, mpd remembers the update finished even
+    /// if that happens before we start waiting for it
     #[instrument(ret, err)]
     pub(crate) fn rescan(&mut self) -> Result<()> {
         use mpdrs::Idle;
 
-        let mut watcher = mpdrs::Client::connect(&self.ip)?;
-        let thread_join_handle = thread::spawn(move || {
-            watcher.wait(&[mpdrs::idle::Subsystem::Update])
-        });
         self.client.rescan()?;
-        thread_join_handle.join().unwrap()?;
+        self.stream.set_read_timeout(Some(RESCAN_TIMEOUT))?;
+        let done = self.client.wait(&[mpdrs::idle::Subsystem::Update]);
+        self.stream.set_read_timeout(Some(self.timeout))?;
+        done?;
         Ok(())
     }
 
     ok_or_reconnect_no_args! {toggle_pause, ()}
     ok_or_reconnect_no_args! {play, ()}
+    ok_or_reconnect_no_args! {stop, ()}
     ok_or_reconnect_no_args! {prev, ()}
     ok_or_reconnect_no_args! {next, ()}
     ok_or_reconnect_no_args! {status, Status}
@@ -92,6 +131,7 @@ impl MpdInterface {
     ok_or_reconnect_one_arg! {random, value, bool, ()}
     ok_or_reconnect_one_arg! {single, value, bool, ()}
     ok_or_reconnect_one_arg! {consume, value, bool, ()}
+    ok_or_reconnect_one_arg! {volume, value, i8, ()}
     ok_or_reconnect_one_arg! {playlist, name, &str, Vec<Song>}
     ok_or_reconnect_one_arg! {push, path, &str, u32}
 
@@ -103,7 +143,7 @@ impl MpdInterface {
         };
 
         debug!("IOError or ParseError, reconnecting...");
-        self.client = mpdrs::Client::connect(&self.ip)?;
+        self.reopen()?;
         self.client.pause(true)
     }
 
@@ -118,19 +158,19 @@ impl MpdInterface {
         };
 
         debug!("IOError or ParseError, reconnecting...");
-        self.client = mpdrs::Client::connect(&self.ip)?;
+        self.reopen()?;
         self.client.load(name, range)
     }
 
-    pub(crate) fn pl_push(&mut self, pl_name: &str, song: &Song) -> Result<()> {
-        match self.client.pl_push(pl_name, &song.file) {
+    pub(crate) fn pl_push(&mut self, pl_name: &str, file: &str) -> Result<()> {
+        match self.client.pl_push(pl_name, file) {
             Err(Error::Io(_) | Error::Parse(_)) => (),
             other => return other,
         };
 
         debug!("IOError or ParseError, reconnecting...");
-        self.client = mpdrs::Client::connect(&self.ip)?;
-        self.client.pl_push(pl_name, &song.file)
+        self.reopen()?;
+        self.client.pl_push(pl_name, file)
     }
 
     pub(crate) fn seek(&mut self, place: u32, pos: u32) -> Result<()> {
@@ -140,7 +180,7 @@ impl MpdInterface {
         };
 
         debug!("IOError or ParseError, reconnecting...");
-        self.client = mpdrs::Client::connect(&self.ip)?;
+        self.reopen()?;
         self.client.seek(place, pos)
     }
 
@@ -151,11 +191,160 @@ impl MpdInterface {
         };
 
         debug!("IOError or ParseError, reconnecting...");
-        self.client = mpdrs::Client::connect(&self.ip)?;
+        self.reopen()?;
         self.client.prioid(id, prio)
     }
+}
+
+impl From<Error> for backend::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Server(err) => match err.detail.as_str() {
+                "Not playing" => Self::NotPlaying,
+                "Bad song index" => Self::BadSongIndex,
+                "No such playlist" => Self::NoSuchPlaylist,
+                "Playlist already exists" => Self::PlaylistExists,
+                _ => Self::Refused(err.detail),
+            },
+            Error::Io(_) | Error::Parse(_) => Self::Connection(err.to_string()),
+            other => Self::Refused(other.to_string()),
+        }
+    }
+}
+
+fn song(song: Song) -> backend::Song {
+    backend::Song {
+        file: song.file,
+        title: song.title,
+        pos: song.place.map(|place| place.pos),
+    }
+}
+
+/// The inherent methods of the same name do the work, these translate
+impl MusicBackend for MpdInterface {
+    fn reconnect(&mut self) -> backend::Result<()> {
+        Ok(self.reopen()?)
+    }
+
+    fn rescan(&mut self) -> backend::Result<()> {
+        Ok(MpdInterface::rescan(self)?)
+    }
+
+    fn status(&mut self) -> backend::Result<backend::Status> {
+        let status = MpdInterface::status(self)?;
+        Ok(backend::Status {
+            state: match status.state {
+                State::Stop => PlayState::Stop,
+                State::Play => PlayState::Play,
+                State::Pause => PlayState::Pause,
+            },
+            volume: status.volume,
+            song: status.song.map(|place| place.pos),
+            elapsed: status.elapsed,
+            duration: status.duration,
+        })
+    }
+
+    fn current_song(&mut self) -> backend::Result<Option<backend::Song>> {
+        Ok(self.currentsong()?.map(song))
+    }
+
+    fn queue(&mut self) -> backend::Result<Vec<backend::Song>> {
+        let queue = MpdInterface::queue(self)?;
+        Ok(queue.into_iter().map(song).collect())
+    }
+
+    fn playlists(&mut self) -> backend::Result<Vec<String>> {
+        let playlists = MpdInterface::playlists(self)?;
+        Ok(playlists.into_iter().map(|playlist| playlist.name).collect())
+    }
+
+    fn playlist(&mut self, name: &str) -> backend::Result<Vec<backend::Song>> {
+        let songs = MpdInterface::playlist(self, name)?;
+        Ok(songs.into_iter().map(song).collect())
+    }
+
+    fn play(&mut self) -> backend::Result<()> {
+        Ok(MpdInterface::play(self)?)
+    }
+
+    fn toggle_pause(&mut self) -> backend::Result<()> {
+        Ok(MpdInterface::toggle_pause(self)?)
+    }
+
+    fn pause(&mut self) -> backend::Result<()> {
+        Ok(MpdInterface::pause(self)?)
+    }
+
+    fn stop(&mut self) -> backend::Result<()> {
+        Ok(MpdInterface::stop(self)?)
+    }
+
+    fn prev(&mut self) -> backend::Result<()> {
+        Ok(MpdInterface::prev(self)?)
+    }
+
+    fn next(&mut self) -> backend::Result<()> {
+        Ok(MpdInterface::next(self)?)
+    }
+
+    fn seek(&mut self, pos: u32, secs: u32) -> backend::Result<()> {
+        Ok(MpdInterface::seek(self, pos, secs)?)
+    }
+
+    fn seek_current(&mut self, secs: u32) -> backend::Result<()> {
+        Ok(self.rewind(secs)?)
+    }
+
+    fn repeat(&mut self, on: bool) -> backend::Result<()> {
+        Ok(MpdInterface::repeat(self, on)?)
+    }
+
+    fn random(&mut self, on: bool) -> backend::Result<()> {
+        Ok(MpdInterface::random(self, on)?)
+    }
+
+    fn single(&mut self, on: bool) -> backend::Result<()> {
+        Ok(MpdInterface::single(self, on)?)
+    }
+
+    fn consume(&mut self, on: bool) -> backend::Result<()> {
+        Ok(MpdInterface::consume(self, on)?)
+    }
+
+    fn volume(&mut self, percent: i8) -> backend::Result<()> {
+        Ok(MpdInterface::volume(self, percent)?)
+    }
+
+    fn clear(&mut self) -> backend::Result<()> {
+        Ok(MpdInterface::clear(self)?)
+    }
+
+    fn load(&mut self, name: &str) -> backend::Result<()> {
+        Ok(MpdInterface::load(self, name, ..)?)
+    }
+
+    fn push(&mut self, file: &str) -> backend::Result<u32> {
+        Ok(MpdInterface::push(self, file)?)
+    }
+
+    fn prioid(&mut self, id: u32, prio: u8) -> backend::Result<()> {
+        Ok(MpdInterface::prioid(self, id, prio)?)
+    }
+
+    fn save(&mut self, name: &str) -> backend::Result<()> {
+        Ok(MpdInterface::save(self, name)?)
+    }
+
+    fn pl_remove(&mut self, name: &str) -> backend::Result<()> {
+        Ok(MpdInterface::pl_remove(self, name)?)
+    }
+
+    fn pl_clear(&mut self, name: &str) -> backend::Result<()> {
+        Ok(MpdInterface::pl_clear(self, name)?)
+    }
 
-    pub(crate) fn playlist_exists(&mut self, playlist_name: &str) -> bool {
-        self.playlist(playlist_name).is_ok()
+    fn pl_push(&mut self, name: &str, file: &str) -> backend::Result<()> {
+        Ok(MpdInterface::pl_push(self, name, file)?)
     }
 }
@@ -0,0 +1,97 @@
+//! Where the controller keeps what it needs to remember between runs. There
+//! are two layouts: the hand-rolled one in [`db`](super::db) and the
+//! dbstruct one in [`db2`](super::db2), which is used now. The old one is
+//! moved over once, see [`migrate`](super::migrate).
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use super::{db2, migrate, AudioMode, Modes};
+use crate::alarm::Schedule;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Position {
+    pub(crate) pos_in_pl: u32,
+    pub(crate) elapsed: u32,
+}
+
+/// Everything in a store
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub(crate) current_mode: Option<AudioMode>,
+    /// current playlist by mode
+    pub(crate) playlists: BTreeMap<AudioMode, String>,
+    pub(crate) positions: BTreeMap<String, Position>,
+    /// unix timestamp by playlist
+    pub(crate) last_played: BTreeMap<String, u64>,
+    /// missing in exports made before there were alarms
+    #[serde(default)]
+    pub(crate) schedule: Schedule,
+}
+
+pub(crate) trait Store: Send {
+    fn fetch_playlist_name(&self, mode: &AudioMode) -> Option<String>;
+    fn store_playlist_name(&self, mode: &AudioMode, playlist_name: &str);
+    fn fetch_position(&self, playlist_name: &str) -> Option<Position>;
+    fn store_position(&self, playlist_name: &str, position: &Position);
+    fn fetch_last_played(&self, playlist: &str) -> Option<u64>;
+    fn store_last_played(&self, playlist: &str, last_played: u64);
+    fn fetch_mode(&self) -> Option<AudioMode>;
+    fn store_mode(&self, mode: &AudioMode);
+    fn fetch_schedule(&self) -> Schedule;
+    fn store_schedule(&self, schedule: &Schedule);
+
+    /// Everything stored, `modes` are the ones to look up playlists for
+    fn snapshot(&self, modes: &Modes) -> Snapshot;
+
+    /// Store everything in `snapshot`, keeping what it does not mention
+    fn restore(&self, snapshot: &Snapshot) {
+        if let Some(mode) = &snapshot.current_mode {
+            self.store_mode(mode);
+        }
+        for (mode, playlist) in &snapshot.playlists {
+            self.store_playlist_name(mode, playlist);
+        }
+        for (playlist, position) in &snapshot.positions {
+            self.store_position(playlist, position);
+        }
+        for (playlist, &last_played) in &snapshot.last_played {
+            self.store_last_played(playlist, last_played);
+        }
+        if snapshot.schedule != Schedule::default() {
+            self.store_schedule(&snapshot.schedule);
+        }
+    }
+}
+
+pub(crate) fn now_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Where the dbstruct layout lives, next to the old database at `path`
+pub(crate) fn layout_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push("_v2");
+    PathBuf::from(name)
+}
+
+/// Opens the store for the database configured at `path`, moving the old
+/// layout over first if that did not happen yet
+pub(crate) fn open(
+    path: &Path,
+    modes: &Modes,
+) -> Result<Box<dyn Store>, String> {
+    let report = migrate::migrate(path, modes, false)?;
+    if !report.up_to_date {
+        info!("{report}");
+    }
+    Ok(Box::new(db2::Db::open(&layout_path(path))?))
+}
@@ -0,0 +1,388 @@
+//! The controller end to end against [`FakeMpd`], and its logic against the
+//! [`Memory`] backend
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use super::fake_mpd::FakeMpd;
+use super::*;
+
+/// A database for a single test, removed afterwards
+struct TempDb(PathBuf);
+
+impl TempDb {
+    fn new() -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let n = COUNT.fetch_add(1, Ordering::Relaxed);
+        let name = format!("button-panel-test-{}-{n}", std::process::id());
+        Self(std::env::temp_dir().join(name))
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+        let _ = std::fs::remove_dir_all(store::layout_path(&self.0));
+    }
+}
+
+fn controller(mpd: &FakeMpd, db: &TempDb) -> AudioController {
+    let mut config = Config::default();
+    config.mpd.port = mpd.port();
+    config.database.path.clone_from(&db.0);
+    AudioController::new("127.0.0.1", &config, Events::default()).unwrap()
+}
+
+fn memory_controller(memory: &Memory, db: &TempDb) -> AudioController {
+    let mut config = Config::default();
+    config.database.path.clone_from(&db.0);
+    let events = Events::default();
+    AudioController::with_backend(memory.clone(), &config, events).unwrap()
+}
+
+fn queue(state: &Player) -> Vec<&str> {
+    state.queue.iter().map(|song| song.file.as_str()).collect()
+}
+
+#[test]
+fn next_mode_skips_modes_without_playlists() {
+    let mpd = FakeMpd::start();
+    mpd.player().add_playlist("music_a", &["m1.ogg", "m2.ogg"]);
+    mpd.player()
+        .add_playlist("podcast_news", &["p1.ogg", "p2.ogg"]);
+    let db = TempDb::new();
+    let mut audio = controller(&mpd, &db);
+    let mut events = audio.events.subscribe();
+    assert_eq!(audio.mode, AudioMode::from("Music"));
+
+    audio.next_mode().unwrap();
+    assert_eq!(audio.mode, AudioMode::from("Podcast"));
+    assert_eq!(audio.fetch_current_mode(), Some(AudioMode::from("Podcast")));
+    {
+        let state = mpd.player();
+        assert_eq!(queue(&state), ["p1.ogg", "p2.ogg"]);
+        assert_eq!(state.current, Some(0));
+        assert_eq!(state.state, PlayState::Pause);
+        assert!(state.consume);
+        assert!(!state.repeat);
+    }
+    let entered = Event::Mode {
+        mode: "Podcast".to_owned(),
+        playlist: "podcast_news".to_owned(),
+    };
+    assert_eq!(events.try_recv().unwrap(), entered);
+
+    // podcast saves its playlist when leaving it, then we wrap around
+    mpd.player().queue.remove(0);
+    audio.next_mode().unwrap();
+    assert_eq!(audio.mode, AudioMode::from("Music"));
+    let state = mpd.player();
+    assert_eq!(queue(&state), ["m1.ogg", "m2.ogg"]);
+    assert_eq!(state.playlists["podcast_news"], ["p2.ogg"]);
+    assert!(!state.consume);
+}
+
+#[test]
+fn next_mode_without_any_playlist_fails() {
+    let mpd = FakeMpd::start();
+    let db = TempDb::new();
+    let mut audio = controller(&mpd, &db);
+    assert!(audio.next_mode().is_err());
+    assert_eq!(audio.mode, AudioMode::from("Music"));
+}
+
+#[test]
+fn switch_playlist_remembers_positions() {
+    let mpd = FakeMpd::start();
+    mpd.player().add_playlist("music_a", &["a1.ogg"]);
+    mpd.player()
+        .add_playlist("music_b", &["b1.ogg", "b2.ogg", "b3.ogg"]);
+    mpd.player().add_playlist("music_c_shuf", &["c1.ogg"]);
+    let db = TempDb::new();
+    let mut audio = controller(&mpd, &db);
+
+    audio.next_playlist().unwrap();
+    assert_eq!(queue(&mpd.player()), ["b1.ogg", "b2.ogg", "b3.ogg"]);
+    {
+        let mut state = mpd.player();
+        state.current = Some(1);
+        state.elapsed = Duration::from_secs(42);
+        state.state = PlayState::Play;
+    }
+
+    audio.next_playlist().unwrap();
+    assert_eq!(mpd.player().current_file(), Some("c1.ogg"));
+    assert!(mpd.player().random, "_shuf playlists are shuffled");
+
+    audio.prev_playlist().unwrap();
+    {
+        let state = mpd.player();
+        assert_eq!(state.current_file(), Some("b2.ogg"));
+        assert_eq!(state.elapsed, Duration::from_secs(42));
+        assert_eq!(state.state, PlayState::Pause);
+        assert!(!state.random);
+    }
+    let stored = audio.db.fetch_playlist_name(&AudioMode::from("Music"));
+    assert_eq!(stored.as_deref(), Some("music_b"));
+
+    // wraps around
+    audio.next_playlist().unwrap();
+    audio.next_playlist().unwrap();
+    assert_eq!(mpd.player().current_file(), Some("a1.ogg"));
+}
+
+#[test]
+fn rewind_after_pause_depends_on_pause_length() {
+    let mpd = FakeMpd::start();
+    mpd.player().add_playlist("podcast_news", &["p1.ogg"]);
+    let db = TempDb::new();
+    let mut audio = controller(&mpd, &db);
+    audio.next_mode().unwrap();
+    assert_eq!(audio.current_mode().rewind, Rewind::Auto);
+
+    let resume_after = |audio: &mut AudioController, pause_secs| {
+        audio.pause().unwrap();
+        let paused_at = now_timestamp() - pause_secs;
+        audio.db.store_last_played("podcast_news", paused_at);
+        mpd.player().elapsed = Duration::from_secs(100);
+        audio.play(ForceRewind::No).unwrap();
+        assert_eq!(mpd.player().state, PlayState::Play);
+        mpd.player().elapsed
+    };
+
+    // half the square root of the pause, too short to bother below 2s
+    assert_eq!(resume_after(&mut audio, 400), Duration::from_secs(90));
+    assert_eq!(resume_after(&mut audio, 3), Duration::from_secs(100));
+    assert_eq!(resume_after(&mut audio, 86400), Duration::from_secs(70));
+}
+
+#[test]
+fn create_wakeup_playlist_mixes_slow_and_normal_songs() {
+    let mpd = FakeMpd::start();
+    let normal: Vec<_> = (0..40).map(|i| format!("normal{i}.ogg")).collect();
+    let normal_refs: Vec<_> = normal.iter().map(String::as_str).collect();
+    mpd.player()
+        .add_playlist("slow", &["s1.ogg", "s2.ogg", "s3.ogg"]);
+    mpd.player().add_playlist("music_all_shuf", &normal_refs);
+    mpd.player()
+        .add_playlist("music_wakeup", &["yesterday.ogg"]);
+    let db = TempDb::new();
+    let mut audio = controller(&mpd, &db);
+
+    audio.create_wakeup_playlist("music_wakeup").unwrap();
+
+    let state = mpd.player();
+    let wakeup = &state.playlists["music_wakeup"];
+    assert_eq!(wakeup.len(), 31);
+    assert!(wakeup[0].starts_with('s'), "starts slow: {wakeup:?}");
+    assert!(wakeup[1..].iter().all(|song| normal.contains(song)));
+    let mut unique = wakeup.clone();
+    unique.sort();
+    unique.dedup();
+    assert_eq!(unique.len(), 31);
+}
+
+#[test]
+fn skip_stops_short_of_the_end() {
+    let memory = Memory::new();
+    memory
+        .player()
+        .add_playlist("music_a", &["a1.ogg", "a2.ogg"]);
+    let db = TempDb::new();
+    let mut audio = memory_controller(&memory, &db);
+    audio.go_to_playlist("music_a").unwrap();
+
+    memory.player().elapsed = Duration::from_secs(170);
+    audio.skip().unwrap();
+    let player = memory.player();
+    assert_eq!(player.current_file(), Some("a1.ogg"));
+    assert_eq!(player.elapsed, memory::SONG_LENGTH - Duration::from_secs(1));
+    assert_eq!(player.state, PlayState::Play);
+}
+
+#[test]
+fn volume_stays_within_bounds() {
+    let memory = Memory::new();
+    let db = TempDb::new();
+    let mut audio = memory_controller(&memory, &db);
+
+    memory.player().volume = 95;
+    audio.change_volume(10).unwrap();
+    assert_eq!(memory.player().volume, 100);
+    audio.change_volume(-120).unwrap();
+    assert_eq!(memory.player().volume, 0);
+    audio.set_volume(120).unwrap();
+    assert_eq!(memory.player().volume, 100);
+    assert_eq!(audio.volume().unwrap(), Some(100));
+
+    // no mixer
+    memory.player().volume = -1;
+    audio.change_volume(10).unwrap();
+    assert_eq!(memory.player().volume, -1);
+    assert_eq!(audio.volume().unwrap(), None);
+}
+
+#[test]
+fn inserted_songs_play_next() {
+    let memory = Memory::new();
+    memory
+        .player()
+        .add_playlist("music_a", &["a1.ogg", "a2.ogg"]);
+    let db = TempDb::new();
+    let mut audio = memory_controller(&memory, &db);
+    audio.go_to_playlist("music_a").unwrap();
+
+    audio.insert_next("new.ogg").unwrap();
+    let player = memory.player();
+    assert_eq!(queue(&player), ["a1.ogg", "a2.ogg", "new.ogg"]);
+    assert_eq!(player.queue[2].prio, 128);
+}
+
+#[test]
+fn missing_playlists_are_errors() {
+    let memory = Memory::new();
+    let db = TempDb::new();
+    let mut audio = memory_controller(&memory, &db);
+
+    let err = audio.next_playlist().unwrap_err();
+    assert!(matches!(err, AudioError::NoPlaylists(_)), "{err}");
+    let err = audio.create_wakeup_playlist("music_wakeup").unwrap_err();
+    assert_eq!(err.to_string(), "Player: No such playlist");
+    assert!(!err.is_unavailable());
+}
+
+#[test]
+fn switching_from_a_deleted_playlist_starts_over() {
+    let memory = Memory::new();
+    for playlist in ["music_a", "music_b", "music_c"] {
+        memory.player().add_playlist(playlist, &["song.ogg"]);
+    }
+    let db = TempDb::new();
+    let mut audio = memory_controller(&memory, &db);
+    audio.go_to_playlist("music_b").unwrap();
+
+    memory.player().playlists.remove("music_b");
+    audio.next_playlist().unwrap();
+    let stored = audio.db.fetch_playlist_name(&AudioMode::from("Music"));
+    assert_eq!(stored.as_deref(), Some("music_a"));
+}
+
+#[tokio::test]
+async fn handle_answers_and_survives_panics() {
+    let memory = Memory::new();
+    let db = TempDb::new();
+    let audio = memory_controller(&memory, &db);
+    let audio = AudioHandle::spawn(audio, Duration::from_secs(1));
+
+    let mode = audio.call(|audio| audio.mode.clone()).await;
+    assert_eq!(mode.unwrap(), AudioMode::from("Music"));
+    let res = audio.call(|_| panic!("odd player")).await;
+    assert_eq!(res, Err::<(), _>("Audio job panicked".to_owned()));
+    audio.try_call(|audio| audio.change_volume(5)).await.unwrap();
+    assert_eq!(memory.player().volume, 55);
+}
+
+#[tokio::test]
+async fn handle_gives_up_on_a_stuck_player() {
+    let memory = Memory::new();
+    let db = TempDb::new();
+    let audio = memory_controller(&memory, &db);
+    let audio = AudioHandle::spawn(audio, Duration::from_millis(50));
+
+    let stuck = |_: &mut AudioController| {
+        std::thread::sleep(Duration::from_millis(300));
+    };
+    let start = std::time::Instant::now();
+    assert!(audio.call(stuck).await.is_err());
+    assert!(start.elapsed() < Duration::from_millis(250));
+    // once unstuck it goes on with the next job
+    let wait = Duration::from_secs(1);
+    assert!(audio.call_within(wait, |_| ()).await.is_ok());
+}
+
+#[test]
+fn old_database_is_migrated_once() {
+    let db = TempDb::new();
+    let modes = Modes::default();
+    let old = super::db::Db::open_unmigrated(&db.0).unwrap();
+    let music = AudioMode::from("Music");
+    let position = store::Position {
+        pos_in_pl: 3,
+        elapsed: 75,
+    };
+    old.store_mode(&AudioMode::from("Podcast"));
+    old.store_playlist_name(&music, "music_b");
+    old.store_position("podcast_news", &position);
+    old.store_last_played("podcast_news", 1_700_000_000);
+    drop(old);
+
+    let report = migrate(&db.0, &modes, true).unwrap();
+    assert!(!store::layout_path(&db.0).exists(), "dry run writes nothing");
+    let json = export(&db.0, &modes).unwrap();
+    assert!(json.contains("\"music_b\""), "{json}");
+    assert!(!store::layout_path(&db.0).exists(), "export writes nothing");
+    let report = report.to_string();
+    assert!(report.contains("current mode: Podcast"), "{report}");
+    assert!(report.contains("Music: music_b"), "{report}");
+    assert!(report.contains("position in 1 playlists"), "{report}");
+
+    let new = store::open(&db.0, &modes).unwrap();
+    assert_eq!(new.fetch_mode(), Some(AudioMode::from("Podcast")));
+    assert_eq!(new.fetch_playlist_name(&music).as_deref(), Some("music_b"));
+    assert_eq!(new.fetch_position("podcast_news"), Some(position));
+    assert_eq!(new.fetch_last_played("podcast_news"), Some(1_700_000_000));
+    assert_eq!(new.snapshot(&modes).positions.len(), 1);
+    drop(new);
+
+    // the old database is kept, changes to it are not moved again
+    let old = super::db::Db::open_unmigrated(&db.0).unwrap();
+    old.store_mode(&music);
+    drop(old);
+    assert!(migrate(&db.0, &modes, false).unwrap().up_to_date);
+    let new = store::open(&db.0, &modes).unwrap();
+    assert_eq!(new.fetch_mode(), Some(AudioMode::from("Podcast")));
+}
+
+#[test]
+fn migrating_leaves_the_backup_as_is() {
+    let db = TempDb::new();
+    let modes = Modes::default();
+    // before modes had names they were stored as a single byte
+    let old = sled::open(&db.0).unwrap();
+    old.insert("current_mode", &[3]).unwrap();
+    drop(old);
+
+    migrate(&db.0, &modes, false).unwrap();
+    let new = store::open(&db.0, &modes).unwrap();
+    assert_eq!(new.fetch_mode(), Some(AudioMode::from("Podcast")));
+    drop(new);
+    let old = sled::open(&db.0).unwrap();
+    let stored = old.get("current_mode").unwrap();
+    assert_eq!(stored.as_deref(), Some(&[3][..]));
+}
+
+#[test]
+fn state_survives_export_and_import() {
+    let (from, to) = (TempDb::new(), TempDb::new());
+    let modes = Modes::default();
+    let db = store::open(&from.0, &modes).unwrap();
+    db.store_mode(&AudioMode::from("Podcast"));
+    db.store_playlist_name(&AudioMode::from("Podcast"), "podcast_news");
+    let position = store::Position {
+        pos_in_pl: 2,
+        elapsed: 1234,
+    };
+    db.store_position("podcast_news", &position);
+    db.store_last_played("podcast_news", 1_700_000_000);
+    drop(db);
+
+    let json = export(&from.0, &modes).unwrap();
+    assert!(json.contains("\"podcast_news\""), "{json}");
+    import(&to.0, &modes, &json).unwrap();
+    assert_eq!(export(&to.0, &modes).unwrap(), json);
+
+    let unknown = json.replace("\"Podcast\"", "\"Radio\"");
+    let err = import(&to.0, &modes, &unknown).unwrap_err();
+    assert_eq!(err, "Unknown modes: Radio");
+}
@@ -0,0 +1,528 @@
+//! Runtime configuration, loaded from a toml file (see `config.toml` for an
+//! example with all the defaults). Every value can be overridden with an
+//! environment variable named `BUTTON_PANEL_<TABLE>_<KEY>`, for example
+//! `BUTTON_PANEL_MPD_PORT=6601`.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::NaiveTime;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::{Result, Section};
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+
+use crate::audiocontrol::{AudioMode, Modes};
+use crate::panel::keyboard::Keys;
+use crate::panel::PanelId;
+
+const ENV_PREFIX: &str = "BUTTON_PANEL";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// toml file with button bindings, uses the shipped keymap if not set
+    pub keymap: Option<PathBuf>,
+    pub mpd: Mpd,
+    pub data_server: DataServer,
+    pub api: Api,
+    pub database: Database,
+    pub alarm: Alarm,
+    pub meditation: Meditation,
+    /// in the order the panel cycles through them
+    pub modes: Modes,
+    /// panels next to the one passed on the command line
+    pub panels: Vec<Panel>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mpd {
+    pub port: u16,
+    /// how long to wait for mpd to answer
+    pub timeout_secs: u64,
+}
+
+impl Default for Mpd {
+    fn default() -> Self {
+        Self {
+            port: 6600,
+            timeout_secs: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DataServer {
+    pub address: SocketAddr,
+}
+
+impl Default for DataServer {
+    fn default() -> Self {
+        Self {
+            address: ([192, 168, 1, 43], 1234).into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Api {
+    /// address to listen on for messages, such as the alarm
+    pub bind: SocketAddr,
+}
+
+impl Default for Api {
+    fn default() -> Self {
+        Self {
+            bind: ([127, 0, 0, 1], 3141).into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Database {
+    pub path: PathBuf,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self {
+            path: PathBuf::from("database"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Alarm {
+    /// played if the wake-up playlist can not be, a path in the mpd library
+    pub sound: PathBuf,
+    /// how long snooze pauses a ringing alarm
+    pub delay_mins: u64,
+    /// missed alarms at most this late, for example after a reboot, still
+    /// go off
+    pub catch_up_mins: u64,
+    /// mode the wake-up playlist is played in
+    pub mode: AudioMode,
+    pub ramp: Ramp,
+}
+
+impl Default for Alarm {
+    fn default() -> Self {
+        Self {
+            sound: PathBuf::from("alarm-with-warning.ogg"),
+            delay_mins: 7,
+            catch_up_mins: 30,
+            mode: AudioMode::from("Music"),
+            ramp: Ramp::default(),
+        }
+    }
+}
+
+/// The volume of the wake-up playlist rises from `start_volume` to
+/// `target_volume` over `duration_mins`, starting with its first song
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Ramp {
+    /// percent
+    pub start_volume: i8,
+    /// percent
+    pub target_volume: i8,
+    /// 0 plays at the target volume right away
+    pub duration_mins: u64,
+    pub curve: Curve,
+}
+
+impl Default for Ramp {
+    fn default() -> Self {
+        Self {
+            start_volume: 10,
+            target_volume: 50,
+            duration_mins: 15,
+            curve: Curve::Quadratic,
+        }
+    }
+}
+
+/// How the volume rises during a [`Ramp`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Curve {
+    /// by the same amount every minute
+    Linear,
+    /// slowly at first, then faster, like a sunrise
+    Quadratic,
+}
+
+/// Same names as in the config file
+impl FromStr for Curve {
+    type Err = serde::de::value::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::deserialize(s.into_deserializer())
+    }
+}
+
+impl Ramp {
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.duration_mins * 60)
+    }
+
+    /// The volume `elapsed` into the ramp
+    pub fn volume_at(&self, elapsed: Duration) -> i8 {
+        let duration = self.duration();
+        let done = if elapsed >= duration {
+            1.0
+        } else {
+            elapsed.as_secs_f64() / duration.as_secs_f64()
+        };
+        let done = match self.curve {
+            Curve::Linear => done,
+            Curve::Quadratic => done * done,
+        };
+        let start = f64::from(self.start_volume);
+        let rise = f64::from(self.target_volume) - start;
+        // both volumes are in 0..=100 so this is too
+        (start + rise * done).round() as i8
+    }
+}
+
+/// Meditation mode is only enabled between `start` and `end`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Meditation {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl Default for Meditation {
+    fn default() -> Self {
+        Self {
+            start: NaiveTime::from_hms_opt(21, 30, 0).expect("valid time"),
+            end: NaiveTime::from_hms_opt(9, 0, 0).expect("valid time"),
+        }
+    }
+}
+
+impl Meditation {
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start < time && time < self.end
+        } else {
+            self.start < time || time < self.end
+        }
+    }
+}
+
+/// A panel is reached over exactly one of `tty`, `tcp`, `udp` or
+/// `keyboard`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Panel {
+    pub id: PanelId,
+    /// serial device of the panel
+    pub tty: Option<PathBuf>,
+    /// address to listen on for the panel, see [`crate::panel::network`]
+    pub tcp: Option<SocketAddr>,
+    pub udp: Option<SocketAddr>,
+    /// input device of a keyboard, see [`crate::panel::keyboard`]
+    pub keyboard: Option<PathBuf>,
+    /// which key is which button, defaults to the numpad
+    pub keys: Option<Keys>,
+}
+
+pub enum Connection<'a> {
+    Tty(&'a Path),
+    Tcp(SocketAddr),
+    Udp(SocketAddr),
+    Keyboard(&'a Path, Keys),
+}
+
+impl Panel {
+    pub fn connection(&self) -> Result<Connection<'_>> {
+        if self.keys.is_some() && self.keyboard.is_none() {
+            return Err(eyre!("Panel {} has keys but no keyboard", self.id));
+        }
+        match (&self.tty, self.tcp, self.udp, &self.keyboard) {
+            (Some(tty), None, None, None) => Ok(Connection::Tty(tty)),
+            (None, Some(bind), None, None) => Ok(Connection::Tcp(bind)),
+            (None, None, Some(bind), None) => Ok(Connection::Udp(bind)),
+            (None, None, None, Some(device)) => Ok(Connection::Keyboard(
+                device,
+                self.keys.clone().unwrap_or_default(),
+            )),
+            _ => Err(eyre!(
+                "Panel {} needs exactly one of tty, tcp, udp or keyboard",
+                self.id
+            )),
+        }
+    }
+}
+
+/// Replace `value` with the env variable `BUTTON_PANEL_<name>` if it is set
+fn override_from_env<T>(
+    env: &impl Fn(&str) -> Option<String>,
+    name: &str,
+    value: &mut T,
+) -> Result<()>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    let var = format!("{ENV_PREFIX}_{name}");
+    if let Some(new) = env(&var) {
+        *value = new
+            .parse()
+            .wrap_err_with(|| format!("Invalid value for {var}: `{new}`"))?;
+    }
+    Ok(())
+}
+
+impl Config {
+    /// Load the config at `path` or the defaults if there is none, then
+    /// apply overrides from the environment and check the result.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let mut config = match path {
+            Some(path) => Self::read(path)?,
+            None => Self::default(),
+        };
+        config.apply_overrides(|var| std::env::var(var).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).wrap_err_with(|| {
+            format!("Could not read config: {}", path.display())
+        })?;
+        Self::parse(&content)
+            .wrap_err_with(|| format!("Invalid config: {}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        toml::from_str(content).wrap_err("Could not parse config")
+    }
+
+    fn apply_overrides(
+        &mut self,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<()> {
+        if let Some(keymap) = env(&format!("{ENV_PREFIX}_KEYMAP")) {
+            self.keymap = Some(PathBuf::from(keymap));
+        }
+        let mpd = &mut self.mpd;
+        override_from_env(&env, "MPD_PORT", &mut mpd.port)?;
+        override_from_env(&env, "MPD_TIMEOUT_SECS", &mut mpd.timeout_secs)?;
+        override_from_env(
+            &env,
+            "DATA_SERVER_ADDRESS",
+            &mut self.data_server.address,
+        )?;
+        override_from_env(&env, "API_BIND", &mut self.api.bind)?;
+        override_from_env(&env, "DATABASE_PATH", &mut self.database.path)?;
+        let alarm = &mut self.alarm;
+        override_from_env(&env, "ALARM_SOUND", &mut alarm.sound)?;
+        override_from_env(&env, "ALARM_DELAY_MINS", &mut alarm.delay_mins)?;
+        override_from_env(
+            &env,
+            "ALARM_CATCH_UP_MINS",
+            &mut alarm.catch_up_mins,
+        )?;
+        let ramp = &mut alarm.ramp;
+        override_from_env(
+            &env,
+            "ALARM_RAMP_START_VOLUME",
+            &mut ramp.start_volume,
+        )?;
+        override_from_env(
+            &env,
+            "ALARM_RAMP_TARGET_VOLUME",
+            &mut ramp.target_volume,
+        )?;
+        override_from_env(
+            &env,
+            "ALARM_RAMP_DURATION_MINS",
+            &mut ramp.duration_mins,
+        )?;
+        override_from_env(&env, "ALARM_RAMP_CURVE", &mut ramp.curve)?;
+        let meditation = &mut self.meditation;
+        override_from_env(&env, "MEDITATION_START", &mut meditation.start)?;
+        override_from_env(&env, "MEDITATION_END", &mut meditation.end)?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.mpd.port == 0 {
+            return Err(eyre!("mpd.port can not be 0"));
+        }
+        if self.mpd.timeout_secs == 0 {
+            return Err(eyre!("mpd.timeout_secs can not be 0"));
+        }
+        self.modes.validate().map_err(|err| eyre!(err))?;
+        if !self.modes.contains(&self.alarm.mode) {
+            return Err(eyre!("alarm.mode {} is not a mode", self.alarm.mode));
+        }
+        if self.alarm.delay_mins == 0 {
+            return Err(eyre!("alarm.delay_mins can not be 0"));
+        }
+        let ramp = &self.alarm.ramp;
+        for (name, volume) in [
+            ("start_volume", ramp.start_volume),
+            ("target_volume", ramp.target_volume),
+        ] {
+            if !(0..=100).contains(&volume) {
+                return Err(eyre!("alarm.ramp.{name} is not a percentage"))
+                    .note(format!("It is {volume}, not between 0 and 100"));
+            }
+        }
+        if self.database.path.as_os_str().is_empty() {
+            return Err(eyre!("database.path can not be empty"));
+        }
+        if self.meditation.start == self.meditation.end {
+            return Err(eyre!("meditation.start and meditation.end are equal"))
+                .suggestion("Make the end differ from the start");
+        }
+        for (i, panel) in self.panels.iter().enumerate() {
+            if panel.id.name().is_empty() {
+                return Err(eyre!("Panel ids can not be empty"));
+            }
+            if panel.id.name().len() > button_protocol::MAX_ID_LEN {
+                return Err(eyre!("Panel id {} is too long", panel.id)).note(
+                    format!("At most {} bytes", button_protocol::MAX_ID_LEN),
+                );
+            }
+            panel.connection()?;
+            let taken = panel.id == PanelId::main()
+                || self.panels[..i].iter().any(|p| p.id == panel.id);
+            if taken {
+                return Err(eyre!("Panel id {} is used twice", panel.id))
+                    .note("The panel passed on the command line is `main`");
+            }
+        }
+        if let Some(keymap) = &self.keymap {
+            if !keymap.is_file() {
+                return Err(eyre!("keymap not found: {}", keymap.display()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_is_the_default() {
+        let example = Config::parse(include_str!("../config.toml")).unwrap();
+        let default = Config::default();
+        assert_eq!(format!("{example:?}"), format!("{default:?}"));
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let mut config = Config::parse("[mpd]\nport = 6601").unwrap();
+        config
+            .apply_overrides(|var| match var {
+                "BUTTON_PANEL_MPD_PORT" => Some("6602".to_owned()),
+                "BUTTON_PANEL_MEDITATION_END" => Some("07:15".to_owned()),
+                "BUTTON_PANEL_ALARM_RAMP_CURVE" => Some("linear".to_owned()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(config.mpd.port, 6602);
+        let end = NaiveTime::from_hms_opt(7, 15, 0).unwrap();
+        assert_eq!(config.meditation.end, end);
+        assert_eq!(config.alarm.ramp.curve, Curve::Linear);
+        assert_eq!(config.api.bind, Api::default().bind);
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        assert!(Config::parse("[mpd]\nport = 66000").is_err());
+        assert!(Config::parse("[api]\nbind = \"localhost\"").is_err());
+        assert!(Config::parse("[mpd]\nhost = \"pi\"").is_err());
+
+        let mut config = Config::default();
+        let res = config.apply_overrides(|var| {
+            (var == "BUTTON_PANEL_DATA_SERVER_ADDRESS").then(|| "pi".to_owned())
+        });
+        assert!(res.is_err());
+        let res = config.apply_overrides(|var| {
+            (var == "BUTTON_PANEL_ALARM_RAMP_CURVE").then(|| "steep".to_owned())
+        });
+        assert!(res.is_err());
+
+        config.meditation.end = config.meditation.start;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.alarm.mode = AudioMode::from("Jazz");
+        assert!(config.validate().is_err());
+        let config = Config::parse("[alarm.ramp]\ntarget_volume = 120")
+            .unwrap();
+        assert!(config.validate().is_err());
+        assert!(Config::parse("[alarm.ramp]\ncurve = \"steep\"").is_err());
+
+        let config = Config::parse(
+            "[[panels]]\nid = \"main\"\ntty = \"/dev/ttyUSB1\"",
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+        let config = Config::parse(
+            "[[panels]]\nid = \"door\"\ntty = \"/dev/ttyUSB1\"\n\
+             udp = \"0.0.0.0:3142\"",
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+        let config = Config::parse(
+            "[[panels]]\nid = \"remote\"\ntcp = \"0.0.0.0:3142\"\n\
+             keys = { top_left = \"KEY_PLAYPAUSE\" }",
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn meditation_time_wraps_around_midnight() {
+        let time = |h| NaiveTime::from_hms_opt(h, 0, 0).unwrap();
+        let night = Meditation::default();
+        assert!(night.contains(time(23)));
+        assert!(night.contains(time(3)));
+        assert!(!night.contains(time(12)));
+
+        let day = Meditation {
+            start: time(10),
+            end: time(14),
+        };
+        assert!(day.contains(time(12)));
+        assert!(!day.contains(time(23)));
+    }
+
+    #[test]
+    fn ramp_rises_to_the_target() {
+        let mins = |m: u64| Duration::from_secs(m * 60);
+        let mut ramp = Ramp {
+            start_volume: 10,
+            target_volume: 50,
+            duration_mins: 10,
+            curve: Curve::Linear,
+        };
+        assert_eq!(ramp.volume_at(Duration::ZERO), 10);
+        assert_eq!(ramp.volume_at(mins(5)), 30);
+        assert_eq!(ramp.volume_at(mins(10)), 50);
+        assert_eq!(ramp.volume_at(mins(60)), 50);
+
+        ramp.curve = Curve::Quadratic;
+        assert_eq!(ramp.volume_at(mins(5)), 20);
+        assert_eq!(ramp.volume_at(mins(10)), 50);
+
+        ramp.duration_mins = 0;
+        assert_eq!(ramp.volume_at(Duration::ZERO), 50);
+    }
+}
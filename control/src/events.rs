@@ -0,0 +1,279 @@
+//! Live events, streamed to subscribers of `GET /events` as server sent
+//! events. Every event is json with a `type` field, the sse event name is that
+//! same type. For example:
+//!
+//! ```text
+//! event: press
+//! data: {"type":"press","panel":"main","press":"short","button":"top_left"}
+//! ```
+
+use std::time::Duration;
+
+use button_protocol::{ButtonPress, Hold};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::debug;
+
+use crate::audiocontrol::{AudioController, AudioHandle};
+use crate::keymap::{button_name, Action};
+use crate::panel::PanelId;
+
+/// How many events a slow subscriber may fall behind before missing some
+const CAPACITY: usize = 64;
+const PLAYER_POLL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    Press {
+        panel: String,
+        /// short, long, double or triple
+        press: &'static str,
+        button: &'static str,
+    },
+    Hold {
+        panel: String,
+        /// start, repeat or release
+        phase: &'static str,
+        button: &'static str,
+        /// number of repeats since the start of the hold
+        #[serde(skip_serializing_if = "Option::is_none")]
+        repeat: Option<u16>,
+    },
+    Chord {
+        panel: String,
+        buttons: Vec<&'static str>,
+    },
+    /// An action was performed, `error` is set if it failed
+    Action {
+        /// a keymap action or one of the api: go_to_mode, go_to_playlist,
+        /// insert_next or alarm
+        action: &'static str,
+        /// what asked for it: panel or api
+        source: &'static str,
+        /// id of the panel that asked for it
+        #[serde(skip_serializing_if = "Option::is_none")]
+        panel: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    Mode {
+        mode: String,
+        playlist: String,
+    },
+    Playlist {
+        mode: String,
+        playlist: String,
+    },
+    /// The mpd play state or the current song changed
+    Player {
+        /// play, pause or stop
+        state: String,
+        song: Option<String>,
+    },
+    /// A request came in over the api, for the recorder only, not streamed
+    /// on `/events`
+    Request {
+        method: String,
+        path: String,
+        #[serde(skip_serializing_if = "String::is_empty")]
+        body: String,
+    },
+    /// The subscriber was too slow and missed some events
+    Lagged {
+        missed: u64,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Press { .. } => "press",
+            Event::Hold { .. } => "hold",
+            Event::Chord { .. } => "chord",
+            Event::Action { .. } => "action",
+            Event::Mode { .. } => "mode",
+            Event::Playlist { .. } => "playlist",
+            Event::Player { .. } => "player",
+            Event::Request { .. } => "request",
+            Event::Lagged { .. } => "lagged",
+        }
+    }
+
+    /// As a server sent event
+    pub fn to_sse(&self) -> String {
+        let data = serde_json::to_string(self).expect("serializable");
+        format!("event: {}\ndata: {data}\n\n", self.name())
+    }
+
+    /// An action asked for by `panel` or by the api if there is no panel
+    pub fn action(
+        action: Action,
+        panel: Option<&PanelId>,
+        res: &Result<(), String>,
+    ) -> Self {
+        Self::performed(action.name(), panel, res)
+    }
+
+    /// Like [`action`](Self::action) for things that are not in the keymap
+    pub fn performed(
+        action: &'static str,
+        panel: Option<&PanelId>,
+        res: &Result<(), String>,
+    ) -> Self {
+        Event::Action {
+            action,
+            source: if panel.is_some() { "panel" } else { "api" },
+            panel: panel.map(PanelId::to_string),
+            error: res.as_ref().err().cloned(),
+        }
+    }
+
+    pub fn from_panel(
+        panel: &PanelId,
+        event: button_protocol::Event,
+    ) -> Self {
+        use button_protocol::Event as Panel;
+
+        let panel = panel.to_string();
+        match event {
+            Panel::Press(press) => {
+                let kind = match press {
+                    ButtonPress::Short(_) => "short",
+                    ButtonPress::Long(_) => "long",
+                    ButtonPress::Double(_) => "double",
+                    ButtonPress::Triple(_) => "triple",
+                };
+                Event::Press {
+                    panel,
+                    press: kind,
+                    button: button_name(press.button()),
+                }
+            }
+            Panel::Hold(hold) => {
+                let (phase, repeat) = match hold {
+                    Hold::Start(_) => ("start", None),
+                    Hold::Repeat(_, repeat) => ("repeat", Some(repeat)),
+                    Hold::Release(_) => ("release", None),
+                };
+                Event::Hold {
+                    panel,
+                    phase,
+                    button: button_name(hold.button()),
+                    repeat,
+                }
+            }
+            Panel::Chord(chord) => Event::Chord {
+                panel,
+                buttons: chord.buttons().map(button_name).collect(),
+            },
+        }
+    }
+}
+
+/// Cheap to clone handle for publishing events
+#[derive(Debug, Clone)]
+pub struct Events(broadcast::Sender<Event>);
+
+impl Default for Events {
+    fn default() -> Self {
+        Self(broadcast::channel(CAPACITY).0)
+    }
+}
+
+impl Events {
+    /// Publish an event, it is dropped if nobody is listening
+    pub fn send(&self, event: Event) {
+        debug!("Event: {event:?}");
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+
+    fn has_subscribers(&self) -> bool {
+        self.0.receiver_count() > 0
+    }
+}
+
+/// mpd does not tell us when it changes state, poll it while someone is
+/// listening
+pub async fn watch_player(audio: AudioHandle, events: Events) {
+    let mut last = None;
+    loop {
+        tokio::time::sleep(PLAYER_POLL).await;
+        if !events.has_subscribers() {
+            last = None;
+            continue;
+        }
+
+        let Ok(Ok(status)) = audio.call(AudioController::status).await else {
+            continue;
+        };
+        let player = Event::Player {
+            state: status.state,
+            song: status.song.map(|song| song.file),
+        };
+        if last.as_ref() != Some(&player) {
+            events.send(player.clone());
+            last = Some(player);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use button_protocol::{Button, Chord};
+
+    #[test]
+    fn panel_events_are_typed_json() {
+        let bed = PanelId::from("bed");
+        let press = Event::from_panel(
+            &bed,
+            button_protocol::Event::Press(ButtonPress::Double(Button::TopLeft)),
+        );
+        let data = concat!(
+            r#"{"type":"press","panel":"bed","#,
+            r#""press":"double","button":"top_left"}"#,
+        );
+        assert_eq!(press.to_sse(), format!("event: press\ndata: {data}\n\n"));
+
+        let chord = Chord::of(&[Button::BottomLeft, Button::BottomRight]);
+        let chord =
+            Event::from_panel(&bed, button_protocol::Event::Chord(chord));
+        let json = serde_json::to_value(chord).unwrap();
+        assert_eq!(json["buttons"][1], "bottom_right");
+    }
+
+    #[test]
+    fn failed_actions_carry_the_error() {
+        let err = Err("no modes".to_owned());
+        let event = Event::action(Action::NextMode, None, &err);
+        let json = serde_json::to_value(event).unwrap();
+        assert_eq!(json["action"], "next_mode");
+        assert_eq!(json["source"], "api");
+        assert_eq!(json["error"], "no modes");
+
+        let main = PanelId::main();
+        let event = Event::action(Action::Stop, Some(&main), &Ok(()));
+        let json = serde_json::to_value(event).unwrap();
+        assert_eq!(json["panel"], "main");
+        assert!(json.get("error").is_none());
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_events() {
+        let events = Events::default();
+        events.send(Event::Lagged { missed: 0 });
+
+        let mut rx = events.subscribe();
+        let mode = Event::Mode {
+            mode: "Podcast".to_owned(),
+            playlist: "podcast_news".to_owned(),
+        };
+        events.send(mode.clone());
+        assert_eq!(rx.recv().await.unwrap(), mode);
+    }
+}
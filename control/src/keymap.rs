@@ -0,0 +1,530 @@
+//! Which action a button press performs, loaded from a toml file. See
+//! `keymap.toml` for the format, it is also the default keymap.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use button_protocol::{Button, ButtonPress, Chord};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::{Result, Section};
+
+use crate::audiocontrol::{AudioMode, Modes};
+use crate::panel::PanelId;
+
+const DEFAULT: &str = include_str!("../keymap.toml");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Previous,
+    Next,
+    Rewind,
+    Skip,
+    TogglePlayback,
+    Play,
+    Pause,
+    Stop,
+    /// also starts playing
+    PrevPlaylist,
+    /// also starts playing
+    NextPlaylist,
+    /// also starts playing
+    NextMode,
+    SendToDataServer,
+    SleepTimer,
+    /// pause a ringing alarm for a while
+    Snooze,
+    VolumeUp,
+    VolumeDown,
+    LockPanel,
+    Nothing,
+}
+
+impl Action {
+    const ALL: [(&'static str, Action); 18] = [
+        ("previous", Action::Previous),
+        ("next", Action::Next),
+        ("rewind", Action::Rewind),
+        ("skip", Action::Skip),
+        ("toggle_playback", Action::TogglePlayback),
+        ("play", Action::Play),
+        ("pause", Action::Pause),
+        ("stop", Action::Stop),
+        ("prev_playlist", Action::PrevPlaylist),
+        ("next_playlist", Action::NextPlaylist),
+        ("next_mode", Action::NextMode),
+        ("send_to_data_server", Action::SendToDataServer),
+        ("sleep_timer", Action::SleepTimer),
+        ("snooze", Action::Snooze),
+        ("volume_up", Action::VolumeUp),
+        ("volume_down", Action::VolumeDown),
+        ("lock_panel", Action::LockPanel),
+        ("nothing", Action::Nothing),
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        lookup(&Self::ALL, name)
+    }
+
+    /// The name used in the keymap and the api
+    pub fn name(self) -> &'static str {
+        name_of(&Self::ALL, &self)
+    }
+
+    fn parse(name: &str) -> Result<Self> {
+        Self::from_name(name)
+            .ok_or_else(|| eyre!("Unknown action: `{name}`"))
+            .with_options(&Self::ALL)
+    }
+
+    /// Actions that repeat while a button is held
+    const HOLDABLE: [(&'static str, Action); 5] = [
+        ("rewind", Action::Rewind),
+        ("skip", Action::Skip),
+        ("volume_up", Action::VolumeUp),
+        ("volume_down", Action::VolumeDown),
+        ("nothing", Action::Nothing),
+    ];
+
+    fn parse_holdable(name: &str) -> Result<Self> {
+        lookup(&Self::HOLDABLE, name)
+            .ok_or_else(|| eyre!("`{name}` can not be bound to a hold"))
+            .with_options(&Self::HOLDABLE)
+    }
+}
+
+const BUTTONS: [(&str, Button); 6] = [
+    ("top_left", Button::TopLeft),
+    ("top_middle", Button::TopMiddle),
+    ("top_right", Button::TopRight),
+    ("bottom_left", Button::BottomLeft),
+    ("bottom_middle", Button::BottomMiddle),
+    ("bottom_right", Button::BottomRight),
+];
+
+type PressKind = fn(Button) -> ButtonPress;
+
+const PRESSES: [(&str, PressKind); 4] = [
+    ("short", ButtonPress::Short),
+    ("long", ButtonPress::Long),
+    ("double", ButtonPress::Double),
+    ("triple", ButtonPress::Triple),
+];
+
+/// The name used in the keymap, for example `top_left`
+pub fn button_name(button: Button) -> &'static str {
+    name_of(&BUTTONS, &button)
+}
+
+fn lookup<T: Clone>(options: &[(&str, T)], name: &str) -> Option<T> {
+    options
+        .iter()
+        .find(|(option, _)| *option == name)
+        .map(|(_, item)| item.clone())
+}
+
+fn name_of<T: PartialEq>(
+    options: &[(&'static str, T)],
+    item: &T,
+) -> &'static str {
+    options
+        .iter()
+        .find(|(_, option)| option == item)
+        .map(|(name, _)| *name)
+        .expect("every item has a name")
+}
+
+trait WithOptions {
+    fn with_options<T>(self, options: &[(&str, T)]) -> Self;
+}
+
+impl<T> WithOptions for Result<T> {
+    fn with_options<O>(self, options: &[(&str, O)]) -> Self {
+        let names: Vec<_> = options.iter().map(|(name, _)| *name).collect();
+        self.with_note(|| format!("Options are: {}", names.join(", ")))
+    }
+}
+
+pub(crate) fn parse_button(name: &str) -> Result<Button> {
+    lookup(&BUTTONS, name)
+        .ok_or_else(|| eyre!("Unknown button: `{name}`"))
+        .with_options(&BUTTONS)
+}
+
+/// Parses `<press>_<button>`, for example `long_top_left`
+pub(crate) fn parse_press(key: &str) -> Result<ButtonPress> {
+    let (press, button) = key
+        .split_once('_')
+        .ok_or_else(|| eyre!("Expected `<press>_<button>`, got: `{key}`"))?;
+    let press = lookup(&PRESSES, press)
+        .ok_or_else(|| eyre!("Unknown press: `{press}`"))
+        .with_options(&PRESSES)?;
+    Ok(press(parse_button(button)?))
+}
+
+/// Parses `<button>+<button>[+...]`, for example `top_left+top_right`
+pub(crate) fn parse_chord(key: &str) -> Result<Chord> {
+    let mut chord = Chord::EMPTY;
+    for button in key.split('+') {
+        chord = chord.with(parse_button(button.trim())?);
+    }
+    if chord.len() < 2 {
+        return Err(eyre!("A chord needs at least two buttons, got: `{key}`"));
+    }
+    Ok(chord)
+}
+
+fn action_name<'a>(key: &str, value: &'a toml::Value) -> Result<&'a str> {
+    value
+        .as_str()
+        .ok_or_else(|| eyre!("Action for `{key}` should be a string"))
+}
+
+fn as_action(key: &str, value: &toml::Value) -> Result<Action> {
+    Action::parse(action_name(key, value)?)
+        .wrap_err_with(|| format!("Invalid binding `{key}`"))
+}
+
+fn as_table<'a>(name: &str, value: &'a toml::Value) -> Result<&'a toml::Table> {
+    value
+        .as_table()
+        .ok_or_else(|| eyre!("`{name}` should be a table"))
+}
+
+/// The bindings in the `[any]` table or in that of a mode
+#[derive(Debug, Clone, Default)]
+struct Table {
+    presses: HashMap<ButtonPress, Action>,
+    /// `hold_<button>`, what repeats while the button is held
+    holds: HashMap<Button, Action>,
+}
+
+fn parse_bindings(table: &toml::Table) -> Result<Table> {
+    let mut bindings = Table::default();
+    for (key, value) in table {
+        let invalid = || format!("Invalid binding `{key}`");
+        if let Some(button) = key.strip_prefix("hold_") {
+            let button = parse_button(button).wrap_err_with(invalid)?;
+            let name = action_name(key, value)?;
+            let action = Action::parse_holdable(name).wrap_err_with(invalid)?;
+            bindings.holds.insert(button, action);
+        } else {
+            let press = parse_press(key).wrap_err_with(invalid)?;
+            bindings.presses.insert(press, as_action(key, value)?);
+        }
+    }
+    Ok(bindings)
+}
+
+/// Bindings of the whole panel or of a single panel
+#[derive(Debug, Clone, Default)]
+struct Bindings {
+    any: Table,
+    modes: HashMap<AudioMode, Table>,
+    chords: HashMap<Chord, Action>,
+}
+
+impl Bindings {
+    /// Add the `[any]`, `[chords]` or mode table called `key`
+    fn add_table(
+        &mut self,
+        key: &str,
+        value: &toml::Value,
+        modes: &Modes,
+    ) -> Result<()> {
+        match key {
+            "any" => self.any = parse_bindings(as_table(key, value)?)?,
+            "chords" => {
+                for (chord, action) in as_table(key, value)? {
+                    let action = as_action(chord, action)?;
+                    let chord = parse_chord(chord).wrap_err_with(|| {
+                        format!("Invalid chord binding `{chord}`")
+                    })?;
+                    self.chords.insert(chord, action);
+                }
+            }
+            mode => {
+                let names: Vec<_> =
+                    modes.iter().map(|m| m.name.key()).collect();
+                let mode = modes
+                    .find(mode)
+                    .cloned()
+                    .ok_or_else(|| eyre!("Unknown table: `[{mode}]`"))
+                    .with_note(|| "Tables are: any, chords, panels or a mode")
+                    .with_note(|| format!("Modes are: {}", names.join(", ")))?;
+                let bindings = parse_bindings(as_table(key, value)?)
+                    .wrap_err_with(|| format!("In table `[{key}]`"))?;
+                self.modes.insert(mode, bindings);
+            }
+        }
+        Ok(())
+    }
+
+    fn press_action(
+        &self,
+        mode: &AudioMode,
+        press: ButtonPress,
+    ) -> Option<Action> {
+        self.modes
+            .get(mode)
+            .and_then(|bindings| bindings.presses.get(&press))
+            .or_else(|| self.any.presses.get(&press))
+            .copied()
+    }
+
+    fn hold_action(&self, mode: &AudioMode, button: Button) -> Option<Action> {
+        self.modes
+            .get(mode)
+            .and_then(|bindings| bindings.holds.get(&button))
+            .or_else(|| self.any.holds.get(&button))
+            .copied()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    /// Used when neither the mode nor `any` binds a press
+    unbound: Action,
+    bindings: Bindings,
+    /// Bindings for a single panel, these go before the other bindings
+    panels: HashMap<PanelId, Bindings>,
+}
+
+impl Keymap {
+    /// The keymap that ships with the panel, it knows about the default
+    /// modes.
+    pub fn shipped(modes: &Modes) -> Result<Self> {
+        Self::parse(DEFAULT, modes)
+            .wrap_err("The shipped keymap does not fit the configured modes")
+            .suggestion("Write a keymap for these modes, see keymap.toml")
+    }
+
+    pub fn load(path: &Path, modes: &Modes) -> Result<Self> {
+        let content = std::fs::read_to_string(path).wrap_err_with(|| {
+            format!("Could not read keymap: {}", path.display())
+        })?;
+        Self::parse(&content, modes)
+            .wrap_err_with(|| format!("Invalid keymap: {}", path.display()))
+    }
+
+    /// Mode tables are the mode names in lowercase
+    pub fn parse(content: &str, modes: &Modes) -> Result<Self> {
+        let table: toml::Table =
+            content.parse().wrap_err("Keymap is not valid toml")?;
+
+        let mut keymap = Keymap {
+            unbound: Action::Nothing,
+            bindings: Bindings::default(),
+            panels: HashMap::new(),
+        };
+
+        for (key, value) in &table {
+            match key.as_str() {
+                "unbound" => keymap.unbound = as_action(key, value)?,
+                "panels" => {
+                    for (id, tables) in as_table(key, value)? {
+                        let bindings = keymap
+                            .panels
+                            .entry(PanelId::from(id.as_str()))
+                            .or_default();
+                        for (key, value) in as_table(id, tables)? {
+                            bindings
+                                .add_table(key, value, modes)
+                                .wrap_err_with(|| {
+                                    format!("In table `[panels.{id}]`")
+                                })?;
+                        }
+                    }
+                }
+                key => keymap.bindings.add_table(key, value, modes)?,
+            }
+        }
+        Ok(keymap)
+    }
+
+    /// Make sure every panel in the keymap exists
+    pub fn check_panels(&self, panels: &[PanelId]) -> Result<()> {
+        for id in self.panels.keys() {
+            if !panels.contains(id) {
+                let names: Vec<_> = panels.iter().map(PanelId::name).collect();
+                return Err(eyre!("Keymap binds unknown panel `{id}`"))
+                    .with_note(|| format!("Panels are: {}", names.join(", ")));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn press_action(
+        &self,
+        panel: &PanelId,
+        mode: &AudioMode,
+        press: ButtonPress,
+    ) -> Action {
+        self.panels
+            .get(panel)
+            .and_then(|bindings| bindings.press_action(mode, press))
+            .or_else(|| self.bindings.press_action(mode, press))
+            .unwrap_or(self.unbound)
+    }
+
+    /// What holding `button` repeats, holds without binding do nothing
+    pub fn hold_action(
+        &self,
+        panel: &PanelId,
+        mode: &AudioMode,
+        button: Button,
+    ) -> Action {
+        self.panels
+            .get(panel)
+            .and_then(|bindings| bindings.hold_action(mode, button))
+            .or_else(|| self.bindings.hold_action(mode, button))
+            .unwrap_or(Action::Nothing)
+    }
+
+    /// Action for presses without binding
+    pub fn unbound(&self) -> Action {
+        self.unbound
+    }
+
+    pub fn chord_action(&self, panel: &PanelId, chord: Chord) -> Action {
+        self.panels
+            .get(panel)
+            .and_then(|bindings| bindings.chords.get(&chord))
+            .or_else(|| self.bindings.chords.get(&chord))
+            .copied()
+            .unwrap_or(Action::Nothing)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use button_protocol::{Button::*, ButtonPress::*};
+
+    #[test]
+    fn shipped_matches_old_behaviour() {
+        let keymap = Keymap::shipped(&Modes::default()).unwrap();
+        let main = PanelId::main();
+        let action = |mode, press| {
+            keymap.press_action(&main, &AudioMode::from(mode), press)
+        };
+
+        assert_eq!(action("Music", Short(TopLeft)), Action::Previous);
+        assert_eq!(action("Podcast", Short(TopLeft)), Action::Rewind);
+        assert_eq!(action("Podcast", Double(TopRight)), Action::Next);
+        assert_eq!(action("Meditation", Long(TopMiddle)), Action::NextMode);
+        assert_eq!(
+            action("Music", Short(BottomLeft)),
+            Action::SendToDataServer
+        );
+        assert_eq!(action("Music", Long(BottomMiddle)), Action::Snooze);
+        let lock = Chord::of(&[BottomLeft, BottomRight]);
+        assert_eq!(keymap.chord_action(&main, lock), Action::LockPanel);
+
+        let hold = |mode, button| {
+            keymap.hold_action(&main, &AudioMode::from(mode), button)
+        };
+        assert_eq!(hold("Podcast", TopLeft), Action::Rewind);
+        assert_eq!(hold("Music", TopLeft), Action::Nothing);
+        assert_eq!(hold("Music", BottomRight), Action::VolumeUp);
+    }
+
+    #[test]
+    fn holds_resolve_like_presses() {
+        let keymap = Keymap::parse(
+            r#"
+            [any]
+            hold_top_left = "volume_down"
+            [podcast]
+            hold_top_left = "rewind"
+            [panels.door.any]
+            hold_top_left = "nothing"
+            "#,
+            &Modes::default(),
+        )
+        .unwrap();
+
+        let hold = |panel: &str, mode| {
+            let (panel, mode) = (PanelId::from(panel), AudioMode::from(mode));
+            keymap.hold_action(&panel, &mode, TopLeft)
+        };
+        assert_eq!(hold("main", "Music"), Action::VolumeDown);
+        assert_eq!(hold("main", "Podcast"), Action::Rewind);
+        assert_eq!(hold("door", "Podcast"), Action::Nothing);
+    }
+
+    #[test]
+    fn mode_overrides_any() {
+        let keymap = Keymap::parse(
+            r#"
+            [any]
+            short_top_left = "stop"
+            [music]
+            short_top_left = "skip"
+            "#,
+            &Modes::default(),
+        )
+        .unwrap();
+
+        let main = PanelId::main();
+        let action = |mode, press| {
+            keymap.press_action(&main, &AudioMode::from(mode), press)
+        };
+        assert_eq!(action("Music", Short(TopLeft)), Action::Skip);
+        assert_eq!(action("Podcast", Short(TopLeft)), Action::Stop);
+        let unbound = action("Podcast", Short(TopRight));
+        assert_eq!(unbound, Action::Nothing);
+    }
+
+    #[test]
+    fn panels_override_the_rest() {
+        let keymap = Keymap::parse(
+            r#"
+            [any]
+            short_bottom_left = "stop"
+            [chords]
+            "top_left+top_right" = "sleep_timer"
+            [panels.door.any]
+            short_bottom_left = "pause"
+            [panels.door.podcast]
+            short_bottom_left = "skip"
+            [panels.door.chords]
+            "top_left+top_right" = "lock_panel"
+            "#,
+            &Modes::default(),
+        )
+        .unwrap();
+
+        let bed = PanelId::from("bed");
+        let door = PanelId::from("door");
+        let press = |panel, mode| {
+            let mode = AudioMode::from(mode);
+            keymap.press_action(panel, &mode, Short(BottomLeft))
+        };
+        assert_eq!(press(&bed, "Music"), Action::Stop);
+        assert_eq!(press(&door, "Music"), Action::Pause);
+        assert_eq!(press(&door, "Podcast"), Action::Skip);
+
+        let chord = Chord::of(&[TopLeft, TopRight]);
+        assert_eq!(keymap.chord_action(&bed, chord), Action::SleepTimer);
+        assert_eq!(keymap.chord_action(&door, chord), Action::LockPanel);
+
+        assert!(keymap.check_panels(&[door]).is_ok());
+        assert!(keymap.check_panels(&[bed]).is_err());
+    }
+
+    #[test]
+    fn errors_name_the_problem() {
+        let modes = Modes::default();
+        let err = |content| {
+            format!("{:?}", Keymap::parse(content, &modes).unwrap_err())
+        };
+
+        assert!(err("[any]\nshort_top_left = \"jump\"").contains("`jump`"));
+        assert!(err("[any]\nshort_top_side = \"skip\"").contains("`top_side`"));
+        assert!(err("[any]\nquick_top_left = \"skip\"").contains("`quick`"));
+        assert!(err("[jazz]\nshort_top_left = \"skip\"").contains("`[jazz]`"));
+        assert!(err("[chords]\n\"top_left\" = \"stop\"").contains("two"));
+        let hold = err("[any]\nhold_top_left = \"next_mode\"");
+        assert!(hold.contains("can not be bound to a hold"));
+    }
+}
@@ -2,143 +2,703 @@
 #![allow(clippy::missing_panics_doc)]
 #![allow(clippy::missing_errors_doc)]
 
-use std::{
-    net::{IpAddr, SocketAddr},
-    str::FromStr,
-    sync::Arc,
-    time::Duration,
-};
+use std::collections::BTreeMap;
+use std::{net::SocketAddr, path::PathBuf, time::Duration};
 
 use clap::Parser;
+use color_eyre::eyre::{eyre, Context};
 use data_server::api::data_source::reconnecting::Client;
-use tokio::{net::TcpListener, sync::Mutex};
-use tracing::error;
+use tokio::sync::mpsc;
+use tokio::{net::TcpListener, task::JoinHandle};
+use tracing::{error, info, warn};
 
+pub mod alarm;
+pub mod api;
 pub mod audiocontrol;
+pub mod config;
+pub mod events;
+pub mod keymap;
 pub mod panel;
-pub mod tcp;
+pub mod record;
+pub mod status;
 
-use crate::audiocontrol::AudioMode;
+use crate::audiocontrol::{AudioMode, Mode};
 
-use self::{audiocontrol::ForceRewind, panel::Panel};
-use audiocontrol::AudioController;
-use button_protocol::ButtonPress;
+use self::audiocontrol::ForceRewind;
+use self::panel::{Keyboard, Network, Panel, PanelId, RecvError, Usart};
+use alarm::Scheduler;
+use audiocontrol::{AudioController, AudioError, AudioHandle};
+use button_protocol::{Blink, Button, ButtonPress, Color, Command, Event, Hold};
+use config::{Config, Connection};
+use events::Events;
+use keymap::{Action, Keymap};
+use status::{Link, SharedHealth};
 
-const ALARM_DELAY_MINS: u64 = 7;
-const ALARM_SOUND_PATH: &str = "alarm-with-warning.ogg";
-
-const DATA_SERVER_IP: &str = "192.168.1.43";
-const DATA_SERVER_PORT: u16 = 1234;
+/// Volume change in percent per hold repeat
+const VOLUME_STEP: i8 = 2;
+const SLEEP_TIMER: Duration = Duration::from_secs(30 * 60);
 
 #[derive(Parser, Debug, Default)]
 #[clap(author, version, about, long_about = None)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
+    #[clap(subcommand)]
+    pub command: Option<SubCommand>,
     #[clap(short, long)]
     pub setup: bool,
-    /// path to the USB device, for example: /dev/ttyUSB0
+    // the empty defaults are only used when running a subcommand
+    /// path to the USB device, for example: /dev/ttyUSB0. Prefer the stable
+    /// path in /dev/serial/by-id/, it survives replugging the panel.
+    #[clap(required = true, default_value = "", hide_default_value = true)]
     pub tty: String,
     /// ip:port for the mpd server
+    #[clap(required = true, default_value = "", hide_default_value = true)]
     pub ip: String,
+    /// toml config file, uses the defaults if not set
+    #[clap(short, long)]
+    pub config: Option<PathBuf>,
+    /// toml file with button bindings, overrides the keymap in the config
+    #[clap(short, long)]
+    pub keymap: Option<PathBuf>,
+    /// append everything that happens to this log, see `replay`
+    #[clap(short, long)]
+    pub record: Option<PathBuf>,
 }
 
-async fn handle_buttonpress(
-    audio: &mut AudioController,
+#[derive(clap::Subcommand, Debug)]
+pub enum SubCommand {
+    /// Show what the running instance is doing
+    Status {
+        /// toml config file of the running instance
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+        /// print the raw json
+        #[clap(long)]
+        json: bool,
+    },
+    /// Manage the alarms of the running instance
+    Alarm {
+        #[clap(subcommand)]
+        command: alarm::Command,
+        /// toml config file of the running instance
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+    },
+    /// Move the database to the current layout, the service does this when
+    /// it starts. Stop it first, the database can only be opened once.
+    Migrate {
+        /// toml config file with the database path
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+        /// only report what would be moved
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Write the playback state in the database to a json file: the
+    /// current mode and playlists, positions and when playlists were last
+    /// played. Stop the service first.
+    Export {
+        /// the json file to write
+        file: PathBuf,
+        /// toml config file with the database path
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+    },
+    /// Store the playback state from a json file made by `export`, keeping
+    /// what the file does not mention. Stop the service first.
+    Import {
+        /// the json file to read
+        file: PathBuf,
+        /// toml config file with the database path
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+    },
+    /// Play back the presses of a panel from a log made with --record
+    Replay {
+        /// the log to play back
+        log: PathBuf,
+        /// ip:port for the mpd server, best a test one
+        ip: String,
+        /// panel whose presses to play back, they come from a panel with
+        /// that id
+        #[clap(long, default_value = "main")]
+        panel: String,
+        /// play back this many times faster than recorded
+        #[clap(long, default_value_t = 1.0)]
+        speedup: f64,
+        /// toml config file, uses the defaults if not set
+        #[clap(short, long)]
+        config: Option<PathBuf>,
+        /// toml file with button bindings
+        #[clap(short, long)]
+        keymap: Option<PathBuf>,
+        /// record the replay too, to compare it with the original
+        #[clap(short, long)]
+        record: Option<PathBuf>,
+    },
+}
+
+async fn send_to_data_server(
     data_server: &mut Option<Client>,
+    health: &SharedHealth,
     button_press: ButtonPress,
 ) {
-    use audiocontrol::AudioMode::*;
-    use button_protocol::{Button::*, ButtonPress::*};
-
-    match (&audio.mode, button_press) {
-        (Music | Singing | Meditation, Short(TopLeft)) => audio.previous(),
-        (Podcast, Short(TopLeft)) => audio.rewind(),
-
-        (Music | Singing | Meditation, Short(TopRight)) => audio.next(),
-        (Podcast, Short(TopRight)) => audio.skip(),
-
-        (_, Short(TopMiddle)) => audio.toggle_playback(),
+    if let Some(data_server) = data_server {
+        println!("Sending reading for {button_press:?} to data server");
+        // the data server has no multi presses, it gets a reading per tap
+        let mut res = Ok(());
+        for _ in 0..button_press.taps() {
+            res = data_server.send_reading(button_press.into()).await;
+            if res.is_err() {
+                break;
+            }
+        }
+        let link = &mut health.lock().expect("not poisoned").data_server;
+        match res {
+            Ok(()) => link.ok(),
+            Err(err) => {
+                error!("Error while sending button to data server: {err}");
+                link.failed(err);
+            }
+        }
+        println!("Done sending reading");
+    }
+}
 
-        (_, Long(TopLeft)) => {
-            audio.prev_playlist();
+/// Perform an action that changes what is playing
+pub(crate) fn perform_audio(
+    audio: &mut AudioController,
+    action: Action,
+) -> Result<(), AudioError> {
+    match action {
+        Action::Previous => audio.previous(),
+        Action::Next => audio.next(),
+        Action::Rewind => audio.rewind(),
+        Action::Skip => audio.skip(),
+        Action::TogglePlayback => audio.toggle_playback(),
+        Action::Play => audio.play(ForceRewind::No),
+        Action::Pause => audio.pause(),
+        Action::Stop => audio.stop(),
+        Action::VolumeUp => audio.change_volume(VOLUME_STEP),
+        Action::VolumeDown => audio.change_volume(-VOLUME_STEP),
+        Action::PrevPlaylist => {
+            audio.prev_playlist()?;
             audio.play(ForceRewind::No)
         }
-        (_, Long(TopRight)) => {
-            audio.next_playlist();
+        Action::NextPlaylist => {
+            audio.next_playlist()?;
             audio.play(ForceRewind::No)
         }
-        (_, Long(TopMiddle)) => {
-            audio.next_mode();
+        Action::NextMode => {
+            audio.next_mode()?;
             audio.play(ForceRewind::No)
         }
+        Action::SendToDataServer
+        | Action::SleepTimer
+        | Action::Snooze
+        | Action::LockPanel
+        | Action::Nothing => Ok(()),
+    }
+}
 
-        (_, b) => {
-            if let Some(data_server) = data_server {
-                println!("Sending reading for {b:?} to data server");
-                if let Err(err) = data_server.send_reading(b.into()).await {
-                    error!("Error while sending button to data server: {err}");
-                }
-                println!("Done sending reading");
+/// Without a ringing alarm a snooze press does what it did before there
+/// was snooze
+fn unless_snoozing(
+    action: Action,
+    event: Event,
+    ringing: bool,
+    keymap: &Keymap,
+) -> Action {
+    match event {
+        _ if action != Action::Snooze || ringing => action,
+        Event::Press(_) => keymap.unbound(),
+        Event::Chord(_) | Event::Hold(_) => Action::Nothing,
+    }
+}
+
+/// Let the user know how handling a press went
+fn feedback(
+    state: &mut PanelState,
+    id: &PanelId,
+    button_press: ButtonPress,
+    result: Result<(), String>,
+    mode: Option<&Mode>,
+) {
+    match result {
+        Err(err) => report_failure(state, id, button_press, &err),
+        Ok(()) if matches!(button_press, ButtonPress::Long(_)) => {
+            if let Some(panel) = state.panels.get(id) {
+                panel.send(Command::Ack(button_press));
             }
         }
+        Ok(()) => (),
+    }
+    if let Some(mode) = mode {
+        show_mode(state, mode);
     }
 }
 
-async fn handle_tcp_message(audio_mutex: &Mutex<AudioController>, message: &str) {
-    match message {
-        "alarm" => {
-            let mut audio = audio_mutex.lock().await;
-            audio.reconnect().unwrap();
-            tokio::time::sleep(Duration::from_millis(500)).await;
+/// Log what went wrong handling `what` from panel `id` and blink it red.
+/// Nothing is retried, the next press is handled as usual.
+fn report_failure(
+    state: &PanelState,
+    id: &PanelId,
+    what: impl std::fmt::Debug,
+    err: &str,
+) {
+    error!("Could not handle {what:?} from {id}: {err}");
+    if let Some(panel) = state.panels.get(id) {
+        panel.send(Command::Blink(None, Color::RED, Blink::ERROR));
+    }
+}
 
-            let pl_name = "music_wakeup";
-            audio.create_wakeup_playlist(pl_name).await;
-            audio.play_mode_playlist(&AudioMode::Music, pl_name).await;
+/// The top middle led of every panel shows the current mode
+fn show_mode(state: &mut PanelState, mode: &Mode) {
+    for panel in state.panels.values_mut() {
+        if panel.shown_mode.as_ref() == Some(&mode.name) {
+            continue;
         }
-        _ => (),
+        panel.shown_mode = Some(mode.name.clone());
+        panel.send(Command::SetLed(Some(Button::TopMiddle), mode.color()));
+    }
+}
+
+async fn send_command(panel: &mut impl Panel, command: Command) {
+    if let Err(err) = panel.send(command).await {
+        warn!("Could not send {command:?} to panel: {err}");
+    }
+}
+
+/// Seek or change the volume while a button is held, `None` if the hold
+/// does nothing. Seeking leaves playback as it was.
+fn handle_hold(
+    audio: &mut AudioController,
+    hold: Hold,
+    action: Action,
+) -> Option<Result<(), AudioError>> {
+    let Hold::Repeat(_, repeat) = hold else {
+        return None;
+    };
+    Some(match action {
+        Action::Rewind => audio.rewind_by(seek_step(repeat)),
+        Action::Skip => audio.skip_by(seek_step(repeat)),
+        Action::VolumeDown => audio.change_volume(-VOLUME_STEP),
+        Action::VolumeUp => audio.change_volume(VOLUME_STEP),
+        _ => return None,
+    })
+}
+
+/// How far to seek per hold repeat, the longer the hold the faster we go
+fn seek_step(repeat: u16) -> Duration {
+    let secs = match repeat {
+        0..=4 => 5,
+        5..=12 => 15,
+        13..=24 => 30,
+        _ => 60,
     };
+    Duration::from_secs(secs)
+}
+
+/// What a panel task tells the dispatcher
+#[derive(Debug)]
+enum FromPanel {
+    Event(Event),
+    Online,
+    Offline(String),
+    Closed(RecvError),
+}
+
+/// A panel as seen by the dispatcher
+struct PanelHandle {
+    commands: mpsc::Sender<Command>,
+    locked: bool,
+    /// mode the panel leds currently show
+    shown_mode: Option<AudioMode>,
+}
+
+impl PanelHandle {
+    /// Queue a command, the panel task sends it
+    fn send(&self, command: Command) {
+        if let Err(err) = self.commands.try_send(command) {
+            warn!("Could not queue {command:?} for panel: {err}");
+        }
+    }
 }
 
-pub async fn run(panel: impl Panel + Send + 'static, args: Args) -> ! {
-    let audio = Arc::new(Mutex::new(AudioController::new(&args.ip, "6600")));
-    audio.lock().await.rescan();
+/// Dispatcher state that is not part of the audio controller
+struct PanelState {
+    panels: BTreeMap<PanelId, PanelHandle>,
+    sleep_timer: Option<JoinHandle<()>>,
+    alarms: Scheduler,
+}
+
+/// Perform an action that changes the dispatcher state
+fn perform_panel(
+    state: &mut PanelState,
+    audio: &AudioHandle,
+    id: &PanelId,
+    action: Action,
+) {
+    match action {
+        Action::SleepTimer => toggle_sleep_timer(state, audio),
+        Action::LockPanel => {
+            if let Some(panel) = state.panels.get_mut(id) {
+                panel.locked = !panel.locked;
+                info!("Panel {id} locked: {}", panel.locked);
+            }
+        }
+        _ => (),
+    }
+}
 
-    let tcp_listener = TcpListener::bind("127.0.0.1:3141").await.unwrap();
+fn toggle_sleep_timer(state: &mut PanelState, audio: &AudioHandle) {
+    if let Some(timer) = state.sleep_timer.take() {
+        if !timer.is_finished() {
+            info!("Cancelling sleep timer");
+            timer.abort();
+            return;
+        }
+    }
+
+    info!("Pausing in {SLEEP_TIMER:?}");
+    let audio = audio.clone();
+    state.sleep_timer = Some(tokio::spawn(async move {
+        tokio::time::sleep(SLEEP_TIMER).await;
+        info!("Sleep timer done");
+        if let Err(err) = audio.try_call(AudioController::pause).await {
+            error!("Could not pause for the sleep timer: {err}");
+        }
+    }));
+}
+
+/// The current mode, logging why if the controller does not tell
+async fn current_mode(audio: &AudioHandle) -> Option<Mode> {
+    let mode = audio.call(|audio| audio.current_mode().clone()).await;
+    mode.inspect_err(|err| error!("Could not get the current mode: {err}"))
+        .ok()
+}
 
-    let buttons = buttonpress_task(panel, audio.clone());
-    let tcp = tcp_task(tcp_listener, audio);
+/// Runs until killed. Next to `panel`, called `id`, the panels in the
+/// config are used. A config panel with the same id is left out, `panel`
+/// stands in for it.
+pub async fn run(
+    id: PanelId,
+    panel: impl Panel + Send + 'static,
+    args: Args,
+) -> color_eyre::Result<()> {
+    let config = Config::load(args.config.as_deref())?;
+    let keymap = match args.keymap.as_ref().or(config.keymap.as_ref()) {
+        Some(path) => Keymap::load(path, &config.modes)?,
+        None => Keymap::shipped(&config.modes)?,
+    };
+    let panel_ids: Vec<_> = std::iter::once(id.clone())
+        .chain(config.panels.iter().map(|panel| panel.id.clone()))
+        .collect();
+    keymap.check_panels(&panel_ids)?;
+
+    let events = Events::default();
+    let audio = AudioController::new(&args.ip, &config, events.clone())
+        .wrap_err("Could not set up the audio controller")?;
+    let timeout = Duration::from_secs(config.mpd.timeout_secs);
+    let audio = AudioHandle::spawn(audio, timeout);
+    let rescan = AudioController::rescan;
+    let rescan = audio.call_within(audiocontrol::RESCAN_TIMEOUT, rescan);
+    let rescanned = rescan.await.and_then(|res| res.map_err(|e| e.to_string()));
+    if let Err(err) = rescanned {
+        error!("Could not rescan the music library: {err}");
+    }
+
+    if let Some(path) = &args.record {
+        tokio::task::spawn(record::start(path, &events).await?);
+    }
+
+    let api_listener = TcpListener::bind(config.api.bind)
+        .await
+        .wrap_err_with(|| format!("Could not listen on {}", config.api.bind))?;
+
+    let health = SharedHealth::default();
+    let (to_dispatcher, from_panels) = mpsc::channel(16);
+    let alarms = Scheduler::load(audio.clone(), config.alarm.clone())
+        .await
+        .map_err(|err| eyre!(err))
+        .wrap_err("Could not load the alarms")?;
+    let mut state = PanelState {
+        panels: BTreeMap::new(),
+        sleep_timer: None,
+        alarms: alarms.clone(),
+    };
+    let handle = spawn_panel(id.clone(), panel, to_dispatcher.clone(), &health);
+    state.panels.insert(id.clone(), handle);
+    for panel in config.panels.iter().filter(|panel| panel.id != id) {
+        let id = panel.id.clone();
+        let to_dispatcher = to_dispatcher.clone();
+        let listen_err = |bind| format!("Could not listen for {id} on {bind}");
+        let handle = match panel.connection()? {
+            Connection::Tty(tty) => {
+                let usart = Usart::new(tty);
+                spawn_panel(id.clone(), usart, to_dispatcher, &health)
+            }
+            Connection::Tcp(bind) => {
+                let network = Network::tcp(id.clone(), bind)
+                    .await
+                    .wrap_err_with(|| listen_err(bind))?;
+                spawn_panel(id.clone(), network, to_dispatcher, &health)
+            }
+            Connection::Udp(bind) => {
+                let network = Network::udp(id.clone(), bind)
+                    .await
+                    .wrap_err_with(|| listen_err(bind))?;
+                spawn_panel(id.clone(), network, to_dispatcher, &health)
+            }
+            Connection::Keyboard(device, keys) => {
+                let keyboard = Keyboard::new(device, keys).await;
+                spawn_panel(id.clone(), keyboard, to_dispatcher, &health)
+            }
+        };
+        state.panels.insert(id, handle);
+    }
+    drop(to_dispatcher);
+
+    let buttons = buttonpress_task(
+        from_panels,
+        state,
+        audio.clone(),
+        keymap,
+        config.data_server.address,
+        health.clone(),
+        events.clone(),
+    );
+    let player = events::watch_player(audio.clone(), events.clone());
+    let api = api::serve(api_listener, audio, health, events, alarms.clone());
     tokio::task::spawn(buttons);
-    tokio::task::spawn(tcp);
+    tokio::task::spawn(player);
+    tokio::task::spawn(alarms.run());
+    tokio::task::spawn(async {
+        if let Err(err) = api.await {
+            error!("Api server stopped: {err}");
+        }
+    });
+
+    std::future::pending().await
+}
 
-    let () = std::future::pending().await;
-    unreachable!();
+/// Starts the task owning `panel`, its health starts out as how opening the
+/// panel went
+fn spawn_panel(
+    id: PanelId,
+    panel: impl Panel + Send + 'static,
+    to_dispatcher: mpsc::Sender<(PanelId, FromPanel)>,
+    health: &SharedHealth,
+) -> PanelHandle {
+    update_health(health, &id, |link| {
+        if panel.is_connected() {
+            link.ok();
+        }
+    });
+    let (commands, from_dispatcher) = mpsc::channel(16);
+    tokio::task::spawn(panel_task(id, panel, from_dispatcher, to_dispatcher));
+    PanelHandle {
+        commands,
+        locked: false,
+        shown_mode: None,
+    }
 }
 
-async fn tcp_task(tcp_listener: TcpListener, audio: Arc<Mutex<AudioController>>) -> ! {
+/// Owns a panel: forwards its events to the dispatcher and sends it the
+/// commands the dispatcher queued
+async fn panel_task(
+    id: PanelId,
+    mut panel: impl Panel + Send,
+    mut commands: mpsc::Receiver<Command>,
+    to_dispatcher: mpsc::Sender<(PanelId, FromPanel)>,
+) {
     loop {
-        let message = tcp::wait_for_message(&tcp_listener).await;
-        handle_tcp_message(&audio, &message).await;
+        let message = tokio::select! {
+            res = panel.recv() => match res {
+                Ok(event) => FromPanel::Event(event),
+                Err(RecvError::Offline(reason)) => {
+                    warn!("Lost panel {id} ({reason}), waiting for it");
+                    let offline = FromPanel::Offline(reason);
+                    let _ = to_dispatcher.send((id.clone(), offline)).await;
+                    match panel.reconnect().await {
+                        Ok(()) => {
+                            // queued while offline, no longer relevant
+                            while commands.try_recv().is_ok() {}
+                            FromPanel::Online
+                        }
+                        Err(err) => FromPanel::Closed(err),
+                    }
+                }
+                Err(err) => FromPanel::Closed(err),
+            },
+            Some(command) = commands.recv() => {
+                send_command(&mut panel, command).await;
+                continue;
+            }
+        };
+
+        let closed = matches!(message, FromPanel::Closed(_));
+        let sent = to_dispatcher.send((id.clone(), message)).await;
+        if sent.is_err() || closed {
+            return;
+        }
     }
 }
 
-async fn buttonpress_task(mut panel: impl Panel, audio: Arc<Mutex<AudioController>>) -> ! {
-    let addr = SocketAddr::new(
-        IpAddr::from_str(DATA_SERVER_IP).expect("Valid const"),
-        DATA_SERVER_PORT,
-    );
+fn update_health(
+    health: &SharedHealth,
+    id: &PanelId,
+    update: impl FnOnce(&mut Link),
+) {
+    let mut health = health.lock().expect("not poisoned");
+    update(health.panels.entry(id.to_string()).or_default());
+}
 
-    let mut data_server = Client::new(addr, Vec::new(), None)
+async fn buttonpress_task(
+    mut from_panels: mpsc::Receiver<(PanelId, FromPanel)>,
+    mut state: PanelState,
+    audio: AudioHandle,
+    keymap: Keymap,
+    data_server: SocketAddr,
+    health: SharedHealth,
+    events: Events,
+) {
+    let mut data_server = Client::new(data_server, Vec::new(), None)
         .await
-        .inspect_err(|err| error!("Invalid client address: {err}"))
+        .inspect_err(|err| {
+            error!("Invalid client address: {err}");
+            let link = &mut health.lock().expect("not poisoned").data_server;
+            link.failed(format!("Invalid client address: {err}"));
+        })
         .ok();
 
-    loop {
-        let button_press = panel
-            .recv()
-            .await // TODO: crash or handle in panel not here
-            .expect("could not deserialize button press before it was send");
-        let mut audio = audio.lock().await;
-        handle_buttonpress(&mut audio, &mut data_server, button_press).await;
+    if let Some(mode) = current_mode(&audio).await {
+        show_mode(&mut state, &mode);
     }
+
+    while let Some((id, message)) = from_panels.recv().await {
+        let event = match message {
+            FromPanel::Event(event) => {
+                update_health(&health, &id, Link::ok);
+                event
+            }
+            FromPanel::Online => {
+                info!("Panel {id} is back");
+                update_health(&health, &id, Link::ok);
+                // the panel forgets its leds when it loses power
+                if let Some(panel) = state.panels.get_mut(&id) {
+                    panel.shown_mode = None;
+                }
+                if let Some(mode) = current_mode(&audio).await {
+                    show_mode(&mut state, &mode);
+                }
+                continue;
+            }
+            FromPanel::Offline(reason) => {
+                update_health(&health, &id, |link| link.failed(reason));
+                continue;
+            }
+            FromPanel::Closed(err) => {
+                error!("Stopped handling button presses from {id}: {err}");
+                update_health(&health, &id, |link| link.failed(err));
+                state.panels.remove(&id);
+                continue;
+            }
+        };
+
+        events.send(events::Event::from_panel(&id, event));
+        // the wake-up volume stops rising once anything is pressed, even on
+        // a locked panel
+        state.alarms.stop_ramp();
+        let action = match event {
+            Event::Chord(chord) => keymap.chord_action(&id, chord),
+            Event::Press(press) => {
+                let Some(mode) = current_mode(&audio).await else {
+                    continue;
+                };
+                keymap.press_action(&id, &mode.name, press)
+            }
+            Event::Hold(hold) => {
+                let Some(mode) = current_mode(&audio).await else {
+                    continue;
+                };
+                keymap.hold_action(&id, &mode.name, hold.button())
+            }
+        };
+        let ringing = state.alarms.is_ringing();
+        let action = unless_snoozing(action, event, ringing, &keymap);
+        let locked = state.panels.get(&id).is_some_and(|panel| panel.locked);
+        if locked && action != Action::LockPanel {
+            info!("Panel {id} locked, ignoring {event:?}");
+            continue;
+        }
+
+        if action == Action::Snooze {
+            let res = state.alarms.snooze().await;
+            events.send(events::Event::action(action, Some(&id), &res));
+            if let Event::Press(press) = event {
+                feedback(&mut state, &id, press, res, None);
+            } else if let Err(err) = res {
+                report_failure(&state, &id, event, &err);
+            }
+            continue;
+        }
+        // a ringing alarm is dismissed by using the panel, holds are part
+        // of a press so that is enough
+        if !matches!(event, Event::Hold(_)) {
+            state.alarms.dismiss();
+        }
+
+        match event {
+            Event::Press(button_press) => {
+                if action == Action::SendToDataServer {
+                    send_to_data_server(&mut data_server, &health, button_press)
+                        .await;
+                }
+                perform_panel(&mut state, &audio, &id, action);
+                let done = audio.call(move |audio| {
+                    let res = perform_audio(audio, action);
+                    let res = res.map_err(|err| err.to_string());
+                    (res, audio.current_mode().clone())
+                });
+                let (res, mode) = match done.await {
+                    Ok((res, mode)) => (res, Some(mode)),
+                    Err(err) => (Err(err), None),
+                };
+                events.send(events::Event::action(action, Some(&id), &res));
+                feedback(&mut state, &id, button_press, res, mode.as_ref());
+            }
+            Event::Chord(chord) => {
+                perform_panel(&mut state, &audio, &id, action);
+                let res = audio
+                    .try_call(move |audio| perform_audio(audio, action))
+                    .await;
+                events.send(events::Event::action(action, Some(&id), &res));
+                if let Err(err) = res {
+                    report_failure(&state, &id, chord, &err);
+                }
+            }
+            Event::Hold(hold) => {
+                let done = audio.call(move |audio| {
+                    let res = handle_hold(audio, hold, action)?;
+                    Some(res.map_err(|err| err.to_string()))
+                });
+                match done.await {
+                    Ok(None) => (),
+                    Ok(Some(res)) => {
+                        let event =
+                            events::Event::action(action, Some(&id), &res);
+                        events.send(event);
+                        if let Err(err) = res {
+                            report_failure(&state, &id, hold, &err);
+                        }
+                    }
+                    Err(err) => report_failure(&state, &id, hold, &err),
+                }
+            }
+        }
+    }
+    warn!("No panels left, stopped handling button presses");
 }
 
 pub fn setup_tracing() {
@@ -159,3 +719,41 @@ pub fn setup_tracing() {
         .try_init()
         .unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audiocontrol::Modes;
+    use button_protocol::Button::BottomMiddle;
+
+    #[tokio::test]
+    async fn panels_start_out_as_opened() {
+        let health = SharedHealth::default();
+        let (tx, _rx) = mpsc::channel(1);
+        let scenario = panel::Scenario { steps: Vec::new() };
+        let (panel, _outcome) = panel::Mock::new(scenario);
+        spawn_panel(PanelId::main(), panel, tx.clone(), &health);
+        let usart = Usart::new("/dev/no-such-panel");
+        spawn_panel(PanelId::from("door"), usart, tx, &health);
+
+        let health = health.lock().unwrap();
+        assert!(health.panels["main"].connected);
+        assert!(!health.panels["door"].connected);
+    }
+
+    #[test]
+    fn snooze_press_reaches_data_server_without_alarm() {
+        let keymap = Keymap::shipped(&Modes::default()).unwrap();
+        let main = PanelId::main();
+        let press = ButtonPress::Long(BottomMiddle);
+        let music = AudioMode::from("Music");
+        let action = keymap.press_action(&main, &music, press);
+        assert_eq!(action, Action::Snooze);
+
+        let event = Event::Press(press);
+        let idle = unless_snoozing(action, event, false, &keymap);
+        assert_eq!(idle, Action::SendToDataServer);
+        let ringing = unless_snoozing(action, event, true, &keymap);
+        assert_eq!(ringing, Action::Snooze);
+    }
+}
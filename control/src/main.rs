@@ -1,7 +1,8 @@
 use clap::Parser;
-use color_eyre::eyre::Context;
+use color_eyre::eyre::{eyre, Context};
 use color_eyre::Result;
 
+use control::audiocontrol::{export, import, migrate};
 use control::panel;
 
 #[tokio::main]
@@ -10,13 +11,72 @@ async fn main() -> Result<()> {
     control::setup_tracing();
     let args = control::Args::parse();
 
+    if let Some(control::SubCommand::Status { config, json }) = &args.command {
+        return control::status::print(config.as_deref(), *json).await;
+    }
+
+    if let Some(control::SubCommand::Alarm { command, config }) = args.command
+    {
+        return control::alarm::cli(config.as_deref(), command).await;
+    }
+
+    if let Some(control::SubCommand::Migrate { config, dry_run }) =
+        &args.command
+    {
+        let config = control::config::Config::load(config.as_deref())?;
+        let report = migrate(&config.database.path, &config.modes, *dry_run)
+            .map_err(|err| eyre!(err))?;
+        println!("{report}");
+        return Ok(());
+    }
+
+    if let Some(control::SubCommand::Export { file, config }) = &args.command {
+        let config = control::config::Config::load(config.as_deref())?;
+        let json = export(&config.database.path, &config.modes)
+            .map_err(|err| eyre!(err))?;
+        std::fs::write(file, json)
+            .wrap_err_with(|| format!("Could not write {}", file.display()))?;
+        println!("Exported to {}", file.display());
+        return Ok(());
+    }
+
+    if let Some(control::SubCommand::Import { file, config }) = &args.command {
+        let config = control::config::Config::load(config.as_deref())?;
+        let json = std::fs::read_to_string(file)
+            .wrap_err_with(|| format!("Could not read {}", file.display()))?;
+        let summary = import(&config.database.path, &config.modes, &json)
+            .map_err(|err| eyre!(err))?;
+        println!("{summary}");
+        return Ok(());
+    }
+
+    if let Some(control::SubCommand::Replay {
+        log,
+        ip,
+        panel: id,
+        speedup,
+        config,
+        keymap,
+        record,
+    }) = args.command
+    {
+        let id = panel::PanelId::from(id.as_str());
+        let panel = panel::Replay::load(&log, &id, speedup)?;
+        let args = control::Args {
+            ip,
+            config,
+            keymap,
+            record,
+            ..control::Args::default()
+        };
+        return control::run(id, panel, args).await;
+    }
+
     if args.setup {
         panel::setup_udev_access().wrap_err("Could not set up udev rules")?;
         return Ok(());
     }
 
-    let panel = panel::Usart::try_connect(&args.tty)
-        .wrap_err("Could not connect to Panel")?;
-
-    control::run(panel, args).await
+    let panel = panel::Usart::new(&args.tty);
+    control::run(panel::PanelId::main(), panel, args).await
 }
@@ -1,119 +1,255 @@
 #![allow(clippy::missing_panics_doc)]
 
-use core::time;
+use std::fmt;
 use std::io;
-use std::path::Path;
-use std::thread;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use async_trait::async_trait;
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
 use color_eyre::{eyre::eyre, Help, Result};
-use futures::stream::StreamExt;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
 use tokio_serial::{SerialPortBuilderExt, SerialStream};
 use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::{debug, info, warn};
 
-use button_protocol::{Button, ButtonPress};
+use button_protocol::{frame, Command, Event};
 
-struct LineCodec;
+pub mod keyboard;
+pub mod mock;
+pub mod network;
+pub mod replay;
+pub use keyboard::Keyboard;
+pub use mock::{Mock, Outcome, Scenario};
+pub use network::Network;
+pub use replay::Replay;
 
-impl Decoder for LineCodec {
-    type Item = u8;
+/// Decodes the next frame `parse` accepts, dropping anything corrupt or
+/// rejected in between.
+fn decode_frame<T>(
+    src: &mut BytesMut,
+    parse: impl Fn(&frame::Frame) -> Result<T, &'static str>,
+) -> Option<T> {
+    loop {
+        match frame::decode(src) {
+            Ok((frame, len)) => {
+                let item = parse(&frame);
+                src.advance(len);
+                match item {
+                    Ok(item) => return Some(item),
+                    Err(err) => warn!("Ignoring invalid frame: {err}"),
+                }
+            }
+            Err(frame::Error::Incomplete) => return None,
+            Err(err) => {
+                let skip = frame::resync(src);
+                warn!("Dropping {skip} corrupt byte(s) from panel: {err:?}");
+                src.advance(skip);
+            }
+        }
+    }
+}
+
+/// Decodes framed [`Event`]s, dropping anything corrupt in between.
+struct FrameCodec;
+
+impl Decoder for FrameCodec {
+    type Item = Event;
     type Error = io::Error;
 
     fn decode(
         &mut self,
         src: &mut BytesMut,
     ) -> Result<Option<Self::Item>, Self::Error> {
-        let newline = src.as_ref().iter().position(|b| *b == b'\n');
-        if let Some(n) = newline {
-            let line = src.split_to(n + 1);
-            return Ok(Some(line[0]));
-        }
-        Ok(None)
+        Ok(decode_frame(src, Event::from_frame))
     }
 }
 
-impl Encoder<String> for LineCodec {
+impl Encoder<Command> for FrameCodec {
     type Error = io::Error;
 
     fn encode(
         &mut self,
-        _item: String,
-        _dst: &mut BytesMut,
+        item: Command,
+        dst: &mut BytesMut,
     ) -> Result<(), Self::Error> {
+        let mut buf = [0; frame::MAX_FRAME_LEN];
+        let len = item.to_frame(&mut buf);
+        dst.extend_from_slice(&buf[..len]);
         Ok(())
     }
 }
 
+/// Name of a panel, the keymap can bind buttons per panel
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct PanelId(String);
+
+impl PanelId {
+    /// The panel passed on the command line
+    pub fn main() -> Self {
+        Self::from("main")
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for PanelId {
+    fn from(name: &str) -> Self {
+        Self(name.to_owned())
+    }
+}
+
+impl fmt::Display for PanelId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecvError {
+    /// The panel is unreachable, [`Panel::reconnect`] waits for it to return
+    Offline(String),
+    /// The panel will not send anything anymore
+    Closed(&'static str),
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvError::Offline(reason) => write!(f, "panel offline: {reason}"),
+            RecvError::Closed(reason) => write!(f, "panel closed: {reason}"),
+        }
+    }
+}
+
 #[async_trait]
 pub trait Panel {
-    async fn recv(&mut self) -> Result<ButtonPress, &'static str>;
+    async fn recv(&mut self) -> Result<Event, RecvError>;
+    async fn send(&mut self, command: Command) -> Result<()>;
+
+    /// Whether the panel can be reached now, for example its tty opened
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    /// Wait for the panel to come back after `recv` reported it offline.
+    /// Panels that can not come back report that they are closed.
+    async fn reconnect(&mut self) -> Result<(), RecvError> {
+        Err(RecvError::Closed("panel can not reconnect"))
+    }
+}
+
+/// Delay between reconnect attempts, doubles with every failed attempt
+#[derive(Debug)]
+struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    const MIN: Duration = Duration::from_millis(250);
+    const MAX: Duration = Duration::from_secs(10);
+
+    fn new() -> Self {
+        Self { delay: Self::MIN }
+    }
+
+    fn next(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (delay * 2).min(Self::MAX);
+        delay
+    }
 }
 
+/// Panel connected over usb serial. When the cable is unplugged the panel
+/// goes offline until the tty shows up again. Pass a stable path, such as
+/// one in `/dev/serial/by-id/`, as `/dev/ttyUSB<n>` can get renumbered when
+/// the panel is plugged back in.
 pub struct Usart {
-    reader: Framed<SerialStream, LineCodec>,
+    tty_path: PathBuf,
+    reader: Option<Framed<SerialStream, FrameCodec>>,
+}
+
+fn open(tty_path: &Path) -> Result<Framed<SerialStream, FrameCodec>> {
+    let tty_path = tty_path.to_string_lossy();
+    let mut port = tokio_serial::new(tty_path, 9600).open_native_async()?;
+
+    #[cfg(unix)]
+    port.set_exclusive(false)?;
+
+    Ok(FrameCodec.framed(port))
 }
 
 impl Usart {
     pub fn try_connect(tty_path: &str) -> Result<Self> {
-        let mut port = tokio_serial::new(tty_path, 9600).open_native_async()?;
-
-        #[cfg(unix)]
-        port.set_exclusive(false)
-            .expect("Unable to set serial port exclusive to false");
+        let tty_path = PathBuf::from(tty_path);
+        let reader = Some(open(&tty_path)?);
+        Ok(Self { tty_path, reader })
+    }
 
-        let reader = LineCodec.framed(port);
+    /// Starts offline if the panel is not there yet, `reconnect` picks it up
+    /// once it is plugged in
+    pub fn new(tty_path: impl Into<PathBuf>) -> Self {
+        let tty_path = tty_path.into();
+        let reader = open(&tty_path)
+            .inspect_err(|err| warn!("Panel is not connected yet: {err}"))
+            .ok();
+        Self { tty_path, reader }
+    }
 
-        Ok(Self { reader })
+    fn disconnected(&mut self, reason: String) -> RecvError {
+        self.reader = None;
+        RecvError::Offline(reason)
     }
 }
 
 #[async_trait]
 impl Panel for Usart {
-    async fn recv(&mut self) -> Result<ButtonPress, &'static str> {
-        let line = self
-            .reader
-            .next()
-            .await
-            .expect("Serial disconnected")
-            .unwrap();
-
-        ButtonPress::deserialize(line)
+    fn is_connected(&self) -> bool {
+        self.reader.is_some()
     }
-}
 
-pub struct Mock {
-    actions: Vec<ButtonPress>,
-    sleep_s: u64,
-}
+    async fn recv(&mut self) -> Result<Event, RecvError> {
+        let Some(reader) = &mut self.reader else {
+            return Err(RecvError::Offline("not connected".to_owned()));
+        };
 
-impl Mock {
-    pub fn full_test() -> Result<Self> {
-        let mut actions = vec![
-            ButtonPress::Short(Button::BottomMiddle), //evening light
-            ButtonPress::Short(Button::TopMiddle),    //play (Music)
-            ButtonPress::Long(Button::TopRight),      //next playlist (Music)
-            ButtonPress::Long(Button::TopRight),      //next playlist (Music)
-            ButtonPress::Long(Button::TopLeft),       //prev playlist (Music)
-            ButtonPress::Long(Button::TopLeft),       //prev playlist (Music)
-        ];
-        actions.reverse();
-        Ok(Mock { actions, sleep_s: 2 })
+        match reader.next().await {
+            Some(Ok(event)) => Ok(event),
+            Some(Err(err)) => Err(self.disconnected(format!("{err}"))),
+            None => Err(self.disconnected("serial disconnected".to_owned())),
+        }
     }
 
-    pub fn bottom_left_only() -> Result<Self> {
-        let actions = vec![
-            ButtonPress::Short(Button::BottomLeft),
-        ];
-        Ok(Mock { actions, sleep_s: 0 })
+    async fn send(&mut self, command: Command) -> Result<()> {
+        let Some(reader) = &mut self.reader else {
+            return Err(eyre!("Panel is offline"));
+        };
+        reader.send(command).await?;
+        Ok(())
     }
-}
 
-#[async_trait]
-impl Panel for Mock {
-    async fn recv(&mut self) -> Result<ButtonPress, &'static str> {
-        thread::sleep(time::Duration::from_secs(self.sleep_s));
-        self.actions.pop().ok_or("No more actions in MockPanel")
+    async fn reconnect(&mut self) -> Result<(), RecvError> {
+        let mut backoff = Backoff::new();
+        loop {
+            tokio::time::sleep(backoff.next()).await;
+            // the tty disappears while unplugged
+            if !self.tty_path.exists() {
+                continue;
+            }
+            match open(&self.tty_path) {
+                Ok(reader) => {
+                    info!("Reconnected to panel at {:?}", self.tty_path);
+                    self.reader = Some(reader);
+                    return Ok(());
+                }
+                Err(err) => debug!("Could not reopen panel: {err}"),
+            }
+        }
     }
 }
 
@@ -130,3 +266,63 @@ pub fn setup_udev_access() -> Result<()> {
     std::fs::write(path, rule)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use button_protocol::{Button, ButtonPress, Hold};
+
+    #[test]
+    fn codec_recovers_from_garbage() {
+        let first = Event::Press(ButtonPress::Short(Button::TopLeft));
+        let second = Event::Hold(Hold::Repeat(Button::BottomRight, 3));
+
+        let mut buf = [0; frame::MAX_FRAME_LEN];
+        let mut src = BytesMut::new();
+        src.extend_from_slice(b"\n\x07");
+        let len = first.to_frame(&mut buf);
+        src.extend_from_slice(&buf[..len]);
+        // same frame with a corrupted payload
+        buf[frame::HEADER_LEN] ^= 0b100;
+        src.extend_from_slice(&buf[..len]);
+        src.extend_from_slice(&[frame::START]);
+        let len = second.to_frame(&mut buf);
+        src.extend_from_slice(&buf[..len]);
+
+        let mut codec = FrameCodec;
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(first));
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(second));
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new();
+        assert_eq!(backoff.next(), Backoff::MIN);
+        assert_eq!(backoff.next(), Backoff::MIN * 2);
+        let delays: Vec<_> = std::iter::repeat_with(|| backoff.next())
+            .take(10)
+            .collect();
+        assert_eq!(delays.last(), Some(&Backoff::MAX));
+    }
+
+    #[tokio::test]
+    async fn missing_panel_is_offline() {
+        let mut panel = Usart::new("/dev/does-not-exist");
+        assert!(matches!(panel.recv().await, Err(RecvError::Offline(_))));
+        let command = Command::Ack(ButtonPress::Short(Button::TopLeft));
+        assert!(panel.send(command).await.is_err());
+    }
+
+    #[test]
+    fn codec_encodes_commands() {
+        let command = Command::Ack(ButtonPress::Long(Button::TopMiddle));
+        let mut dst = BytesMut::new();
+        FrameCodec.encode(command, &mut dst).unwrap();
+
+        let (frame, len) = frame::decode(&dst).unwrap();
+        assert_eq!(len, dst.len());
+        assert_eq!(Command::from_frame(&frame), Ok(command));
+    }
+}
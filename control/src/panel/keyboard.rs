@@ -0,0 +1,483 @@
+//! A keyboard as a panel, for example a usb numpad or a media key remote.
+//! Keys are read straight from the linux input device (evdev) and mapped to
+//! buttons with [`Keys`]. Presses are told apart by how long a key was down,
+//! using the same [`detect`](button_protocol::detect) thresholds as the
+//! panel firmware, so short, long, double and triple presses, holds and
+//! chords all work.
+//!
+//! The device is not grabbed, other programs such as the console still see
+//! the keys. Pass a stable path, such as one in `/dev/input/by-id/`, as the
+//! `/dev/input/event<n>` numbering changes when devices are plugged in.
+
+use std::collections::{HashMap, VecDeque};
+use std::ffi::c_long;
+use std::io;
+use std::mem::size_of;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use color_eyre::Result;
+use futures::StreamExt;
+use serde::Deserialize;
+use tokio::fs::File;
+use tokio::io::AsyncRead;
+use tokio::time::{timeout_at, Instant};
+use tokio_util::codec::{Decoder, FramedRead};
+use tracing::{debug, info, warn};
+
+use button_protocol::detect::PanelDetector;
+use button_protocol::{Button, Chord, Command, Event};
+
+use super::{Backoff, Panel, RecvError};
+use crate::keymap::parse_button;
+
+const EV_KEY: u16 = 1;
+const KEY_UP: i32 = 0;
+const KEY_DOWN: i32 = 1;
+/// A `struct input_event`: a timeval of two longs, then type, code and value
+const EVENT_LEN: usize = 2 * size_of::<c_long>() + 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct InputEvent {
+    /// when the kernel saw it
+    millis: u64,
+    kind: u16,
+    code: u16,
+    value: i32,
+}
+
+impl InputEvent {
+    fn parse(raw: &[u8]) -> Self {
+        const LONG: usize = size_of::<c_long>();
+        let long = |i: usize| {
+            let bytes = raw[i * LONG..(i + 1) * LONG].try_into();
+            u64::try_from(c_long::from_ne_bytes(bytes.expect("long")))
+                .unwrap_or(0)
+        };
+        let rest = &raw[2 * LONG..];
+        Self {
+            millis: long(0) * 1000 + long(1) / 1000,
+            kind: u16::from_ne_bytes([rest[0], rest[1]]),
+            code: u16::from_ne_bytes([rest[2], rest[3]]),
+            value: i32::from_ne_bytes([rest[4], rest[5], rest[6], rest[7]]),
+        }
+    }
+}
+
+struct InputCodec;
+
+impl Decoder for InputCodec {
+    type Item = InputEvent;
+    type Error = io::Error;
+
+    fn decode(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < EVENT_LEN {
+            return Ok(None);
+        }
+        let raw = src.split_to(EVENT_LEN);
+        Ok(Some(InputEvent::parse(&raw)))
+    }
+}
+
+/// Linux key codes by name, see `linux/input-event-codes.h`. Keys missing
+/// here can be given by their code.
+const KEY_CODES: [(&str, u16); 38] = [
+    ("KEY_ESC", 1),
+    ("KEY_BACKSPACE", 14),
+    ("KEY_TAB", 15),
+    ("KEY_ENTER", 28),
+    ("KEY_KPASTERISK", 55),
+    ("KEY_SPACE", 57),
+    ("KEY_KP7", 71),
+    ("KEY_KP8", 72),
+    ("KEY_KP9", 73),
+    ("KEY_KPMINUS", 74),
+    ("KEY_KP4", 75),
+    ("KEY_KP5", 76),
+    ("KEY_KP6", 77),
+    ("KEY_KPPLUS", 78),
+    ("KEY_KP1", 79),
+    ("KEY_KP2", 80),
+    ("KEY_KP3", 81),
+    ("KEY_KP0", 82),
+    ("KEY_KPDOT", 83),
+    ("KEY_KPENTER", 96),
+    ("KEY_KPSLASH", 98),
+    ("KEY_HOME", 102),
+    ("KEY_UP", 103),
+    ("KEY_PAGEUP", 104),
+    ("KEY_LEFT", 105),
+    ("KEY_RIGHT", 106),
+    ("KEY_END", 107),
+    ("KEY_DOWN", 108),
+    ("KEY_PAGEDOWN", 109),
+    ("KEY_MUTE", 113),
+    ("KEY_VOLUMEDOWN", 114),
+    ("KEY_VOLUMEUP", 115),
+    ("KEY_PAUSE", 119),
+    ("KEY_NEXTSONG", 163),
+    ("KEY_PLAYPAUSE", 164),
+    ("KEY_PREVIOUSSONG", 165),
+    ("KEY_STOPCD", 166),
+    ("KEY_PLAY", 207),
+];
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Key {
+    Code(u16),
+    Name(String),
+}
+
+/// Which key is which button. In the config a table of `button = key`,
+/// where the key is a name such as `"KEY_KP7"` or a key code. Buttons that
+/// are left out can not be pressed.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "HashMap<String, Key>")]
+pub struct Keys(HashMap<u16, Button>);
+
+impl Keys {
+    fn button(&self, code: u16) -> Option<Button> {
+        self.0.get(&code).copied()
+    }
+}
+
+/// The numpad: 7, 8 and 9 are the top row and 4, 5 and 6 the bottom row
+impl Default for Keys {
+    fn default() -> Self {
+        Self(HashMap::from([
+            (71, Button::TopLeft),
+            (72, Button::TopMiddle),
+            (73, Button::TopRight),
+            (75, Button::BottomLeft),
+            (76, Button::BottomMiddle),
+            (77, Button::BottomRight),
+        ]))
+    }
+}
+
+impl TryFrom<HashMap<String, Key>> for Keys {
+    type Error = String;
+
+    fn try_from(table: HashMap<String, Key>) -> Result<Self, Self::Error> {
+        let mut keys = HashMap::new();
+        for (button, key) in table {
+            let button = parse_button(&button).map_err(|e| e.to_string())?;
+            let code = match key {
+                Key::Code(code) => code,
+                Key::Name(name) => KEY_CODES
+                    .iter()
+                    .find(|(known, _)| *known == name)
+                    .map(|(_, code)| *code)
+                    .ok_or_else(|| {
+                        format!("Unknown key: `{name}`, use its key code")
+                    })?,
+            };
+            if keys.insert(code, button).is_some() {
+                return Err(format!("Key {code} is bound to two buttons"));
+            }
+        }
+        Ok(Self(keys))
+    }
+}
+
+/// Panel reading key presses from an input device, or from a recording of
+/// one for [`Keyboard::from_reader`].
+pub struct Keyboard {
+    /// device to reopen when it comes back, `None` for a recording
+    path: Option<PathBuf>,
+    device: Option<FramedRead<Input, InputCodec>>,
+    keys: Keys,
+    detector: PanelDetector,
+    down: Chord,
+    /// time of the last key as stamped by the kernel and when we read it
+    clock: Option<(u64, Instant)>,
+    ready: VecDeque<Event>,
+}
+
+type Input = Box<dyn AsyncRead + Unpin + Send>;
+
+impl Keyboard {
+    /// Starts offline if the device is not there yet, `reconnect` picks it
+    /// up once it is plugged in
+    pub async fn new(path: impl Into<PathBuf>, keys: Keys) -> Self {
+        let path = path.into();
+        let device = File::open(&path)
+            .await
+            .inspect_err(|err| warn!("Keyboard is not connected yet: {err}"))
+            .ok()
+            .map(|file| Box::new(file) as Input);
+        let mut keyboard = Self::from_input(device, keys);
+        keyboard.path = Some(path);
+        keyboard
+    }
+
+    /// Plays back raw input events, such as a copy of what
+    /// `/dev/input/event<n>` gave. Presses are timed by the recorded
+    /// timestamps, they come out as fast as they can be read.
+    pub fn from_reader(
+        reader: impl AsyncRead + Unpin + Send + 'static,
+        keys: Keys,
+    ) -> Self {
+        Self::from_input(Some(Box::new(reader)), keys)
+    }
+
+    fn from_input(input: Option<Input>, keys: Keys) -> Self {
+        Self {
+            path: None,
+            device: input.map(|input| FramedRead::new(input, InputCodec)),
+            keys,
+            detector: PanelDetector::new(),
+            down: Chord::EMPTY,
+            clock: None,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Queue everything the detector has due at `now`
+    fn poll_until(&mut self, now: u64) {
+        while let Some(deadline) =
+            self.detector.deadline().filter(|deadline| *deadline <= now)
+        {
+            let Some(event) = self.detector.poll(deadline) else {
+                break;
+            };
+            self.ready.push_back(event);
+        }
+    }
+
+    fn handle(&mut self, input: InputEvent) {
+        if input.kind != EV_KEY {
+            return;
+        }
+        let Some(button) = self.keys.button(input.code) else {
+            debug!("Ignoring unmapped key {}", input.code);
+            return;
+        };
+        // the kernel uses the wall clock, which may jump back
+        let last = self.clock.map_or(0, |(millis, _)| millis);
+        let now = input.millis.max(last);
+        self.clock = Some((now, Instant::now()));
+        self.poll_until(now);
+
+        // key repeats (2) and keys already down when we started are ignored
+        let event = match input.value {
+            KEY_DOWN if !self.down.contains(button) => {
+                self.down = self.down.with(button);
+                self.detector.pressed(button, now)
+            }
+            KEY_UP if self.down.contains(button) => {
+                self.down = self.down.without(button);
+                self.detector.released(button, now)
+            }
+            _ => None,
+        };
+        self.ready.extend(event);
+    }
+
+    /// Finish the presses in progress, the keys will not come up anymore
+    fn disconnected(&mut self, reason: String) -> RecvError {
+        self.device = None;
+        let now = self.clock.map_or(0, |(millis, _)| millis);
+        for button in self.down.buttons() {
+            self.ready.extend(self.detector.released(button, now));
+        }
+        self.down = Chord::EMPTY;
+        self.poll_until(u64::MAX);
+        RecvError::Offline(reason)
+    }
+}
+
+#[async_trait]
+impl Panel for Keyboard {
+    fn is_connected(&self) -> bool {
+        self.device.is_some()
+    }
+
+    async fn recv(&mut self) -> Result<Event, RecvError> {
+        loop {
+            if let Some(event) = self.ready.pop_front() {
+                return Ok(event);
+            }
+            let Some(device) = &mut self.device else {
+                return Err(RecvError::Offline("not connected".to_owned()));
+            };
+
+            let due = self.detector.deadline().zip(self.clock);
+            let input = match due {
+                Some((deadline, (millis, read_at))) => {
+                    let wait = deadline.saturating_sub(millis);
+                    let at = read_at + Duration::from_millis(wait);
+                    match timeout_at(at, device.next()).await {
+                        Ok(input) => input,
+                        Err(_elapsed) => {
+                            self.poll_until(deadline);
+                            continue;
+                        }
+                    }
+                }
+                None => device.next().await,
+            };
+
+            let err = match input {
+                Some(Ok(input)) => {
+                    self.handle(input);
+                    continue;
+                }
+                Some(Err(err)) => self.disconnected(err.to_string()),
+                None => self.disconnected("input device closed".to_owned()),
+            };
+            if let Some(event) = self.ready.pop_front() {
+                return Ok(event);
+            }
+            return Err(err);
+        }
+    }
+
+    async fn send(&mut self, command: Command) -> Result<()> {
+        debug!("Keyboard has no leds, ignoring: {command:?}");
+        Ok(())
+    }
+
+    async fn reconnect(&mut self) -> Result<(), RecvError> {
+        let Some(path) = self.path.clone() else {
+            return Err(RecvError::Closed("end of the recording"));
+        };
+        let mut backoff = Backoff::new();
+        loop {
+            tokio::time::sleep(backoff.next()).await;
+            match File::open(&path).await {
+                Ok(file) => {
+                    info!("Reconnected to keyboard at {path:?}");
+                    let input = Box::new(file) as Input;
+                    self.device = Some(FramedRead::new(input, InputCodec));
+                    self.clock = None;
+                    return Ok(());
+                }
+                Err(err) => debug!("Could not reopen keyboard: {err}"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use button_protocol::{ButtonPress, Hold};
+
+    const EV_SYN: u16 = 0;
+
+    /// Raw input events as the kernel would give them, the time in ms
+    fn recording(events: &[(u64, u16, i32)]) -> Vec<u8> {
+        let mut raw = Vec::new();
+        let mut push = |millis: u64, kind: u16, code: u16, value: i32| {
+            let sec = c_long::try_from(millis / 1000).unwrap();
+            let usec = c_long::try_from(millis % 1000 * 1000).unwrap();
+            raw.extend_from_slice(&sec.to_ne_bytes());
+            raw.extend_from_slice(&usec.to_ne_bytes());
+            raw.extend_from_slice(&kind.to_ne_bytes());
+            raw.extend_from_slice(&code.to_ne_bytes());
+            raw.extend_from_slice(&value.to_ne_bytes());
+        };
+        // starting at a realistic wall clock time
+        let start = 1_700_000_000_000;
+        for &(millis, code, value) in events {
+            push(start + millis, EV_KEY, code, value);
+            push(start + millis, EV_SYN, 0, 0);
+        }
+        raw
+    }
+
+    async fn play(events: &[(u64, u16, i32)]) -> Vec<Event> {
+        let raw = io::Cursor::new(recording(events));
+        let mut keyboard = Keyboard::from_reader(raw, Keys::default());
+        let mut got = Vec::new();
+        loop {
+            match keyboard.recv().await {
+                Ok(event) => got.push(event),
+                Err(RecvError::Offline(_)) => break,
+                Err(err) => panic!("unexpected {err}"),
+            }
+        }
+        let reconnect = keyboard.reconnect().await;
+        assert!(matches!(reconnect, Err(RecvError::Closed(_))));
+        got
+    }
+
+    #[tokio::test]
+    async fn presses_are_timed_like_the_firmware() {
+        let events = play(&[
+            // short, with key repeat noise and an unmapped key
+            (0, 71, KEY_DOWN),
+            (30, 30, KEY_DOWN),
+            (100, 71, 2),
+            (120, 71, KEY_UP),
+            (130, 30, KEY_UP),
+            // long
+            (1000, 73, KEY_DOWN),
+            (1600, 73, KEY_UP),
+            // double
+            (3000, 76, KEY_DOWN),
+            (3100, 76, KEY_UP),
+            (3200, 76, KEY_DOWN),
+            (3300, 76, KEY_UP),
+            // chord
+            (5000, 75, KEY_DOWN),
+            (5020, 77, KEY_DOWN),
+            (5200, 75, KEY_UP),
+            (5210, 77, KEY_UP),
+            // short, only reported once the recording ended
+            (6000, 72, KEY_DOWN),
+            (6100, 72, KEY_UP),
+        ])
+        .await;
+
+        let chord = Chord::of(&[Button::BottomLeft, Button::BottomRight]);
+        assert_eq!(
+            events,
+            [
+                Event::Press(ButtonPress::Short(Button::TopLeft)),
+                Event::Press(ButtonPress::Long(Button::TopRight)),
+                Event::Press(ButtonPress::Double(Button::BottomMiddle)),
+                Event::Chord(chord),
+                Event::Press(ButtonPress::Short(Button::TopMiddle)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn held_key_repeats_until_released() {
+        let events = play(&[(0, 76, KEY_DOWN), (2600, 76, KEY_UP)]).await;
+        let button = Button::BottomMiddle;
+        assert_eq!(
+            events,
+            [
+                Event::Hold(Hold::Start(button)),
+                Event::Hold(Hold::Repeat(button, 1)),
+                Event::Hold(Hold::Repeat(button, 2)),
+                Event::Hold(Hold::Release(button)),
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_by_name_or_code() {
+        let keys: Keys = toml::from_str(
+            "top_left = \"KEY_PLAYPAUSE\"\nbottom_right = 30",
+        )
+        .unwrap();
+        assert_eq!(keys.button(164), Some(Button::TopLeft));
+        assert_eq!(keys.button(30), Some(Button::BottomRight));
+        assert_eq!(keys.button(71), None);
+
+        assert!(toml::from_str::<Keys>("top_left = \"KEY_NOPE\"").is_err());
+        assert!(toml::from_str::<Keys>("top_lft = 30").is_err());
+        let twice = "top_left = 30\ntop_right = 30";
+        assert!(toml::from_str::<Keys>(twice).is_err());
+    }
+}
@@ -0,0 +1,331 @@
+//! A panel that plays a [`Scenario`]: timed presses and the commands the
+//! panel should get back. Scenarios are toml files with a list of steps,
+//! see `scenarios/` for examples:
+//!
+//! ```toml
+//! [[steps]]
+//! press = "long_top_right"
+//! [[steps]]
+//! expect = "ack"
+//! [[steps]]
+//! wait_ms = 500
+//! ```
+//!
+//! Each step does exactly one of:
+//!
+//!  - `press`: send a press, as `<press>_<button>` like in the keymap
+//!  - `chord`: send a chord, as `<button>+<button>`
+//!  - `wait_ms`: wait this long before the next step
+//!  - `expect`: wait for the panel to be sent a command, one of `"ack"`,
+//!    `"error"` or `{ mode_color = [r, g, b] }` for the led showing the mode.
+//!    Commands before it are skipped. Fails after `within_ms`, which
+//!    defaults to a second.
+//!
+//! How it went is reported through the [`Outcome`] once all steps are done
+//! or an expectation failed.
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, error, info};
+
+use button_protocol::{Button, Color, Command, Event};
+
+use super::{Panel, RecvError};
+use crate::keymap::{parse_chord, parse_press};
+
+const DEFAULT_WITHIN: Duration = Duration::from_secs(1);
+
+/// A command the panel should get
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Expect {
+    /// a long press was acknowledged
+    Ack,
+    /// handling a press failed
+    Error,
+    /// the top middle led shows the mode with this color
+    ModeColor([u8; 3]),
+}
+
+impl Expect {
+    fn matches(self, command: &Command) -> bool {
+        match (self, command) {
+            (Expect::Ack, Command::Ack(_)) => true,
+            (Expect::Error, Command::Blink(_, color, _)) => {
+                *color == Color::RED
+            }
+            (Expect::ModeColor([r, g, b]), Command::SetLed(button, color)) => {
+                *button == Some(Button::TopMiddle)
+                    && *color == Color::rgb(r, g, b)
+            }
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    Send(Event),
+    Wait(Duration),
+    Expect { expect: Expect, within: Duration },
+}
+
+/// A step as written in the scenario file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawStep {
+    press: Option<String>,
+    chord: Option<String>,
+    wait_ms: Option<u64>,
+    expect: Option<Expect>,
+    within_ms: Option<u64>,
+}
+
+impl RawStep {
+    fn parse(self) -> Result<Step> {
+        if self.within_ms.is_some() && self.expect.is_none() {
+            return Err(eyre!("within_ms only goes with expect"));
+        }
+        match (self.press, self.chord, self.wait_ms, self.expect) {
+            (Some(press), None, None, None) => {
+                Ok(Step::Send(Event::Press(parse_press(&press)?)))
+            }
+            (None, Some(chord), None, None) => {
+                Ok(Step::Send(Event::Chord(parse_chord(&chord)?)))
+            }
+            (None, None, Some(ms), None) => {
+                Ok(Step::Wait(Duration::from_millis(ms)))
+            }
+            (None, None, None, Some(expect)) => Ok(Step::Expect {
+                expect,
+                within: self
+                    .within_ms
+                    .map_or(DEFAULT_WITHIN, Duration::from_millis),
+            }),
+            _ => Err(eyre!(
+                "A step needs exactly one of press, chord, wait_ms or expect"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawScenario {
+    steps: Vec<RawStep>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scenario {
+    pub steps: Vec<Step>,
+}
+
+impl Scenario {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path).wrap_err_with(|| {
+            format!("Could not read scenario: {}", path.display())
+        })?;
+        Self::parse(&content)
+            .wrap_err_with(|| format!("Invalid scenario: {}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let raw: RawScenario =
+            toml::from_str(content).wrap_err("Could not parse scenario")?;
+        let steps = raw
+            .steps
+            .into_iter()
+            .enumerate()
+            .map(|(i, step)| {
+                step.parse().wrap_err_with(|| format!("Invalid step {}", i + 1))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { steps })
+    }
+}
+
+/// How the scenario went, resolves once the mock is done
+pub struct Outcome(oneshot::Receiver<Result<(), String>>);
+
+impl Outcome {
+    pub async fn wait(self) -> Result<()> {
+        match self.0.await {
+            Ok(res) => res.map_err(|err| eyre!(err)),
+            Err(_) => Err(eyre!("Mock panel dropped before it finished")),
+        }
+    }
+}
+
+pub struct Mock {
+    steps: VecDeque<Step>,
+    /// commands sent to the panel that no expectation used yet
+    received: VecDeque<Command>,
+    /// end of the current wait or expectation, kept as `recv` may be
+    /// cancelled while waiting
+    until: Option<Instant>,
+    outcome: Option<oneshot::Sender<Result<(), String>>>,
+}
+
+impl Mock {
+    pub fn new(scenario: Scenario) -> (Self, Outcome) {
+        let (tx, rx) = oneshot::channel();
+        let mock = Mock {
+            steps: scenario.steps.into(),
+            received: VecDeque::new(),
+            until: None,
+            outcome: Some(tx),
+        };
+        (mock, Outcome(rx))
+    }
+
+    pub fn load(path: &Path) -> Result<(Self, Outcome)> {
+        Ok(Self::new(Scenario::load(path)?))
+    }
+
+    fn finish(&mut self, res: Result<(), String>) {
+        match &res {
+            Ok(()) => info!("Scenario finished"),
+            Err(err) => error!("Scenario failed: {err}"),
+        }
+        if let Some(outcome) = self.outcome.take() {
+            let _ = outcome.send(res);
+        }
+    }
+
+    fn deadline(&mut self, duration: Duration) -> Instant {
+        *self.until.get_or_insert_with(|| Instant::now() + duration)
+    }
+
+    fn next_step(&mut self) {
+        self.steps.pop_front();
+        self.until = None;
+    }
+}
+
+#[async_trait]
+impl Panel for Mock {
+    async fn recv(&mut self) -> Result<Event, RecvError> {
+        loop {
+            let Some(step) = self.steps.front().cloned() else {
+                self.finish(Ok(()));
+                return Err(RecvError::Closed("scenario finished"));
+            };
+
+            match step {
+                Step::Send(event) => {
+                    self.next_step();
+                    return Ok(event);
+                }
+                Step::Wait(duration) => {
+                    sleep_until(self.deadline(duration)).await;
+                    self.next_step();
+                }
+                Step::Expect { expect, within } => {
+                    let until = self.deadline(within);
+                    let found =
+                        self.received.iter().position(|c| expect.matches(c));
+                    if let Some(i) = found {
+                        self.received.drain(..=i);
+                        self.next_step();
+                    } else if Instant::now() >= until {
+                        let seen: Vec<_> = self.received.drain(..).collect();
+                        self.finish(Err(format!(
+                            "expected {expect:?} within {within:?}, \
+                             got: {seen:?}"
+                        )));
+                        self.steps.clear();
+                        return Err(RecvError::Closed("scenario failed"));
+                    } else {
+                        // a command coming in cancels this
+                        sleep_until(until).await;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send(&mut self, command: Command) -> Result<()> {
+        debug!("MockPanel got command: {command:?}");
+        self.received.push_back(command);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use button_protocol::{Blink, ButtonPress};
+
+    #[test]
+    fn scenario_steps() {
+        let scenario = Scenario::parse(
+            "[[steps]]\npress = \"long_top_right\"\n\
+             [[steps]]\nchord = \"bottom_left+bottom_right\"\n\
+             [[steps]]\nwait_ms = 200\n\
+             [[steps]]\nexpect = { mode_color = [0, 0, 255] }\n\
+             within_ms = 50",
+        )
+        .unwrap();
+        let long = ButtonPress::Long(Button::TopRight);
+        assert_eq!(scenario.steps[0], Step::Send(Event::Press(long)));
+        assert_eq!(scenario.steps[2], Step::Wait(Duration::from_millis(200)));
+        assert_eq!(
+            scenario.steps[3],
+            Step::Expect {
+                expect: Expect::ModeColor([0, 0, 255]),
+                within: Duration::from_millis(50),
+            }
+        );
+
+        let both = "[[steps]]\npress = \"short_top_left\"\nwait_ms = 5";
+        assert!(Scenario::parse(both).is_err());
+        let bad_press = "[[steps]]\npress = \"quick_top_left\"";
+        assert!(Scenario::parse(bad_press).is_err());
+    }
+
+    #[test]
+    fn shipped_scenarios_parse() {
+        let scenarios = [
+            include_str!("../../scenarios/full_test.toml"),
+            include_str!("../../scenarios/press_bottom_left.toml"),
+        ];
+        for scenario in scenarios {
+            Scenario::parse(scenario).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn expectations_are_checked() {
+        let scenario = Scenario::parse(
+            "[[steps]]\npress = \"long_top_left\"\n\
+             [[steps]]\nexpect = \"ack\"\n\
+             [[steps]]\nexpect = \"error\"\nwithin_ms = 20",
+        )
+        .unwrap();
+        let (mut mock, outcome) = Mock::new(scenario);
+
+        let long = ButtonPress::Long(Button::TopLeft);
+        assert_eq!(mock.recv().await, Ok(Event::Press(long)));
+        mock.send(Command::SetLed(None, Color::OFF)).await.unwrap();
+        mock.send(Command::Ack(long)).await.unwrap();
+        // no error blink comes
+        assert!(matches!(mock.recv().await, Err(RecvError::Closed(_))));
+        assert!(outcome.wait().await.is_err());
+
+        let scenario =
+            Scenario::parse("[[steps]]\nexpect = \"error\"").unwrap();
+        let (mut mock, outcome) = Mock::new(scenario);
+        let blink = Command::Blink(None, Color::RED, Blink::ERROR);
+        mock.send(blink).await.unwrap();
+        assert!(matches!(mock.recv().await, Err(RecvError::Closed(_))));
+        assert!(outcome.wait().await.is_ok());
+    }
+}
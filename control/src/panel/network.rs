@@ -0,0 +1,529 @@
+//! Panels that talk over the network instead of usb serial, for example a
+//! panel behind an esp bridge or a phone app. They send the same frames as a
+//! serial panel, over tcp or udp.
+//!
+//! The panel starts by sending a `Hello` with the protocol version and its
+//! id, the host answers with a `Welcome`. Over udp the panel should repeat the
+//! hello until it gets the welcome. A panel that speaks another protocol
+//! version is turned away with a warning naming the version. After that the
+//! panel sends a `Ping` at least every [`KEEPALIVE`], which the host answers
+//! with a `Pong`. When the host hears nothing for [`TIMEOUT`] the panel is
+//! offline until it says hello again.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::{Buf, BytesMut};
+use color_eyre::{eyre::eyre, Result};
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::{timeout, timeout_at, Instant};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tokio_util::udp::UdpFramed;
+use tracing::{debug, info, warn};
+
+use button_protocol::{frame, Command, Event, Hello};
+
+use super::{decode_frame, Panel, PanelId, RecvError};
+
+/// How often an idle panel should ping
+pub const KEEPALIVE: Duration = Duration::from_secs(5);
+/// A panel we did not hear from for this long is offline
+pub const TIMEOUT: Duration = Duration::from_secs(15);
+/// How long a new tcp connection gets to say hello
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FromRemote {
+    Hello { version: u8, id: PanelId },
+    Ping,
+    Event(Event),
+}
+
+impl FromRemote {
+    fn from_frame(frame: &frame::Frame) -> Result<Self, &'static str> {
+        match frame.kind {
+            frame::Kind::Hello => {
+                let hello = Hello::from_frame(frame)?;
+                Ok(FromRemote::Hello {
+                    version: hello.version,
+                    id: PanelId::from(hello.id),
+                })
+            }
+            frame::Kind::Ping => Ok(FromRemote::Ping),
+            _ => Event::from_frame(frame).map(FromRemote::Event),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ToRemote {
+    Welcome,
+    Pong,
+    Command(Command),
+}
+
+struct NetCodec;
+
+impl Decoder for NetCodec {
+    type Item = FromRemote;
+    type Error = io::Error;
+
+    fn decode(
+        &mut self,
+        src: &mut BytesMut,
+    ) -> Result<Option<Self::Item>, Self::Error> {
+        // other versions are corrupt frames, apart from the hello so the
+        // handshake can tell which version the panel speaks
+        if let Ok((version, frame, len)) = frame::decode_any_version(src) {
+            if version != frame::VERSION && frame.kind == frame::Kind::Hello {
+                let hello = FromRemote::from_frame(&frame);
+                src.advance(len);
+                match hello {
+                    Ok(hello) => return Ok(Some(hello)),
+                    Err(err) => warn!("Ignoring invalid hello: {err}"),
+                }
+            }
+        }
+        Ok(decode_frame(src, FromRemote::from_frame))
+    }
+}
+
+impl Encoder<ToRemote> for NetCodec {
+    type Error = io::Error;
+
+    fn encode(
+        &mut self,
+        item: ToRemote,
+        dst: &mut BytesMut,
+    ) -> Result<(), Self::Error> {
+        let mut buf = [0; frame::MAX_FRAME_LEN];
+        let len = match item {
+            ToRemote::Welcome => {
+                frame::encode(frame::Kind::Welcome, &[], &mut buf)
+            }
+            ToRemote::Pong => frame::encode(frame::Kind::Pong, &[], &mut buf),
+            ToRemote::Command(command) => Ok(command.to_frame(&mut buf)),
+        };
+        let len = len.expect("buffer fits the largest frame");
+        dst.extend_from_slice(&buf[..len]);
+        Ok(())
+    }
+}
+
+fn check_hello(
+    expected: &PanelId,
+    version: u8,
+    id: &PanelId,
+) -> Result<(), String> {
+    if version != frame::VERSION {
+        return Err(format!(
+            "panel speaks protocol version {version}, we speak {}",
+            frame::VERSION
+        ));
+    }
+    if id != expected {
+        return Err(format!("expected panel {expected}, got {id}"));
+    }
+    Ok(())
+}
+
+enum Link {
+    Tcp {
+        listener: TcpListener,
+        conn: Option<Framed<TcpStream, NetCodec>>,
+    },
+    Udp {
+        socket: UdpFramed<NetCodec>,
+        /// address of the panel, once it said hello
+        peer: Option<SocketAddr>,
+    },
+}
+
+/// Panel that connects to us over the network, only the panel with the
+/// expected id is accepted.
+pub struct Network {
+    id: PanelId,
+    link: Link,
+    /// offline if we hear nothing before this
+    deadline: Instant,
+    timeout: Duration,
+}
+
+impl Network {
+    /// Wait for the panel to connect over tcp on `bind`
+    pub async fn tcp(id: PanelId, bind: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(bind).await?;
+        Ok(Self::new(
+            id,
+            Link::Tcp {
+                listener,
+                conn: None,
+            },
+        ))
+    }
+
+    /// Wait for the panel to say hello over udp on `bind`
+    pub async fn udp(id: PanelId, bind: SocketAddr) -> io::Result<Self> {
+        let socket = UdpSocket::bind(bind).await?;
+        Ok(Self::new(
+            id,
+            Link::Udp {
+                socket: UdpFramed::new(socket, NetCodec),
+                peer: None,
+            },
+        ))
+    }
+
+    fn new(id: PanelId, link: Link) -> Self {
+        Self {
+            id,
+            link,
+            deadline: Instant::now(),
+            timeout: TIMEOUT,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.link {
+            Link::Tcp { listener, .. } => listener.local_addr(),
+            Link::Udp { socket, .. } => socket.get_ref().local_addr(),
+        }
+    }
+
+    fn heard_from_panel(&mut self) {
+        self.deadline = Instant::now() + self.timeout;
+    }
+
+    fn disconnect(&mut self, reason: String) -> RecvError {
+        match &mut self.link {
+            Link::Tcp { conn, .. } => *conn = None,
+            Link::Udp { peer, .. } => *peer = None,
+        }
+        RecvError::Offline(reason)
+    }
+
+    async fn reply(&mut self, message: ToRemote) -> Result<(), RecvError> {
+        let res = match &mut self.link {
+            Link::Tcp {
+                conn: Some(conn), ..
+            } => conn.send(message).await,
+            Link::Udp {
+                socket,
+                peer: Some(peer),
+            } => socket.send((message, *peer)).await,
+            _ => return Err(RecvError::Offline("not connected".to_owned())),
+        };
+        res.map_err(|err| self.disconnect(err.to_string()))
+    }
+
+    /// The next message from the panel, `None` for messages that are not
+    /// for us
+    async fn next(&mut self) -> Result<Option<FromRemote>, RecvError> {
+        let limit = self.timeout;
+        let timed_out = || format!("no keepalive for {limit:?}");
+        match &mut self.link {
+            Link::Tcp { conn: None, .. } | Link::Udp { peer: None, .. } => {
+                Err(RecvError::Offline("not connected".to_owned()))
+            }
+            Link::Tcp {
+                conn: Some(conn), ..
+            } => match timeout_at(self.deadline, conn.next()).await {
+                Ok(Some(Ok(message))) => Ok(Some(message)),
+                Ok(Some(Err(err))) => Err(self.disconnect(err.to_string())),
+                Ok(None) => {
+                    Err(self.disconnect("connection closed".to_owned()))
+                }
+                Err(_) => Err(self.disconnect(timed_out())),
+            },
+            Link::Udp {
+                socket,
+                peer: Some(peer),
+            } => match timeout_at(self.deadline, socket.next()).await {
+                Ok(Some(Ok((message, from)))) if from == *peer => {
+                    Ok(Some(message))
+                }
+                Ok(Some(Ok((FromRemote::Hello { version, id }, from)))) => {
+                    // the panel came back with a new address
+                    if check_hello(&self.id, version, &id).is_ok() {
+                        info!("Panel {id} moved to {from}");
+                        *peer = from;
+                        return Ok(Some(FromRemote::Hello { version, id }));
+                    }
+                    Ok(None)
+                }
+                Ok(Some(Ok((_, from)))) => {
+                    debug!("Ignoring message from unknown address {from}");
+                    Ok(None)
+                }
+                // for example icmp port unreachable, udp has no connection
+                // that could be lost
+                Ok(Some(Err(err))) => {
+                    debug!("Udp error: {err}");
+                    Ok(None)
+                }
+                Ok(None) => Err(RecvError::Closed("udp socket closed")),
+                Err(_) => Err(self.disconnect(timed_out())),
+            },
+        }
+    }
+
+    async fn accept_tcp(&mut self) -> io::Result<()> {
+        let Link::Tcp { listener, conn } = &mut self.link else {
+            unreachable!("only called for tcp");
+        };
+        loop {
+            let (stream, from) = listener.accept().await?;
+            let mut framed = Framed::new(stream, NetCodec);
+            let hello = match timeout(HANDSHAKE_TIMEOUT, framed.next()).await {
+                Ok(Some(Ok(FromRemote::Hello { version, id }))) => {
+                    check_hello(&self.id, version, &id)
+                }
+                Ok(Some(Ok(_))) => Err("first message was no hello".to_owned()),
+                Ok(Some(Err(err))) => Err(err.to_string()),
+                Ok(None) => Err("connection closed".to_owned()),
+                Err(_) => Err("no hello in time".to_owned()),
+            };
+            if let Err(err) = hello {
+                warn!("Rejected panel connection from {from}: {err}");
+                continue;
+            }
+            if let Err(err) = framed.send(ToRemote::Welcome).await {
+                warn!("Could not welcome panel at {from}: {err}");
+                continue;
+            }
+            info!("Panel {} connected from {from}", self.id);
+            *conn = Some(framed);
+            return Ok(());
+        }
+    }
+
+    async fn accept_udp(&mut self) -> Result<(), RecvError> {
+        let Link::Udp { socket, peer } = &mut self.link else {
+            unreachable!("only called for udp");
+        };
+        loop {
+            let (version, id, from) = match socket.next().await {
+                Some(Ok((FromRemote::Hello { version, id }, from))) => {
+                    (version, id, from)
+                }
+                Some(Ok((_, from))) => {
+                    debug!("Ignoring {from}, it did not say hello");
+                    continue;
+                }
+                Some(Err(err)) => {
+                    debug!("Udp error: {err}");
+                    continue;
+                }
+                None => return Err(RecvError::Closed("udp socket closed")),
+            };
+            if let Err(err) = check_hello(&self.id, version, &id) {
+                warn!("Rejected panel at {from}: {err}");
+                continue;
+            }
+            if let Err(err) = socket.send((ToRemote::Welcome, from)).await {
+                warn!("Could not welcome panel at {from}: {err}");
+                continue;
+            }
+            info!("Panel {id} said hello from {from}");
+            *peer = Some(from);
+            return Ok(());
+        }
+    }
+}
+
+#[async_trait]
+impl Panel for Network {
+    /// Only once the panel said hello
+    fn is_connected(&self) -> bool {
+        match &self.link {
+            Link::Tcp { conn, .. } => conn.is_some(),
+            Link::Udp { peer, .. } => peer.is_some(),
+        }
+    }
+
+    async fn recv(&mut self) -> Result<Event, RecvError> {
+        loop {
+            let Some(message) = self.next().await? else {
+                continue;
+            };
+            self.heard_from_panel();
+            match message {
+                FromRemote::Event(event) => return Ok(event),
+                FromRemote::Ping => self.reply(ToRemote::Pong).await?,
+                // the panel missed our welcome
+                FromRemote::Hello { .. } => {
+                    self.reply(ToRemote::Welcome).await?;
+                }
+            }
+        }
+    }
+
+    async fn send(&mut self, command: Command) -> Result<()> {
+        self.reply(ToRemote::Command(command))
+            .await
+            .map_err(|err| eyre!("Could not send to panel: {err}"))
+    }
+
+    async fn reconnect(&mut self) -> Result<(), RecvError> {
+        match self.link {
+            Link::Tcp { .. } => {
+                while let Err(err) = self.accept_tcp().await {
+                    warn!("Could not accept panel connection: {err}");
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+            Link::Udp { .. } => self.accept_udp().await?,
+        }
+        self.heard_from_panel();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use button_protocol::{Button, ButtonPress, Color};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn hello(id: &str) -> Vec<u8> {
+        let mut buf = [0; frame::MAX_FRAME_LEN];
+        let hello = Hello {
+            version: frame::VERSION,
+            id,
+        };
+        let len = hello.to_frame(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    /// A hello as a panel speaking `version` would send it
+    fn hello_in(version: u8, id: &str) -> Vec<u8> {
+        let mut hello = hello(id);
+        hello[1] = version;
+        hello[frame::HEADER_LEN] = version;
+        let crc_at = hello.len() - frame::CRC_LEN;
+        let crc = frame::crc16(&hello[1..crc_at]);
+        hello[crc_at..].copy_from_slice(&crc.to_be_bytes());
+        hello
+    }
+
+    fn ping() -> Vec<u8> {
+        let mut buf = [0; frame::MAX_FRAME_LEN];
+        let len = frame::encode(frame::Kind::Ping, &[], &mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    fn press() -> (Event, Vec<u8>) {
+        let event = Event::Press(ButtonPress::Short(Button::BottomLeft));
+        let mut buf = [0; frame::MAX_FRAME_LEN];
+        let len = event.to_frame(&mut buf);
+        (event, buf[..len].to_vec())
+    }
+
+    fn kind(data: &[u8]) -> frame::Kind {
+        frame::decode(data).unwrap().0.kind
+    }
+
+    async fn read_frame(stream: &mut TcpStream) -> Vec<u8> {
+        let mut header = [0; frame::HEADER_LEN];
+        stream.read_exact(&mut header).await.unwrap();
+        let mut rest = vec![0; usize::from(header[3]) + frame::CRC_LEN];
+        stream.read_exact(&mut rest).await.unwrap();
+        [&header[..], &rest].concat()
+    }
+
+    #[tokio::test]
+    async fn tcp_handshake_events_and_keepalive() {
+        let door = PanelId::from("door");
+        let loopback = ([127, 0, 0, 1], 0).into();
+        let mut panel = Network::tcp(door, loopback).await.unwrap();
+        let addr = panel.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            // the wrong panel is turned away
+            let mut bed = TcpStream::connect(addr).await.unwrap();
+            bed.write_all(&hello("bed")).await.unwrap();
+            assert_eq!(bed.read(&mut [0; 8]).await.unwrap(), 0);
+
+            let mut door = TcpStream::connect(addr).await.unwrap();
+            door.write_all(&hello("door")).await.unwrap();
+            assert_eq!(
+                kind(&read_frame(&mut door).await),
+                frame::Kind::Welcome
+            );
+            door.write_all(&ping()).await.unwrap();
+            assert_eq!(kind(&read_frame(&mut door).await), frame::Kind::Pong);
+            door.write_all(&press().1).await.unwrap();
+            let command = read_frame(&mut door).await;
+            let (command, _) = frame::decode(&command).unwrap();
+            Command::from_frame(&command).unwrap()
+        });
+
+        assert!(matches!(panel.recv().await, Err(RecvError::Offline(_))));
+        panel.reconnect().await.unwrap();
+        assert_eq!(panel.recv().await, Ok(press().0));
+        let command = Command::SetLed(None, Color::GREEN);
+        panel.send(command).await.unwrap();
+        assert_eq!(client.await.unwrap(), command);
+
+        // the client is gone
+        assert!(matches!(panel.recv().await, Err(RecvError::Offline(_))));
+    }
+
+    #[tokio::test]
+    async fn other_versions_are_turned_away() {
+        let mut src = BytesMut::from(&hello_in(2, "door")[..]);
+        let door = PanelId::from("door");
+        let decoded = NetCodec.decode(&mut src).unwrap().unwrap();
+        let FromRemote::Hello { version, id } = decoded else {
+            panic!("not a hello: {decoded:?}");
+        };
+        assert_eq!(version, 2);
+        let err = check_hello(&door, version, &id).unwrap_err();
+        assert!(err.contains("version 2"), "{err}");
+
+        let loopback = ([127, 0, 0, 1], 0).into();
+        let mut panel = Network::tcp(door, loopback).await.unwrap();
+        let addr = panel.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let mut old = TcpStream::connect(addr).await.unwrap();
+            old.write_all(&hello_in(2, "door")).await.unwrap();
+            // closed right away, not after the handshake timeout
+            let mut buf = [0; 8];
+            let read = timeout(Duration::from_secs(1), old.read(&mut buf));
+            assert_eq!(read.await.unwrap().unwrap(), 0);
+
+            let mut door = TcpStream::connect(addr).await.unwrap();
+            door.write_all(&hello("door")).await.unwrap();
+            kind(&read_frame(&mut door).await)
+        });
+        panel.reconnect().await.unwrap();
+        assert_eq!(client.await.unwrap(), frame::Kind::Welcome);
+    }
+
+    #[tokio::test]
+    async fn udp_panel_times_out() {
+        let door = PanelId::from("door");
+        let loopback = ([127, 0, 0, 1], 0).into();
+        let mut panel = Network::udp(door, loopback).await.unwrap();
+        panel.timeout = Duration::from_millis(200);
+        let addr = panel.local_addr().unwrap();
+
+        let client = UdpSocket::bind(loopback).await.unwrap();
+        client.send_to(&press().1, addr).await.unwrap();
+        client.send_to(&hello("door"), addr).await.unwrap();
+        panel.reconnect().await.unwrap();
+        let mut buf = [0; frame::MAX_FRAME_LEN];
+        let len = client.recv(&mut buf).await.unwrap();
+        assert_eq!(kind(&buf[..len]), frame::Kind::Welcome);
+
+        client.send_to(&press().1, addr).await.unwrap();
+        assert_eq!(panel.recv().await, Ok(press().0));
+        let Err(RecvError::Offline(reason)) = panel.recv().await else {
+            panic!("panel should be offline");
+        };
+        assert!(reason.contains("keepalive"));
+    }
+}
@@ -0,0 +1,207 @@
+//! A panel that plays back the presses, holds and chords of one panel from
+//! a log written by [`crate::record`]. The time between them is kept, or
+//! shortened by a speedup factor. Everything else in the log is skipped.
+
+use std::collections::VecDeque;
+use std::path::Path;
+
+use async_trait::async_trait;
+use chrono::{DateTime, FixedOffset};
+use color_eyre::eyre::{eyre, Context};
+use color_eyre::Result;
+use serde_json::Value;
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, info};
+
+use button_protocol::{Chord, Command, Event, Hold};
+
+use super::{Panel, PanelId, RecvError};
+use crate::keymap::{parse_button, parse_press};
+
+fn field<'a>(record: &'a Value, name: &str) -> Result<&'a str> {
+    record[name]
+        .as_str()
+        .ok_or_else(|| eyre!("Missing field `{name}`"))
+}
+
+/// The panel event in a line of the log, if it is one from `panel`
+fn parse_line(
+    line: &str,
+    panel: &PanelId,
+) -> Result<Option<(DateTime<FixedOffset>, Event)>> {
+    let record: Value = serde_json::from_str(line)?;
+    let kind = field(&record, "type")?;
+    if !matches!(kind, "press" | "hold" | "chord")
+        || field(&record, "panel")? != panel.name()
+    {
+        return Ok(None);
+    }
+
+    let at = DateTime::parse_from_rfc3339(field(&record, "at")?)?;
+    let event = match kind {
+        "press" => {
+            let press = field(&record, "press")?;
+            let button = field(&record, "button")?;
+            Event::Press(parse_press(&format!("{press}_{button}"))?)
+        }
+        "hold" => {
+            let button = parse_button(field(&record, "button")?)?;
+            let hold = match field(&record, "phase")? {
+                "start" => Hold::Start(button),
+                "release" => Hold::Release(button),
+                "repeat" => {
+                    let repeat = record["repeat"]
+                        .as_u64()
+                        .and_then(|repeat| u16::try_from(repeat).ok())
+                        .ok_or_else(|| eyre!("Invalid field `repeat`"))?;
+                    Hold::Repeat(button, repeat)
+                }
+                phase => return Err(eyre!("Unknown hold phase: `{phase}`")),
+            };
+            Event::Hold(hold)
+        }
+        _ => {
+            let buttons = record["buttons"]
+                .as_array()
+                .ok_or_else(|| eyre!("Missing field `buttons`"))?;
+            let mut chord = Chord::EMPTY;
+            for button in buttons {
+                let name = button.as_str().unwrap_or_default();
+                chord = chord.with(parse_button(name)?);
+            }
+            Event::Chord(chord)
+        }
+    };
+    Ok(Some((at, event)))
+}
+
+pub struct Replay {
+    events: VecDeque<(DateTime<FixedOffset>, Event)>,
+    speedup: f64,
+    /// when the previous event was recorded
+    last: Option<DateTime<FixedOffset>>,
+    /// when the next event is due, kept as `recv` may be cancelled
+    until: Option<Instant>,
+}
+
+impl Replay {
+    /// Replays what `panel` sent, `speedup` times faster than it happened
+    pub fn parse(content: &str, panel: &PanelId, speedup: f64) -> Result<Self> {
+        if !(speedup > 0.0 && speedup.is_finite()) {
+            return Err(eyre!("Speedup should be above zero, got {speedup}"));
+        }
+        let mut events = VecDeque::new();
+        for (i, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = parse_line(line, panel).wrap_err_with(|| {
+                format!("Invalid record on line {}", i + 1)
+            })?;
+            events.extend(event);
+        }
+        info!("Replaying {} events from panel {panel}", events.len());
+        Ok(Self {
+            events,
+            speedup,
+            last: None,
+            until: None,
+        })
+    }
+
+    pub fn load(path: &Path, panel: &PanelId, speedup: f64) -> Result<Self> {
+        let content = std::fs::read_to_string(path).wrap_err_with(|| {
+            format!("Could not read recording: {}", path.display())
+        })?;
+        Self::parse(&content, panel, speedup)
+            .wrap_err_with(|| format!("Invalid recording: {}", path.display()))
+    }
+}
+
+#[async_trait]
+impl Panel for Replay {
+    async fn recv(&mut self) -> Result<Event, RecvError> {
+        let Some(&(at, event)) = self.events.front() else {
+            return Err(RecvError::Closed("end of the recording"));
+        };
+        let gap = self
+            .last
+            .and_then(|last| (at - last).to_std().ok())
+            .unwrap_or_default();
+        let speedup = self.speedup;
+        let until = *self
+            .until
+            .get_or_insert_with(|| Instant::now() + gap.div_f64(speedup));
+        sleep_until(until).await;
+
+        self.events.pop_front();
+        self.last = Some(at);
+        self.until = None;
+        info!("Replaying {event:?}, recorded at {at}");
+        Ok(event)
+    }
+
+    async fn send(&mut self, command: Command) -> Result<()> {
+        debug!("Replayed panel got command: {command:?}");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use button_protocol::{Button, ButtonPress};
+
+    const LOG: &str = concat!(
+        r#"{"at":"2026-10-17T03:00:00.000+02:00","type":"press","#,
+        r#""panel":"main","press":"long","button":"top_right"}"#,
+        "\n",
+        r#"{"at":"2026-10-17T03:00:00.100+02:00","type":"request","#,
+        r#""method":"POST","path":"/alarm"}"#,
+        "\n",
+        r#"{"at":"2026-10-17T03:00:00.200+02:00","type":"press","#,
+        r#""panel":"door","press":"short","button":"top_left"}"#,
+        "\n",
+        r#"{"at":"2026-10-17T03:00:00.300+02:00","type":"hold","#,
+        r#""panel":"main","phase":"repeat","button":"bottom_left","#,
+        r#""repeat":2}"#,
+        "\n",
+        r#"{"at":"2026-10-17T03:00:00.400+02:00","type":"chord","#,
+        r#""panel":"main","buttons":["top_left","top_right"]}"#,
+        "\n",
+    );
+
+    #[tokio::test(start_paused = true)]
+    async fn replays_one_panel_with_speedup() {
+        let mut replay = Replay::parse(LOG, &PanelId::main(), 10.0).unwrap();
+        let start = Instant::now();
+        let mut events = Vec::new();
+        while let Ok(event) = replay.recv().await {
+            events.push(event);
+        }
+        // 400ms recorded, played 10 times faster
+        let took = start.elapsed();
+        assert_eq!(took, Duration::from_millis(40));
+
+        let chord = Chord::of(&[Button::TopLeft, Button::TopRight]);
+        assert_eq!(
+            events,
+            [
+                Event::Press(ButtonPress::Long(Button::TopRight)),
+                Event::Hold(Hold::Repeat(Button::BottomLeft, 2)),
+                Event::Chord(chord),
+            ]
+        );
+    }
+
+    #[test]
+    fn broken_records_are_reported() {
+        let main = PanelId::main();
+        assert!(Replay::parse(LOG, &main, 0.0).is_err());
+        let broken = r#"{"at":"now","type":"press","panel":"main"}"#;
+        let err = Replay::parse(broken, &main, 1.0).err().unwrap();
+        assert!(format!("{err:?}").contains("line 1"));
+    }
+}
@@ -0,0 +1,92 @@
+//! Recording of everything that happens, to reproduce odd behaviour later.
+//! Every [`Event`] is appended to the log as a line of json with the local
+//! time it happened at, for example:
+//!
+//! ```text
+//! {"at":"2026-10-17T03:12:01.123+02:00","type":"press","panel":"main",...}
+//! ```
+//!
+//! That covers the presses from the panels, the requests to the api and the
+//! actions they led to. [`crate::panel::Replay`] feeds the presses back.
+
+use std::path::Path;
+
+use chrono::{Local, SecondsFormat};
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use serde::Serialize;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{error, info};
+
+use crate::events::{Event, Events};
+
+#[derive(Serialize)]
+struct Record<'a> {
+    at: String,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// A line of the log for `event` happening now
+fn line(event: &Event) -> String {
+    let record = Record {
+        at: Local::now().to_rfc3339_opts(SecondsFormat::Millis, false),
+        event,
+    };
+    let mut line = serde_json::to_string(&record).expect("serializable");
+    line.push('\n');
+    line
+}
+
+/// Opens the log, appending to it if it exists. The returned future writes
+/// the events to it until the events stop.
+pub async fn start(
+    path: &Path,
+    events: &Events,
+) -> Result<impl std::future::Future<Output = ()>> {
+    let log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .wrap_err_with(|| format!("Could not open {}", path.display()))?;
+    info!("Recording to {}", path.display());
+    Ok(record(log, events.subscribe()))
+}
+
+async fn record(mut log: File, mut events: broadcast::Receiver<Event>) {
+    loop {
+        let event = match events.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(missed)) => Event::Lagged { missed },
+            Err(RecvError::Closed) => return,
+        };
+        // flushed right away, the log is most useful right before a crash
+        let res = async {
+            log.write_all(line(&event).as_bytes()).await?;
+            log.flush().await
+        };
+        if let Err(err) = res.await {
+            error!("Stopped recording, could not write: {err}");
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_timestamped_events() {
+        let line = line(&Event::Lagged { missed: 3 });
+        assert!(line.ends_with('\n'));
+        let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["type"], "lagged");
+        assert_eq!(json["missed"], 3);
+        let at = json["at"].as_str().unwrap();
+        assert!(chrono::DateTime::parse_from_rfc3339(at).is_ok());
+    }
+}
@@ -0,0 +1,235 @@
+//! What the controller is up to, served as json on `GET /status` and shown by
+//! the `status` subcommand.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use color_eyre::eyre::Context;
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::audiocontrol::now_timestamp;
+use crate::config::Config;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Status {
+    pub mode: String,
+    /// mpd play state: play, pause or stop
+    pub state: String,
+    pub song: Option<Song>,
+    pub modes: Vec<ModeStatus>,
+    /// by panel id
+    pub panels: BTreeMap<String, Link>,
+    pub data_server: Link,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Song {
+    pub file: String,
+    pub title: Option<String>,
+    pub elapsed_secs: Option<u64>,
+    pub duration_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ModeStatus {
+    pub mode: String,
+    /// playlist the mode continues with
+    pub playlist: Option<String>,
+    /// where in the playlist we stopped the last time we left it
+    pub position: Option<Position>,
+    /// unix timestamp
+    pub last_played: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub song: u32,
+    pub elapsed_secs: u32,
+}
+
+/// How the connection to something outside the process is doing
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Link {
+    /// whether the last exchange succeeded
+    pub connected: bool,
+    /// unix timestamp of the last successful exchange
+    pub last_seen: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl Link {
+    pub fn ok(&mut self) {
+        self.connected = true;
+        self.last_seen = Some(now_timestamp());
+    }
+
+    pub fn failed(&mut self, err: impl fmt::Display) {
+        self.connected = false;
+        self.last_error = Some(err.to_string());
+    }
+}
+
+/// Connection health, updated by the tasks talking to the panel and the
+/// data server
+#[derive(Debug, Default)]
+pub struct Health {
+    /// by panel id
+    pub panels: BTreeMap<String, Link>,
+    pub data_server: Link,
+}
+
+pub type SharedHealth = Arc<Mutex<Health>>;
+
+fn ago(timestamp: u64, now: u64) -> String {
+    let secs = now.saturating_sub(timestamp);
+    match secs {
+        0..=119 => format!("{secs}s ago"),
+        120..=7199 => format!("{}m ago", secs / 60),
+        7200..=172_799 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+fn minutes(secs: Option<u64>) -> String {
+    secs.map_or("?".to_owned(), |secs| {
+        format!("{}:{:02}", secs / 60, secs % 60)
+    })
+}
+
+fn link(name: &str, link: &Link, now: u64) -> String {
+    let mut line = format!(
+        "{name}: {}",
+        if link.connected { "ok" } else { "offline" }
+    );
+    if let Some(last_seen) = link.last_seen {
+        let _ = write!(line, ", last seen {}", ago(last_seen, now));
+    }
+    if let Some(err) = &link.last_error {
+        let _ = write!(line, ", last error: {err}");
+    }
+    line
+}
+
+/// Human readable version of the status
+pub fn render(status: &Status, now: u64) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "Mode: {} ({})", status.mode, status.state);
+    if let Some(song) = &status.song {
+        let _ = writeln!(
+            out,
+            "Song: {} [{} / {}]",
+            song.title.as_deref().unwrap_or(&song.file),
+            minutes(song.elapsed_secs),
+            minutes(song.duration_secs),
+        );
+    }
+
+    let _ = writeln!(out, "Playlists:");
+    for mode in &status.modes {
+        let _ = write!(
+            out,
+            "  {:<12} {}",
+            mode.mode,
+            mode.playlist.as_deref().unwrap_or("-")
+        );
+        if let Some(Position { song, elapsed_secs }) = &mode.position {
+            let elapsed = minutes(Some(u64::from(*elapsed_secs)));
+            let _ = write!(out, ", song {song} at {elapsed}");
+        }
+        if let Some(last_played) = mode.last_played {
+            let _ = write!(out, ", played {}", ago(last_played, now));
+        }
+        out.push('\n');
+    }
+
+    for (id, panel) in &status.panels {
+        let _ = writeln!(out, "{}", link(&format!("Panel {id}"), panel, now));
+    }
+    let _ = writeln!(out, "{}", link("Data server", &status.data_server, now));
+    out
+}
+
+/// Where the running instance serves `route`
+pub(crate) fn api_url(config: &Config, route: &str) -> String {
+    let mut address = config.api.bind;
+    if address.ip().is_unspecified() {
+        address.set_ip([127, 0, 0, 1].into());
+    }
+    format!("http://{address}/{route}")
+}
+
+/// Ask the running instance for its status and print it
+pub async fn print(config: Option<&Path>, json: bool) -> Result<()> {
+    let config = Config::load(config)?;
+    let url = api_url(&config, "status");
+    let body = reqwest::get(&url)
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .wrap_err_with(|| format!("Could not get status from {url}"))?
+        .text()
+        .await
+        .wrap_err("Could not read status")?;
+
+    if json {
+        println!("{body}");
+    } else {
+        let status: Status =
+            serde_json::from_str(&body).wrap_err("Invalid status")?;
+        print!("{}", render(&status, now_timestamp()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_status() {
+        let status = Status {
+            mode: "Podcast".to_owned(),
+            state: "play".to_owned(),
+            song: Some(Song {
+                file: "podcasts/episode.mp3".to_owned(),
+                title: None,
+                elapsed_secs: Some(75),
+                duration_secs: Some(3600),
+            }),
+            modes: vec![ModeStatus {
+                mode: "Podcast".to_owned(),
+                playlist: Some("podcast_news".to_owned()),
+                position: Some(Position {
+                    song: 2,
+                    elapsed_secs: 61,
+                }),
+                last_played: Some(1000),
+            }],
+            panels: BTreeMap::from([(
+                "main".to_owned(),
+                Link {
+                    connected: true,
+                    last_seen: Some(1990),
+                    last_error: None,
+                },
+            )]),
+            data_server: Link {
+                connected: false,
+                last_seen: None,
+                last_error: Some("timed out".to_owned()),
+            },
+        };
+
+        let expected = "\
+Mode: Podcast (play)
+Song: podcasts/episode.mp3 [1:15 / 60:00]
+Playlists:
+  Podcast      podcast_news, song 2 at 1:01, played 16m ago
+Panel main: ok, last seen 10s ago
+Data server: offline, last error: timed out
+";
+        assert_eq!(render(&status, 2000), expected);
+    }
+}
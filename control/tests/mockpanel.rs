@@ -1,20 +1,28 @@
-use color_eyre::eyre::Context;
+use std::path::Path;
+
 use color_eyre::Result;
 
 use control::panel;
 
 #[tokio::test]
+#[ignore = "needs mpd, set MPD_HOST if it is not on localhost"]
 async fn main() -> Result<()> {
     color_eyre::install()?;
     control::setup_tracing();
 
     let args = control::Args {
-        ip: "192.168.1.101".to_owned(),
+        ip: std::env::var("MPD_HOST").unwrap_or("127.0.0.1".to_owned()),
         ..control::Args::default()
     };
 
-    let panel =
-        panel::Mock::full_test().wrap_err("Could not connect to Panel")?;
+    let scenario = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("scenarios")
+        .join("full_test.toml");
+    let (panel, outcome) = panel::Mock::load(&scenario)?;
+    let id = panel::PanelId::main();
 
-    control::run(panel, args).await
+    tokio::select! {
+        res = control::run(id, panel, args) => res,
+        res = outcome.wait() => res,
+    }
 }
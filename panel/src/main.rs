@@ -3,12 +3,13 @@
 
 use defmt::*;
 use defmt_rtt as _;
-use embassy_stm32::peripherals::USART1;
-use embassy_stm32::usart::Uart;
+use embassy_futures::join::join;
+use embassy_futures::select::{select, select_array, Either};
+use embassy_stm32::peripherals::{DMA2_CH2, DMA2_CH7, USART1};
+use embassy_stm32::usart::{Uart, UartRx, UartTx};
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::mutex::Mutex;
-use embassy_time::Instant;
-use futures::join;
+use embassy_time::{Instant, Timer};
 // global logger
 use panic_probe as _;
 
@@ -24,15 +25,15 @@ defmt::timestamp! {"{=u64}", {
 }
 
 use embassy_executor::Spawner;
-use embassy_stm32::dma::NoDma;
 use embassy_stm32::exti::ExtiInput;
-use embassy_stm32::gpio::{Input, Pin, Pull};
+use embassy_stm32::gpio::{AnyPin, Input, Pin, Pull};
 use embassy_stm32::usart;
 use embassy_stm32::{Peripheral, Peripherals};
 
-use button_protocol::{Button, ButtonPress};
+use button_protocol::detect::PanelDetector;
+use button_protocol::{frame, Button, Command, Event};
 
-type UsartMutex<'a> = Mutex<NoopRawMutex, Uart<'a, USART1>>;
+type UsartMutex<'a> = Mutex<NoopRawMutex, UartTx<'a, USART1, DMA2_CH7>>;
 
 // #[embassy_executor::main]
 // async fn main(_spawner: Spawner, p: Peripherals) {
@@ -41,47 +42,87 @@ type UsartMutex<'a> = Mutex<NoopRawMutex, Uart<'a, USART1>>;
 //     let mut config = usart::Config::default();
 //     config.baudrate = 9600;
 //
-//     let usart =
-//         Uart::new(p.USART1, p.PA10, p.PA9, _, NoDma, NoDma, config).unwrap();
-//     let usart: UsartMutex = Mutex::new(usart);
+//     let usart = Uart::new(
+//         p.USART1, p.PA10, p.PA9, _, p.DMA2_CH7, p.DMA2_CH2, config,
+//     )
+//     .unwrap();
+//     let (tx, rx) = usart.split();
+//     let usart: UsartMutex = Mutex::new(tx);
 //
-//     let a = wait_for_button(&usart, p.PB12, p.EXTI12, Button::TopLeft);
-//     let b = wait_for_button(&usart, p.PB13, p.EXTI13, Button::TopMiddle);
-//     let c = wait_for_button(&usart, p.PB1, p.EXTI1, Button::TopRight);
-//     let d = wait_for_button(&usart, p.PC15, p.EXTI15, Button::BottomLeft);
-//     let e = wait_for_button(&usart, p.PB0, p.EXTI0, Button::BottomMiddle);
-//     let f = wait_for_button(&usart, p.PC14, p.EXTI14, Button::BottomRight);
-//     join!(a, b, c, d, e, f);
+//     // same order as Button::ALL
+//     let mut buttons = [
+//         button(p.PB12, p.EXTI12),
+//         button(p.PB13, p.EXTI13),
+//         button(p.PB1, p.EXTI1),
+//         button(p.PC15, p.EXTI15),
+//         button(p.PB0, p.EXTI0),
+//         button(p.PC14, p.EXTI14),
+//     ];
+//     join(watch_buttons(&usart, &mut buttons), receive_commands(rx)).await;
 // }
 //
-// async fn wait_for_button<'d, T: Pin>(
-//     usart: &UsartMutex<'_>,
+// fn button<'d, T: Pin>(
 //     pin: impl Peripheral<P = T> + 'd,
 //     ch: impl Peripheral<P = T::ExtiChannel> + 'd,
-//     name: button_protocol::Button,
+// ) -> ExtiInput<'d, AnyPin> {
+//     let button = Input::new(pin.into_ref().map_into(), Pull::Down);
+//     ExtiInput::new(button, ch.into_ref().map_into())
+// }
+//
+// /// All buttons are watched together so presses on different buttons can
+// /// be combined into chords.
+// async fn watch_buttons(
+//     usart: &UsartMutex<'_>,
+//     buttons: &mut [ExtiInput<'_, AnyPin>; 6],
 // ) {
-//     let button = Input::new(pin, Pull::Down);
-//     let mut button = ExtiInput::new(button, ch);
+//     let mut detector = PanelDetector::new();
+//     let now = || Instant::now().as_millis();
 //
 //     loop {
-//         button.wait_for_high().await;
-//         let press_time = Instant::now();
-//         button.wait_for_low().await;
+//         let deadline =
+//             detector.deadline().map_or(Instant::MAX, Instant::from_millis);
+//         let edges = buttons.each_mut().map(|b| b.wait_for_any_edge());
+//         let edge = select(select_array(edges), Timer::at(deadline)).await;
 //
-//         let press_millis = press_time.elapsed().as_millis();
-//         let button_press = match press_millis {
-//             0..=50 => continue,
-//             51..=400 => ButtonPress::Short(name),
-//             401..=2000 => ButtonPress::Long(name),
-//             _ => continue,
+//         let event = match edge {
+//             Either::First(((), i)) if buttons[i].is_high() => {
+//                 detector.pressed(Button::ALL[i], now())
+//             }
+//             Either::First(((), i)) => {
+//                 detector.released(Button::ALL[i], now())
+//             }
+//             Either::Second(()) => None,
 //         };
-//         debug!("Button {} pressed for {}ms", name, press_millis);
+//         if let Some(event) = event {
+//             send(usart, event).await;
+//         }
+//         while let Some(event) = detector.poll(now()) {
+//             send(usart, event).await;
+//         }
+//     }
+// }
+//
+// async fn send(usart: &UsartMutex<'_>, event: Event) {
+//     let mut buf = [0; frame::MAX_FRAME_LEN];
+//     let len = event.to_frame(&mut buf);
+//     let mut usart = usart.lock().await;
+//     unwrap!(usart.blocking_write(&buf[..len]));
 //
-//         let mut buf = [0, b'\n'];
-//         buf[0] = button_press.serialize();
-//         let mut usart = usart.lock().await;
-//         unwrap!(usart.blocking_write(&buf));
+//     info!("Event: {}", event)
+// }
 //
-//         info!("Press: {}", button_press)
+// async fn receive_commands(mut rx: UartRx<'_, USART1, DMA2_CH2>) {
+//     let mut reader = frame::Reader::new();
+//     let mut byte = [0];
+//     loop {
+//         unwrap!(rx.read(&mut byte).await);
+//         let Some(frame) = reader.push(byte[0]) else {
+//             continue;
+//         };
+//         match Command::from_frame(&frame) {
+//             // TODO: drive the button leds once the panel has them
+//             Ok(command) => info!("Command: {}", command),
+//             Err(err) => warn!("Invalid command from host: {}", err),
+//         }
 //     }
 // }